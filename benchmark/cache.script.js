@@ -0,0 +1,57 @@
+import http from "k6/http";
+import { check } from "k6";
+import { config } from "./.config.js";
+
+// Warm cache lookups of a few addresses from many clients at once, the case the sharded cache is meant for
+export let options = {
+    scenarios: {
+        warm_cache: {
+            executor: "constant-vus",
+            vus: 200,
+            duration: "30s",
+        },
+    },
+    thresholds: {
+        "http_req_duration{scenario:warm_cache}": ["p(95)<50"],
+        "checks{scenario:warm_cache}": ["rate>0.99"],
+    },
+};
+
+// Addresses with a working WKD setup, failed lookups are cached for a shorter time
+const emails = (__ENV.EMAILS || "test@dp42.dev").split(",");
+
+export function setup() {
+    const env_name = __ENV.ENV || "localhost";
+    const env_config = config[env_name];
+    console.log(`Running against ${env_name} environment`);
+
+    if (!env_config) {
+        throw new Error(`Environment ${env_name} not found in config`);
+    }
+
+    // Fill the cache so the measured requests are all hits
+    for (let email of emails) {
+        http.get(`${env_config.base_url}/api/lookup?email=${encodeURIComponent(email)}`);
+    }
+
+    return {
+        env_config,
+    };
+}
+
+export default function (data) {
+    const email = emails[__ITER % emails.length];
+    let res = http.get(`${data.env_config.base_url}/api/lookup?email=${encodeURIComponent(email)}`);
+    check(res, {
+        "status is 200": (r) => r.status === 200,
+    });
+}
+
+export function teardown(data) {
+    let res = http.get(`${data.env_config.base_url}/api/cache/stats`);
+    if (res.status === 200) {
+        console.log(`Cache stats: ${res.body}`);
+    } else {
+        console.log("Cache stats are off, start the server with WKD_TESTER_SERVER_CACHE_STATS=true");
+    }
+}
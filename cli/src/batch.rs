@@ -0,0 +1,346 @@
+use clap::ValueEnum;
+use miette::{IntoDiagnostic, Result};
+use reqwest::Client;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::task::JoinSet;
+use tokio::time::{Instant, sleep_until};
+use wkd::fetch::{WkdFetch, WkdMethodSelection};
+use wkd::result::WkdResult;
+use wkd::uri::{WkdUri, WkdUriError};
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Method {
+    /// Only run the direct method
+    Direct,
+    /// Only run the advanced method
+    Advanced,
+    /// Run both methods
+    #[default]
+    Both,
+}
+
+impl From<Method> for WkdMethodSelection {
+    fn from(method: Method) -> Self {
+        match method {
+            Method::Direct => WkdMethodSelection::Direct,
+            Method::Advanced => WkdMethodSelection::Advanced,
+            Method::Both => WkdMethodSelection::Both,
+        }
+    }
+}
+
+/// Outcome of looking up one address
+pub struct Lookup<T = WkdFetch> {
+    pub user_id: String,
+    pub result: Result<(WkdUri, T), WkdUriError>,
+}
+
+impl Lookup {
+    pub fn into_wkd_result(self) -> WkdResult {
+        match self.result {
+            Ok((wkd_uri, wkd_fetch)) => WkdResult::from_fetch(&self.user_id, wkd_uri, wkd_fetch),
+            Err(err) => WkdResult::from_uri_error(&self.user_id, &err),
+        }
+    }
+}
+
+/// Reads one address per line, `-` reads from stdin. Blank lines and `#` comments are skipped.
+pub fn read_user_ids(path: &Path) -> Result<Vec<String>> {
+    let reader: Box<dyn BufRead> = if path == Path::new("-") {
+        Box::new(BufReader::new(std::io::stdin()))
+    } else {
+        Box::new(BufReader::new(std::fs::File::open(path).into_diagnostic()?))
+    };
+
+    let mut user_ids = Vec::new();
+    for line in reader.lines() {
+        let line = line.into_diagnostic()?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            user_ids.push(line.to_string());
+        }
+    }
+
+    Ok(user_ids)
+}
+
+/// Limits on how many lookups run at once and how fast they start against one domain
+#[derive(Debug, Clone, Copy)]
+pub struct BatchLimits {
+    /// Lookups in flight over all domains
+    pub concurrency: usize,
+    /// Lookups in flight against the same domain
+    pub domain_concurrency: usize,
+    /// Minimum time between the starts of two lookups against the same domain
+    pub domain_delay: Duration,
+}
+
+/// Per-domain cap on lookups in flight and on how often they start
+struct DomainGate {
+    permits: Semaphore,
+    next_start: Mutex<Option<Instant>>,
+}
+
+impl DomainGate {
+    fn new(concurrency: usize) -> Self {
+        DomainGate {
+            permits: Semaphore::new(concurrency.max(1)),
+            next_start: Mutex::new(None),
+        }
+    }
+
+    /// Waits for a free slot of the domain and then for its start time, every caller gets the next start time
+    async fn enter(&self, delay: Duration) -> SemaphorePermit<'_> {
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("domain semaphore is never closed");
+
+        let start = {
+            let mut next_start = self.next_start.lock().unwrap();
+            let start = next_start.map_or_else(Instant::now, |next| next.max(Instant::now()));
+            *next_start = Some(start + delay);
+            start
+        };
+        sleep_until(start).await;
+
+        permit
+    }
+}
+
+/// Looks up every address with at most `limits.concurrency` lookups in flight.
+///
+/// Up to `limits.domain_concurrency` lookups run against the same domain at once, and they start at
+/// least `limits.domain_delay` apart. Results are returned in input order.
+pub async fn lookup_all(
+    user_ids: Vec<String>,
+    reqwest_client: Client,
+    limits: BatchLimits,
+    selection: WkdMethodSelection,
+) -> Vec<Lookup> {
+    lookup_each(user_ids, limits, move |wkd_uri| {
+        let reqwest_client = reqwest_client.clone();
+        async move {
+            let wkd_fetch = WkdFetch::fetch(&wkd_uri, Some(reqwest_client), selection).await;
+            (wkd_uri, wkd_fetch)
+        }
+    })
+    .await
+}
+
+/// Runs `fetch` for every address that parses, scheduled like [`lookup_all`]
+async fn lookup_each<T, F, Fut>(
+    user_ids: Vec<String>,
+    limits: BatchLimits,
+    fetch: F,
+) -> Vec<Lookup<T>>
+where
+    T: Send + 'static,
+    F: Fn(WkdUri) -> Fut,
+    Fut: Future<Output = (WkdUri, T)> + Send + 'static,
+{
+    let semaphore = Arc::new(Semaphore::new(limits.concurrency.max(1)));
+    let mut domains: HashMap<String, Arc<DomainGate>> = HashMap::new();
+    let mut lookups: Vec<Option<Lookup<T>>> = Vec::new();
+    let mut tasks = JoinSet::new();
+
+    for (index, user_id) in user_ids.into_iter().enumerate() {
+        lookups.push(None);
+
+        let wkd_uri = match WkdUri::new(&user_id) {
+            Ok(wkd_uri) => wkd_uri,
+            Err(err) => {
+                lookups[index] = Some(Lookup {
+                    user_id,
+                    result: Err(err),
+                });
+                continue;
+            }
+        };
+
+        let domain = domains
+            .entry(wkd_uri.domain_part.clone())
+            .or_insert_with(|| Arc::new(DomainGate::new(limits.domain_concurrency)))
+            .clone();
+        let semaphore = semaphore.clone();
+        let fetch = fetch(wkd_uri);
+
+        tasks.spawn(async move {
+            // Wait for the domain before taking a permit so politeness doesn't starve other domains
+            let _domain_permit = domain.enter(limits.domain_delay).await;
+            let _permit = semaphore.acquire().await;
+            let (wkd_uri, fetched) = fetch.await;
+
+            (
+                index,
+                Lookup {
+                    user_id,
+                    result: Ok((wkd_uri, fetched)),
+                },
+            )
+        });
+    }
+
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((index, lookup)) => lookups[index] = Some(lookup),
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        }
+    }
+
+    lookups.into_iter().flatten().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lookup_each_keeps_input_order() {
+        // joe's response is held back until jane's lookup finished, so they complete in reverse order
+        let (jane_done, jane_done_rx) = std::sync::mpsc::channel();
+        let jane_done_rx = Mutex::new(jane_done_rx);
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/joe")
+            .with_chunked_body(move |w| {
+                jane_done_rx.lock().unwrap().recv().unwrap();
+                w.write_all(b"joe")
+            })
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/jane")
+            .with_body("jane")
+            .create_async()
+            .await;
+
+        let user_ids = vec![
+            "invalid".to_string(),
+            "joe@example.org".to_string(),
+            "jane@example.org".to_string(),
+            "also-invalid".to_string(),
+        ];
+        let limits = BatchLimits {
+            concurrency: 2,
+            domain_concurrency: 2,
+            domain_delay: Duration::ZERO,
+        };
+        let client = Client::new();
+        let lookups = lookup_each(user_ids, limits, |wkd_uri| {
+            let request = client
+                .get(format!("{}/{}", server.url(), wkd_uri.local_part))
+                .send();
+            let jane_done = jane_done.clone();
+            async move {
+                let body = request.await.unwrap().text().await.unwrap();
+                if body == "jane" {
+                    jane_done.send(()).unwrap();
+                }
+                (wkd_uri, body)
+            }
+        })
+        .await;
+
+        assert_eq!(lookups.len(), 4);
+        assert_eq!(lookups[0].user_id, "invalid");
+        assert!(lookups[0].result.is_err());
+        assert_eq!(lookups[1].user_id, "joe@example.org");
+        assert_eq!(lookups[1].result.as_ref().unwrap().1, "joe");
+        assert_eq!(lookups[2].user_id, "jane@example.org");
+        assert_eq!(lookups[2].result.as_ref().unwrap().1, "jane");
+        assert_eq!(lookups[3].user_id, "also-invalid");
+        assert!(lookups[3].result.is_err());
+    }
+
+    #[tokio::test]
+    async fn lookups_of_one_domain_overlap() {
+        // Every response is held back until three requests wait for one, so the lookups only finish when three
+        // of them run at once
+        let barrier = Arc::new(std::sync::Barrier::new(3));
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/")
+            .with_chunked_body(move |w| {
+                barrier.wait();
+                w.write_all(b"key")
+            })
+            .expect(6)
+            .create_async()
+            .await;
+
+        let in_flight = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let most = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let user_ids = (0..6).map(|i| format!("user{i}@example.org")).collect();
+        let limits = BatchLimits {
+            concurrency: 8,
+            domain_concurrency: 3,
+            domain_delay: Duration::ZERO,
+        };
+        let client = Client::new();
+        let lookups = lookup_each(user_ids, limits, |wkd_uri| {
+            let request = client.get(server.url()).send();
+            let in_flight = in_flight.clone();
+            let most = most.clone();
+            async move {
+                let running = in_flight.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+                most.fetch_max(running, std::sync::atomic::Ordering::SeqCst);
+                request.await.unwrap().bytes().await.unwrap();
+                in_flight.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                (wkd_uri, ())
+            }
+        })
+        .await;
+
+        assert_eq!(lookups.len(), 6);
+        assert_eq!(most.load(std::sync::atomic::Ordering::SeqCst), 3);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn lookups_of_one_domain_start_apart() {
+        let starts = Arc::new(Mutex::new(Vec::new()));
+        let user_ids = (0..4).map(|i| format!("user{i}@example.org")).collect();
+        let limits = BatchLimits {
+            concurrency: 8,
+            domain_concurrency: 8,
+            domain_delay: Duration::from_millis(20),
+        };
+        let begin = Instant::now();
+        lookup_each(user_ids, limits, |wkd_uri| {
+            let starts = starts.clone();
+            async move {
+                starts.lock().unwrap().push(Instant::now());
+                (wkd_uri, ())
+            }
+        })
+        .await;
+
+        // The nth start is scheduled n delays after the first, and never fires early
+        let mut starts = starts.lock().unwrap().clone();
+        starts.sort();
+        for (n, start) in starts.into_iter().enumerate() {
+            assert!(start - begin >= limits.domain_delay * n as u32, "{n}");
+        }
+    }
+
+    #[test]
+    fn read_user_ids_skips_comments() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("user-ids");
+        std::fs::write(
+            &path,
+            "# audit list\njoe@example.org\n\n  jane@example.org  \n",
+        )
+        .unwrap();
+
+        let user_ids = read_user_ids(&path).unwrap();
+        assert_eq!(user_ids, vec!["joe@example.org", "jane@example.org"]);
+    }
+}
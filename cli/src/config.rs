@@ -0,0 +1,372 @@
+use crate::output::OutputFormat;
+use crate::{Args, Command};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, ValueEnum};
+use miette::{IntoDiagnostic, Result, WrapErr, miette};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use wkd::client::WkdFetchConfig;
+
+/// Config file read when neither `--config` nor `WKD_TESTER_CONFIG` is set and it exists
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("wkd-tester").join("config.toml"))
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub client: ClientConfig,
+    pub defaults: Defaults,
+}
+
+/// `[client]` table, durations are written like `10s` or `1m 30s`
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub timeout: Option<String>,
+    pub connect_timeout: Option<String>,
+    pub user_agent: Option<String>,
+    pub ca_certs: Vec<PathBuf>,
+    pub disable_system_roots: bool,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub proxy: Option<String>,
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    pub no_proxy: Vec<String>,
+}
+
+/// `[defaults]` table, values are the same as for the matching flags
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Defaults {
+    pub format: Option<String>,
+    pub fail_on: Option<String>,
+    pub method: Option<String>,
+    pub require_method: Option<String>,
+    pub concurrency: Option<usize>,
+    pub domain_concurrency: Option<usize>,
+    pub domain_delay: Option<u64>,
+}
+
+impl Config {
+    /// Reads `path`, or the default path when no path is given
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_path() {
+                Some(path) if path.is_file() => path,
+                _ => return Ok(Config::default()),
+            },
+        };
+
+        let contents = std::fs::read_to_string(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Invalid config file {}", path.display()))
+    }
+}
+
+fn parse_enum<T: ValueEnum>(key: &str, value: &str) -> Result<T> {
+    T::from_str(value, true)
+        .map_err(|_| miette!("Invalid value `{value}` for `{key}` in the config file"))
+}
+
+fn parse_duration(key: &str, value: &str) -> Result<Duration> {
+    humantime::parse_duration(value)
+        .map_err(|err| miette!("Invalid duration `{value}` for `{key}` in the config file: {err}"))
+}
+
+/// Replaces the options left at their built-in default with the `[defaults]` of the config file.
+///
+/// Flags and `WKD_TESTER_*` variables are resolved by clap before, so they take precedence.
+pub fn apply_defaults(args: &mut Args, matches: &ArgMatches, defaults: &Defaults) -> Result<()> {
+    fn unset(matches: &ArgMatches, id: &str) -> bool {
+        matches!(
+            matches.value_source(id),
+            None | Some(ValueSource::DefaultValue)
+        )
+    }
+
+    if let Some(Command::Verify {
+        fail_on,
+        method,
+        require_method,
+        ..
+    }) = &mut args.command
+    {
+        if let Some(("verify", matches)) = matches.subcommand() {
+            if unset(matches, "fail_on")
+                && let Some(value) = &defaults.fail_on
+            {
+                *fail_on = parse_enum("defaults.fail_on", value)?;
+            }
+            if unset(matches, "method")
+                && let Some(value) = &defaults.method
+            {
+                *method = parse_enum("defaults.method", value)?;
+            }
+            if unset(matches, "require_method")
+                && let Some(value) = &defaults.require_method
+            {
+                *require_method = parse_enum("defaults.require_method", value)?;
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Lint { fail_on, .. }) = &mut args.command {
+        if let Some(("lint", matches)) = matches.subcommand()
+            && unset(matches, "fail_on")
+            && let Some(value) = &defaults.fail_on
+        {
+            *fail_on = parse_enum("defaults.fail_on", value)?;
+        }
+        return Ok(());
+    }
+
+    if unset(matches, "format")
+        && let Some(value) = &defaults.format
+    {
+        args.format = parse_enum("defaults.format", value)?;
+    }
+    if unset(matches, "fail_on")
+        && let Some(value) = &defaults.fail_on
+    {
+        args.fail_on = parse_enum("defaults.fail_on", value)?;
+    }
+    if unset(matches, "method")
+        && let Some(value) = &defaults.method
+    {
+        args.method = parse_enum("defaults.method", value)?;
+    }
+    if unset(matches, "require_method")
+        && let Some(value) = &defaults.require_method
+    {
+        args.require_method = parse_enum("defaults.require_method", value)?;
+    }
+    if unset(matches, "concurrency")
+        && let Some(concurrency) = defaults.concurrency
+    {
+        args.concurrency = concurrency;
+    }
+    if unset(matches, "domain_concurrency")
+        && let Some(domain_concurrency) = defaults.domain_concurrency
+    {
+        args.domain_concurrency = domain_concurrency;
+    }
+    if unset(matches, "domain_delay")
+        && let Some(domain_delay) = defaults.domain_delay
+    {
+        args.domain_delay = domain_delay;
+    }
+
+    // Checked once the config file was applied instead of with clap's `conflicts_with`, which only sees the
+    // flag and the variable
+    if args.watch.is_some() && args.format != OutputFormat::Human {
+        let source = match matches.value_source("format") {
+            Some(ValueSource::CommandLine) => "--format",
+            Some(ValueSource::EnvVariable) => "WKD_TESTER_FORMAT",
+            _ => "`defaults.format` in the config file",
+        };
+        return Err(miette!(
+            "--watch prints a human readable report and can't be combined with {source}, use `--format human`"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Client settings from the flags and `WKD_TESTER_*` variables, falling back to the `[client]` table
+pub fn fetch_config(args: &Args, client: &ClientConfig) -> Result<WkdFetchConfig> {
+    let timeout = match args.timeout {
+        Some(timeout) => Some(timeout),
+        None => client
+            .timeout
+            .as_deref()
+            .map(|value| parse_duration("client.timeout", value))
+            .transpose()?,
+    };
+    let connect_timeout = match args.connect_timeout {
+        Some(connect_timeout) => Some(connect_timeout),
+        None => client
+            .connect_timeout
+            .as_deref()
+            .map(|value| parse_duration("client.connect_timeout", value))
+            .transpose()?,
+    };
+
+    Ok(WkdFetchConfig {
+        timeout,
+        connect_timeout,
+        user_agent: args
+            .user_agent
+            .clone()
+            .or_else(|| client.user_agent.clone()),
+        ca_certs: match args.ca_cert.is_empty() {
+            true => client.ca_certs.clone(),
+            false => args.ca_cert.clone(),
+        },
+        disable_system_roots: args.no_system_roots || client.disable_system_roots,
+        // The key belongs to the certificate, so both come from the same source
+        client_cert: args
+            .client_cert
+            .clone()
+            .or_else(|| client.client_cert.clone()),
+        client_key: match args.client_cert {
+            Some(_) => args.client_key.clone(),
+            None => args
+                .client_key
+                .clone()
+                .or_else(|| client.client_key.clone()),
+        },
+        proxy: args.proxy.clone().or_else(|| client.proxy.clone()),
+        http_proxy: args
+            .http_proxy
+            .clone()
+            .or_else(|| client.http_proxy.clone()),
+        https_proxy: args
+            .https_proxy
+            .clone()
+            .or_else(|| client.https_proxy.clone()),
+        no_proxy: match args.no_proxy.is_empty() {
+            true => client.no_proxy.clone(),
+            false => args.no_proxy.clone(),
+        },
+        // Testing internal WKD hosts is a normal use of the CLI, the block list is for public servers
+        blocked_networks: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::FailOn;
+    use clap::{CommandFactory, FromArgMatches};
+
+    const CONFIG: &str = r#"
+[client]
+timeout = "10s"
+user_agent = "wkd-tester-ci"
+ca_certs = ["/etc/wkd-tester/ca.pem"]
+proxy = "socks5h://proxy.example.org:1080"
+no_proxy = ["localhost", ".internal.example.org"]
+
+[defaults]
+format = "json"
+fail_on = "error"
+concurrency = 8
+"#;
+
+    fn parse(argv: &[&str]) -> (Args, ArgMatches) {
+        let matches = Args::command().try_get_matches_from(argv).unwrap();
+        (Args::from_arg_matches(&matches).unwrap(), matches)
+    }
+
+    #[test]
+    fn config_file_fills_unset_options() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let (mut args, matches) = parse(&["wkd-tester", "-u", "joe@example.org", "-f", "tap"]);
+        apply_defaults(&mut args, &matches, &config.defaults).unwrap();
+
+        assert_eq!(args.format, OutputFormat::Tap);
+        assert_eq!(args.fail_on, FailOn::Error);
+        assert_eq!(args.concurrency, 8);
+
+        let fetch_config = fetch_config(&args, &config.client).unwrap();
+        assert_eq!(fetch_config.timeout, Some(Duration::from_secs(10)));
+        assert_eq!(fetch_config.user_agent.as_deref(), Some("wkd-tester-ci"));
+        assert_eq!(
+            fetch_config.ca_certs,
+            vec![PathBuf::from("/etc/wkd-tester/ca.pem")]
+        );
+
+        assert_eq!(
+            fetch_config.proxy.as_deref(),
+            Some("socks5h://proxy.example.org:1080")
+        );
+
+        let (args, _) = parse(&[
+            "wkd-tester",
+            "verify",
+            "--ca-cert",
+            "ca.pem",
+            "--no-proxy",
+            "a.example,b.example",
+            "key.asc",
+        ]);
+        let fetch_config = super::fetch_config(&args, &config.client).unwrap();
+        assert_eq!(fetch_config.ca_certs, vec![PathBuf::from("ca.pem")]);
+        assert_eq!(fetch_config.no_proxy, vec!["a.example", "b.example"]);
+    }
+
+    #[test]
+    fn config_file_applies_to_lint() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let (mut args, matches) = parse(&["wkd-tester", "lint", "public"]);
+        apply_defaults(&mut args, &matches, &config.defaults).unwrap();
+
+        assert!(matches!(
+            args.command,
+            Some(Command::Lint {
+                fail_on: FailOn::Error,
+                ..
+            })
+        ));
+
+        let (mut args, matches) = parse(&["wkd-tester", "verify", "-m", "direct", "key.asc"]);
+        apply_defaults(&mut args, &matches, &config.defaults).unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Command::Verify {
+                fail_on: FailOn::Error,
+                method: crate::batch::Method::Direct,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn watch_rejects_a_format_from_any_source() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let (mut args, matches) = parse(&["wkd-tester", "-u", "joe@example.org", "--watch", "5m"]);
+        let err = apply_defaults(&mut args, &matches, &config.defaults).unwrap_err();
+        assert!(err.to_string().contains("`defaults.format`"), "{err}");
+
+        let (mut args, matches) = parse(&[
+            "wkd-tester",
+            "-u",
+            "joe@example.org",
+            "--watch",
+            "5m",
+            "-f",
+            "json",
+        ]);
+        let err = apply_defaults(&mut args, &matches, &Defaults::default()).unwrap_err();
+        assert!(err.to_string().contains("--format"), "{err}");
+
+        let (mut args, matches) = parse(&[
+            "wkd-tester",
+            "-u",
+            "joe@example.org",
+            "--watch",
+            "5m",
+            "-f",
+            "human",
+        ]);
+        apply_defaults(&mut args, &matches, &config.defaults).unwrap();
+        assert_eq!(args.format, OutputFormat::Human);
+    }
+
+    #[test]
+    fn config_file_rejects_unknown_keys_and_values() {
+        assert!(toml::from_str::<Config>("[defaults]\nformats = \"json\"\n").is_err());
+
+        let config: Config = toml::from_str("[defaults]\nformat = \"yaml\"\n").unwrap();
+        let (mut args, matches) = parse(&["wkd-tester", "-u", "joe@example.org"]);
+        assert!(apply_defaults(&mut args, &matches, &config.defaults).is_err());
+    }
+}
@@ -0,0 +1,529 @@
+use crate::policy;
+use clap::ValueEnum;
+use miette::{IntoDiagnostic, Result, miette};
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use wkd::result::{WkdMethodType, WkdResult, WkdSeverity, WkdUriResult};
+
+/// Exit code when the runs differ, like diff(1)
+const EXIT_CHANGED: u8 = 1;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum DiffFormat {
+    /// One line per change
+    #[default]
+    Human,
+    /// JSON array with one object per change
+    Json,
+}
+
+/// A difference between two runs, in the order the addresses and methods are compared
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    UserIdAdded(String),
+    UserIdRemoved(String),
+    MethodAdded {
+        user_id: String,
+        method: &'static str,
+    },
+    MethodRemoved {
+        user_id: String,
+        method: &'static str,
+    },
+    KeyAppeared {
+        user_id: String,
+        method: &'static str,
+        fingerprint: String,
+    },
+    KeyDisappeared {
+        user_id: String,
+        method: &'static str,
+        fingerprint: String,
+    },
+    FingerprintChanged {
+        user_id: String,
+        method: &'static str,
+        old: String,
+        new: String,
+    },
+    KeyFieldChanged {
+        user_id: String,
+        method: &'static str,
+        field: &'static str,
+        old: String,
+        new: String,
+    },
+    CheckFailed {
+        user_id: String,
+        method: &'static str,
+        code: String,
+        severity: WkdSeverity,
+    },
+    CheckPassed {
+        user_id: String,
+        method: &'static str,
+        code: String,
+    },
+    SkippedChanged {
+        user_id: String,
+        method: &'static str,
+        skipped: bool,
+    },
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Change::UserIdAdded(user_id) => write!(f, "{user_id}: added"),
+            Change::UserIdRemoved(user_id) => write!(f, "{user_id}: removed"),
+            Change::MethodAdded { user_id, method } => write!(f, "{user_id} {method}: added"),
+            Change::MethodRemoved { user_id, method } => write!(f, "{user_id} {method}: removed"),
+            Change::KeyAppeared {
+                user_id,
+                method,
+                fingerprint,
+            } => write!(f, "{user_id} {method}: key appeared ({fingerprint})"),
+            Change::KeyDisappeared {
+                user_id,
+                method,
+                fingerprint,
+            } => write!(f, "{user_id} {method}: key disappeared (was {fingerprint})"),
+            Change::FingerprintChanged {
+                user_id,
+                method,
+                old,
+                new,
+            } => write!(f, "{user_id} {method}: fingerprint {old} -> {new}"),
+            Change::KeyFieldChanged {
+                user_id,
+                method,
+                field,
+                old,
+                new,
+            } => write!(f, "{user_id} {method}: {field} {old} -> {new}"),
+            Change::CheckFailed {
+                user_id,
+                method,
+                code,
+                severity,
+            } => write!(f, "{user_id} {method}: {code} appeared ({severity:?})"),
+            Change::CheckPassed {
+                user_id,
+                method,
+                code,
+            } => write!(f, "{user_id} {method}: {code} resolved"),
+            Change::SkippedChanged {
+                user_id,
+                method,
+                skipped: true,
+            } => write!(f, "{user_id} {method}: now skipped"),
+            Change::SkippedChanged {
+                user_id,
+                method,
+                skipped: false,
+            } => write!(f, "{user_id} {method}: no longer skipped"),
+        }
+    }
+}
+
+impl Change {
+    fn to_json(&self) -> Value {
+        match self {
+            Change::UserIdAdded(user_id) => json!({"change": "user_id_added", "user_id": user_id}),
+            Change::UserIdRemoved(user_id) => {
+                json!({"change": "user_id_removed", "user_id": user_id})
+            }
+            Change::MethodAdded { user_id, method } => json!({
+                "change": "method_added",
+                "user_id": user_id,
+                "method": method,
+            }),
+            Change::MethodRemoved { user_id, method } => json!({
+                "change": "method_removed",
+                "user_id": user_id,
+                "method": method,
+            }),
+            Change::KeyAppeared {
+                user_id,
+                method,
+                fingerprint,
+            } => json!({
+                "change": "key_appeared",
+                "user_id": user_id,
+                "method": method,
+                "fingerprint": fingerprint,
+            }),
+            Change::KeyDisappeared {
+                user_id,
+                method,
+                fingerprint,
+            } => json!({
+                "change": "key_disappeared",
+                "user_id": user_id,
+                "method": method,
+                "fingerprint": fingerprint,
+            }),
+            Change::FingerprintChanged {
+                user_id,
+                method,
+                old,
+                new,
+            } => json!({
+                "change": "fingerprint_changed",
+                "user_id": user_id,
+                "method": method,
+                "old": old,
+                "new": new,
+            }),
+            Change::KeyFieldChanged {
+                user_id,
+                method,
+                field,
+                old,
+                new,
+            } => json!({
+                "change": format!("{field}_changed"),
+                "user_id": user_id,
+                "method": method,
+                "old": old,
+                "new": new,
+            }),
+            Change::CheckFailed {
+                user_id,
+                method,
+                code,
+                severity,
+            } => json!({
+                "change": "check_failed",
+                "user_id": user_id,
+                "method": method,
+                "code": code,
+                "severity": severity,
+            }),
+            Change::CheckPassed {
+                user_id,
+                method,
+                code,
+            } => json!({
+                "change": "check_passed",
+                "user_id": user_id,
+                "method": method,
+                "code": code,
+            }),
+            Change::SkippedChanged {
+                user_id,
+                method,
+                skipped,
+            } => json!({
+                "change": "skipped_changed",
+                "user_id": user_id,
+                "method": method,
+                "skipped": skipped,
+            }),
+        }
+    }
+}
+
+/// Reads a saved run: a single result, an array of results or one result per line
+pub fn read_results(path: &Path) -> Result<Vec<WkdResult>> {
+    let contents = std::fs::read_to_string(path).into_diagnostic()?;
+    parse_results(&contents)
+        .ok_or_else(|| miette!("{} does not hold WKD results in JSON", path.display()))
+}
+
+fn parse_results(contents: &str) -> Option<Vec<WkdResult>> {
+    if let Ok(results) = serde_json::from_str::<Vec<WkdResult>>(contents) {
+        return Some(results);
+    }
+    if let Ok(result) = serde_json::from_str::<WkdResult>(contents) {
+        return Some(vec![result]);
+    }
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str::<WkdResult>(line).ok())
+        .collect()
+}
+
+fn method_name(method_type: &WkdMethodType) -> &'static str {
+    match method_type {
+        WkdMethodType::Direct => "direct",
+        WkdMethodType::Advanced => "advanced",
+    }
+}
+
+fn find_method<'a>(result: &'a WkdResult, method: &str) -> Option<&'a WkdUriResult> {
+    result
+        .methods
+        .iter()
+        .find(|uri_result| method_name(&uri_result.method_type) == method)
+}
+
+/// Lists every change from the `old` to the `new` run
+pub fn changes(old: &[WkdResult], new: &[WkdResult]) -> Vec<Change> {
+    let old: BTreeMap<&str, &WkdResult> = old
+        .iter()
+        .map(|result| (result.user_id.as_str(), result))
+        .collect();
+    let new: BTreeMap<&str, &WkdResult> = new
+        .iter()
+        .map(|result| (result.user_id.as_str(), result))
+        .collect();
+
+    let mut changes = Vec::new();
+
+    for user_id in old.keys().filter(|user_id| !new.contains_key(*user_id)) {
+        changes.push(Change::UserIdRemoved(user_id.to_string()));
+    }
+
+    for (user_id, new_result) in &new {
+        let Some(old_result) = old.get(user_id) else {
+            changes.push(Change::UserIdAdded(user_id.to_string()));
+            continue;
+        };
+
+        for method in
+            [WkdMethodType::Direct, WkdMethodType::Advanced].map(|method| method_name(&method))
+        {
+            match (
+                find_method(old_result, method),
+                find_method(new_result, method),
+            ) {
+                (Some(old_method), Some(new_method)) => {
+                    method_changes(user_id, method, old_method, new_method, &mut changes)
+                }
+                (Some(_), None) => changes.push(Change::MethodRemoved {
+                    user_id: user_id.to_string(),
+                    method,
+                }),
+                (None, Some(_)) => changes.push(Change::MethodAdded {
+                    user_id: user_id.to_string(),
+                    method,
+                }),
+                (None, None) => {}
+            }
+        }
+    }
+
+    changes
+}
+
+fn method_changes(
+    user_id: &str,
+    method: &'static str,
+    old: &WkdUriResult,
+    new: &WkdUriResult,
+    changes: &mut Vec<Change>,
+) {
+    let user_id = user_id.to_string();
+
+    if old.skipped != new.skipped {
+        changes.push(Change::SkippedChanged {
+            user_id: user_id.clone(),
+            method,
+            skipped: new.skipped,
+        });
+    }
+
+    match (&old.key, &new.key) {
+        (None, Some(key)) => changes.push(Change::KeyAppeared {
+            user_id: user_id.clone(),
+            method,
+            fingerprint: key.fingerprint.clone(),
+        }),
+        (Some(key), None) => changes.push(Change::KeyDisappeared {
+            user_id: user_id.clone(),
+            method,
+            fingerprint: key.fingerprint.clone(),
+        }),
+        (Some(old_key), Some(new_key)) if old_key.fingerprint != new_key.fingerprint => changes
+            .push(Change::FingerprintChanged {
+                user_id: user_id.clone(),
+                method,
+                old: old_key.fingerprint.clone(),
+                new: new_key.fingerprint.clone(),
+            }),
+        (Some(old_key), Some(new_key)) => {
+            for (field, old_value, new_value) in [
+                ("expiry", &old_key.expiry, &new_key.expiry),
+                (
+                    "revocation_status",
+                    &old_key.revocation_status,
+                    &new_key.revocation_status,
+                ),
+            ] {
+                if old_value != new_value {
+                    changes.push(Change::KeyFieldChanged {
+                        user_id: user_id.clone(),
+                        method,
+                        field,
+                        old: old_value.clone(),
+                        new: new_value.clone(),
+                    });
+                }
+            }
+        }
+        (None, None) => {}
+    }
+
+    let old_codes: BTreeSet<&str> = old.errors.iter().map(|error| error.code.as_str()).collect();
+    let new_codes: BTreeSet<&str> = new.errors.iter().map(|error| error.code.as_str()).collect();
+
+    for code in new_codes.difference(&old_codes) {
+        let severity = new
+            .errors
+            .iter()
+            .find(|error| error.code == *code)
+            .map(|error| error.severity.clone())
+            .unwrap_or(WkdSeverity::Error);
+        changes.push(Change::CheckFailed {
+            user_id: user_id.clone(),
+            method,
+            code: code.to_string(),
+            severity,
+        });
+    }
+    for code in old_codes.difference(&new_codes) {
+        changes.push(Change::CheckPassed {
+            user_id: user_id.clone(),
+            method,
+            code: code.to_string(),
+        });
+    }
+}
+
+/// Prints the changes between two saved runs, exits with 1 when they differ
+pub fn diff(old: &Path, new: &Path, format: DiffFormat) -> Result<u8> {
+    let changes = changes(&read_results(old)?, &read_results(new)?);
+
+    match format {
+        DiffFormat::Human if changes.is_empty() => println!("No changes"),
+        DiffFormat::Human => {
+            for change in &changes {
+                println!("{change}");
+            }
+        }
+        DiffFormat::Json => {
+            let changes: Vec<Value> = changes.iter().map(Change::to_json).collect();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&changes).into_diagnostic()?
+            );
+        }
+    }
+
+    match changes.is_empty() {
+        true => Ok(policy::EXIT_PASS),
+        false => Ok(EXIT_CHANGED),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(user_id: &str, fingerprint: Option<&str>, codes: &[&str]) -> String {
+        let key = match fingerprint {
+            Some(fingerprint) => json!({
+                "fingerprint": fingerprint,
+                "revocation_status": "Not as far as we know",
+                "expiry": "2030-01-01",
+                "algorithm": "EdDSA",
+                "randomart": "",
+            }),
+            None => Value::Null,
+        };
+        let errors: Vec<Value> = codes
+            .iter()
+            .map(|code| json!({"name": code, "code": code, "severity": "Warning", "message": ""}))
+            .collect();
+        json!({
+            "user_id": user_id,
+            "methods": [{
+                "uri": "",
+                "key": key,
+                "errors": errors,
+                "method_type": "Direct",
+                "successes": [],
+                "timestamp": "2026-10-18T00:00:00Z",
+            }],
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn parse_results_accepts_object_array_and_ndjson() {
+        let joe = result("joe@example.org", Some("AAAA"), &[]);
+        let jane = result("jane@example.org", None, &[]);
+
+        assert_eq!(parse_results(&joe).unwrap().len(), 1);
+        assert_eq!(parse_results(&format!("[{joe},{jane}]")).unwrap().len(), 2);
+        assert_eq!(parse_results(&format!("{joe}\n{jane}\n")).unwrap().len(), 2);
+        assert!(parse_results("{}").is_none());
+    }
+
+    #[test]
+    fn changes_between_runs() {
+        let old = parse_results(&format!(
+            "{}\n{}\n{}",
+            result(
+                "joe@example.org",
+                Some("AAAA"),
+                &["wkd_fetch::policy_file_not_found"]
+            ),
+            result("jane@example.org", Some("CCCC"), &[]),
+            result("gone@example.org", None, &[]),
+        ))
+        .unwrap();
+        let new = parse_results(&format!(
+            "{}\n{}\n{}",
+            result(
+                "joe@example.org",
+                Some("BBBB"),
+                &["wkd_fetch::content_type_not_octet_stream"]
+            ),
+            result("jane@example.org", None, &[]),
+            result("new@example.org", None, &[]),
+        ))
+        .unwrap();
+
+        let changes: Vec<String> = changes(&old, &new)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                "gone@example.org: removed",
+                "jane@example.org direct: key disappeared (was CCCC)",
+                "joe@example.org direct: fingerprint AAAA -> BBBB",
+                "joe@example.org direct: wkd_fetch::content_type_not_octet_stream appeared (Warning)",
+                "joe@example.org direct: wkd_fetch::policy_file_not_found resolved",
+                "new@example.org: added",
+            ]
+        );
+        assert!(super::changes(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn changes_report_removed_methods() {
+        let direct = parse_results(&result("joe@example.org", Some("AAAA"), &[])).unwrap();
+        let mut both = direct.clone();
+        let mut advanced = direct[0].methods[0].clone();
+        advanced.method_type = WkdMethodType::Advanced;
+        both[0].methods.push(advanced);
+
+        let changes: Vec<String> = changes(&both, &direct)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(changes, vec!["joe@example.org advanced: removed"]);
+
+        let changes: Vec<String> = super::changes(&direct, &both)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(changes, vec!["joe@example.org advanced: added"]);
+    }
+}
@@ -0,0 +1,62 @@
+use clap::ValueEnum;
+use miette::{IntoDiagnostic, Result};
+use std::path::{Path, PathBuf};
+use wkd::cert::Cert;
+use wkd::generate::{WkdDirectory, WkdLayout};
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Layout {
+    /// `.well-known/openpgpkey/hu/<hash>`, served from the domain itself
+    Direct,
+    /// `.well-known/openpgpkey/<domain>/hu/<hash>`, served from `openpgpkey.<domain>`
+    #[default]
+    Advanced,
+}
+
+impl From<Layout> for WkdLayout {
+    fn from(layout: Layout) -> Self {
+        match layout {
+            Layout::Direct => WkdLayout::Direct,
+            Layout::Advanced => WkdLayout::Advanced,
+        }
+    }
+}
+
+pub fn generate(
+    key_files: &[PathBuf],
+    out_dir: &Path,
+    layout: Layout,
+    domains: &[String],
+) -> Result<()> {
+    let mut certs = Vec::new();
+    for key_file in key_files {
+        let data = std::fs::read(key_file).into_diagnostic()?;
+        certs.extend(Cert::from_bytes(&data)?);
+    }
+
+    let directory = WkdDirectory::new(&certs, layout.into(), domains)?;
+
+    for user_id in &directory.invalid_user_ids {
+        println!("Skipping user ID that can't be looked up over WKD: {user_id}");
+    }
+
+    let written = directory.write(out_dir)?;
+
+    for entry in &directory.entries {
+        println!(
+            "{} -> {} ({})",
+            entry.user_id,
+            entry.path.display(),
+            entry.fingerprints.join(", ")
+        );
+    }
+    for policy_path in directory.policy_paths() {
+        if written.contains(&policy_path) {
+            println!("Created policy file {}", policy_path.display());
+        } else {
+            println!("Kept existing policy file {}", policy_path.display());
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use crate::policy::{self, FailOn};
+use miette::{Diagnostic, Report, Result, Severity};
+use std::path::Path;
+use wkd::generate::WkdLayout;
+use wkd::lint::{WkdLint, WkdLintError};
+
+/// Lints the tree and returns the exit code matching the worst finding
+pub fn lint(path: &Path, fail_on: FailOn) -> Result<u8> {
+    let lint = WkdLint::new(path)?;
+    let code = exit_code(&lint, fail_on);
+
+    for directory in lint.directories {
+        match (directory.layout, &directory.domain) {
+            (WkdLayout::Advanced, Some(domain)) => {
+                println!("Advanced layout for {domain}: {}", directory.path.display())
+            }
+            _ => println!("Direct layout: {}", directory.path.display()),
+        }
+
+        for error in directory.errors {
+            println!("{:?}", Report::new(error));
+        }
+
+        for file in directory.files {
+            if file.user_ids.is_empty() {
+                println!("{}", file.path.display());
+            } else {
+                println!(
+                    "{} -> {} ({})",
+                    file.path.display(),
+                    file.user_ids.join(", "),
+                    file.fingerprints.join(", ")
+                );
+            }
+            for error in file.errors {
+                println!("{:?}", Report::new(error));
+            }
+        }
+    }
+
+    Ok(code)
+}
+
+/// Exit code of the worst finding, advice never fails the run
+fn exit_code(lint: &WkdLint, fail_on: FailOn) -> u8 {
+    let severities = lint
+        .errors()
+        .map(|(_, error)| WkdLintError::severity(error));
+
+    let mut exit_code = policy::EXIT_PASS;
+    for severity in severities {
+        match severity {
+            None | Some(Severity::Error) => return policy::EXIT_ERRORS,
+            Some(Severity::Warning) if fail_on == FailOn::Warning => {
+                exit_code = policy::EXIT_WARNINGS
+            }
+            Some(Severity::Warning) | Some(Severity::Advice) => {}
+        }
+    }
+    exit_code
+}
@@ -1,85 +1,351 @@
-use clap::Parser;
-use miette::Report;
-use miette::Result;
-use openpgp_tester_lib::load::load_key;
-use wkd::fetch::{WkdFetch, WkdFetchUriResult};
-use wkd::uri::WkdUri;
+mod batch;
+mod config;
+mod diff;
+mod generate;
+mod lint;
+mod output;
+mod policy;
+mod report;
+mod save;
+mod serve;
+mod verify;
+mod watch;
+
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use miette::{Result, miette};
+use output::OutputFormat;
+use policy::{FailOn, LookupStatus, RequireMethod};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
-#[command(version, author, about, long_about = None)]
+#[command(
+    version,
+    author,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    /// The GPG User ID to look up (example: Joe.Doe@example.org)
-    #[arg(short, long, required = true)]
-    user_id: String,
+    /// The GPG User ID to look up (example: Joe.Doe@example.org), can be repeated
+    #[arg(short, long, required_unless_present = "from_file")]
+    user_id: Vec<String>,
+
+    /// Read User IDs from a file, one per line (`-` reads from stdin)
+    #[arg(long, value_name = "PATH")]
+    from_file: Option<PathBuf>,
+
+    /// Maximum number of lookups running at the same time
+    #[arg(long, default_value_t = 4, env = "WKD_TESTER_CONCURRENCY")]
+    concurrency: usize,
+
+    /// Maximum number of lookups running at the same time against the same domain
+    #[arg(long, default_value_t = 2, env = "WKD_TESTER_DOMAIN_CONCURRENCY")]
+    domain_concurrency: usize,
+
+    /// Minimum delay in milliseconds between the starts of lookups against the same domain
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 0,
+        env = "WKD_TESTER_DOMAIN_DELAY"
+    )]
+    domain_delay: u64,
+
+    /// Print the full report for every address, not only the summary table
+    #[arg(long)]
+    details: bool,
+
+    /// Output format of the lookup result
+    #[arg(short, long, value_enum, default_value_t, env = "WKD_TESTER_FORMAT")]
+    format: OutputFormat,
+
+    /// Lowest severity that makes the run exit non-zero
+    #[arg(long, value_enum, default_value_t, env = "WKD_TESTER_FAIL_ON")]
+    fail_on: FailOn,
+
+    /// Which methods to run, the other method is reported as skipped
+    #[arg(short, long, value_enum, default_value_t, env = "WKD_TESTER_METHOD")]
+    method: batch::Method,
+
+    /// Which method has to pass for an address to pass
+    #[arg(long, value_enum, default_value_t, env = "WKD_TESTER_REQUIRE_METHOD")]
+    require_method: RequireMethod,
+
+    /// Re-run the lookups at this interval (example: 30s, 5m) and print only what changed
+    #[arg(long, value_name = "INTERVAL", value_parser = humantime::parse_duration)]
+    watch: Option<Duration>,
+
+    /// Shell command run when a watched run changed, the changes are in `WKD_TESTER_CHANGES`
+    #[arg(long, value_name = "COMMAND", requires = "watch")]
+    on_change: Option<String>,
+
+    /// Write the key served by each method to this directory
+    #[arg(long, value_name = "DIR", conflicts_with = "watch")]
+    save_key: Option<PathBuf>,
+
+    /// ASCII armor the saved keys
+    #[arg(long, requires = "save_key")]
+    armor: bool,
+
+    /// Config file [default: $XDG_CONFIG_HOME/wkd-tester/config.toml]
+    #[arg(long, value_name = "PATH", env = "WKD_TESTER_CONFIG", global = true)]
+    config: Option<PathBuf>,
+
+    /// Timeout of every request (example: 10s)
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, env = "WKD_TESTER_TIMEOUT", global = true)]
+    timeout: Option<Duration>,
+
+    /// Timeout of establishing a connection (example: 5s)
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, env = "WKD_TESTER_CONNECT_TIMEOUT", global = true)]
+    connect_timeout: Option<Duration>,
+
+    /// User-Agent header sent with every request
+    #[arg(long, env = "WKD_TESTER_USER_AGENT", global = true)]
+    user_agent: Option<String>,
+
+    /// PEM bundle with extra CA certificates to trust, can be repeated
+    #[arg(long, value_name = "PATH", env = "WKD_TESTER_CA_CERT", global = true)]
+    ca_cert: Vec<PathBuf>,
+
+    /// Only trust the `--ca-cert` certificates, not the system roots
+    #[arg(long, env = "WKD_TESTER_NO_SYSTEM_ROOTS", global = true)]
+    no_system_roots: bool,
+
+    /// PEM file with the client certificate (and key) for mTLS
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "WKD_TESTER_CLIENT_CERT",
+        global = true
+    )]
+    client_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of `--client-cert`
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "WKD_TESTER_CLIENT_KEY",
+        global = true
+    )]
+    client_key: Option<PathBuf>,
+
+    /// Proxy for all lookups, an http://, https://, socks5:// or socks5h:// URL
+    #[arg(long, value_name = "URL", env = "WKD_TESTER_PROXY", global = true)]
+    proxy: Option<String>,
+
+    /// Proxy for http:// requests only, wins over `--proxy`
+    #[arg(long, value_name = "URL", env = "WKD_TESTER_HTTP_PROXY", global = true)]
+    http_proxy: Option<String>,
+
+    /// Proxy for https:// requests only, wins over `--proxy`
+    #[arg(
+        long,
+        value_name = "URL",
+        env = "WKD_TESTER_HTTPS_PROXY",
+        global = true
+    )]
+    https_proxy: Option<String>,
+
+    /// Comma separated hosts, domains and IP ranges that bypass the proxies
+    #[arg(
+        long,
+        value_name = "HOSTS",
+        value_delimiter = ',',
+        env = "WKD_TESTER_NO_PROXY",
+        global = true
+    )]
+    no_proxy: Vec<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
-    let user_id = args.user_id;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Look up every user ID of a local key over WKD and compare the served certificates with it
+    Verify {
+        /// Lowest severity that makes the run exit non-zero
+        #[arg(long, value_enum, default_value_t, env = "WKD_TESTER_FAIL_ON")]
+        fail_on: FailOn,
 
-    let wkd_uri = WkdUri::new(&user_id)?;
+        /// Which methods to run, the other method is reported as skipped
+        #[arg(short, long, value_enum, default_value_t, env = "WKD_TESTER_METHOD")]
+        method: batch::Method,
 
-    println!("Advanced method URI: {}", wkd_uri.advanced_uri);
-    println!("Direct method URI: {}", wkd_uri.direct_uri);
+        /// Which method has to serve a matching certificate for a user ID to pass
+        #[arg(long, value_enum, default_value_t, env = "WKD_TESTER_REQUIRE_METHOD")]
+        require_method: RequireMethod,
 
-    let wkd_fetch = WkdFetch::fetch(&wkd_uri, None).await;
+        /// Path to the published key file (armored or binary)
+        key_file: PathBuf,
+    },
+    /// Build a `.well-known/openpgpkey` directory from public key files
+    Generate {
+        /// Directory layout to generate
+        #[arg(short, long, value_enum, default_value_t)]
+        layout: generate::Layout,
 
-    unwrap_wkd_fetch(wkd_fetch.advanced_method, "Advanced");
-    unwrap_wkd_fetch(wkd_fetch.direct_method, "Direct");
+        /// Only publish user IDs in this domain, can be repeated
+        #[arg(short, long)]
+        domain: Vec<String>,
 
-    Ok(())
+        /// Web root to write the `.well-known` directory to
+        #[arg(short, long, value_name = "DIR")]
+        output: PathBuf,
+
+        /// Public key files (armored or binary)
+        #[arg(required = true)]
+        key_files: Vec<PathBuf>,
+    },
+    /// Check a `.well-known/openpgpkey` directory on disk without any network access
+    Lint {
+        /// Lowest severity that makes the run exit non-zero
+        #[arg(long, value_enum, default_value_t, env = "WKD_TESTER_FAIL_ON")]
+        fail_on: FailOn,
+
+        /// Web root or `.well-known/openpgpkey` directory to check
+        path: PathBuf,
+    },
+    /// Serve a directory like a WKD server would, to test clients against it
+    Serve(serve::ServeArgs),
+    /// Compare two runs saved with `--format json` or `--format ndjson`
+    ///
+    /// Saved runs hold the fingerprint, expiry and revocation status of each key but not its user IDs, so user IDs
+    /// added to or removed from a key with the same fingerprint are not reported.
+    Diff {
+        /// Output format of the changes
+        #[arg(short, long, value_enum, default_value_t)]
+        format: diff::DiffFormat,
+
+        /// The earlier run
+        old: PathBuf,
+
+        /// The later run
+        new: PathBuf,
+    },
 }
 
-fn unwrap_wkd_fetch(wkd_fetch: WkdFetchUriResult, method: &str) {
-    if wkd_fetch.data.is_none() {
-        println!("{method} method fetch failed with following errors:");
-        for error in wkd_fetch.errors {
-            println!("{:?}", Report::new(error));
+impl Args {
+    fn batch_limits(&self) -> batch::BatchLimits {
+        batch::BatchLimits {
+            concurrency: self.concurrency,
+            domain_concurrency: self.domain_concurrency,
+            domain_delay: Duration::from_millis(self.domain_delay),
         }
-        return;
     }
+}
 
-    if !wkd_fetch.errors.is_empty() {
-        println!("{method} method fetch was successful with warnings:");
-        for error in wkd_fetch.errors {
-            println!("{:?}", Report::new(error));
+/// Exits with 0 when all checks passed, 1 for warnings only and 2 for errors
+#[tokio::main]
+async fn main() -> ExitCode {
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+
+    match run(args, matches).await {
+        Ok(code) => ExitCode::from(code),
+        Err(err) => {
+            eprintln!("{err:?}");
+            ExitCode::from(policy::EXIT_ERRORS)
         }
     }
+}
 
-    println!("{method} tests:");
-    for success in wkd_fetch.successes {
-        println!(" - {success:?} Passed")
+fn check_methods(require_method: RequireMethod, method: batch::Method) -> Result<()> {
+    if matches!(
+        (require_method, method),
+        (RequireMethod::Direct, batch::Method::Advanced)
+            | (RequireMethod::Advanced, batch::Method::Direct)
+    ) {
+        return Err(miette!(
+            "--require-method can't require a method that --method skips"
+        ));
     }
+    Ok(())
+}
+
+async fn run(mut args: Args, matches: ArgMatches) -> Result<u8> {
+    let config = config::Config::load(args.config.as_deref())?;
+    config::apply_defaults(&mut args, &matches, &config.defaults)?;
+    // Only the subcommands that fetch build a client, so a bad `[client]` table doesn't break the others
+    let reqwest_client = || -> Result<reqwest::Client> {
+        Ok(config::fetch_config(&args, &config.client)?.client()?)
+    };
 
-    if let Some(data) = wkd_fetch.data {
-        match load_key(data) {
-            Ok(key) => {
-                println!(
-                    "{method} method key loading succeed with fingerprint: {}",
-                    key.fingerprint
-                );
-                println!(
-                    "{method} method key loading succeed with revocation status: {}",
-                    key.revocation_status
-                );
-                println!(
-                    "{method} method key loading succeed with expiry status: {}",
-                    key.expiry
-                );
-                println!(
-                    "{method} method key loading succeed with algorithm: {}",
-                    key.algorithm
-                );
-                println!(
-                    "{method} method key loading succeed with randomart:\n{}",
-                    key.randomart
-                );
-            }
-            Err(error) => {
-                println!("{method} method key loading failed with following errors:");
-                println!("{:?}", Report::new(error));
-            }
-        };
+    match &args.command {
+        Some(Command::Verify {
+            fail_on,
+            method,
+            require_method,
+            key_file,
+        }) => {
+            check_methods(*require_method, *method)?;
+            return verify::verify(
+                key_file,
+                reqwest_client()?,
+                (*method).into(),
+                *require_method,
+                *fail_on,
+            )
+            .await;
+        }
+        Some(Command::Generate {
+            layout,
+            domain,
+            output,
+            key_files,
+        }) => {
+            generate::generate(key_files, output, *layout, domain)?;
+            return Ok(policy::EXIT_PASS);
+        }
+        Some(Command::Lint { fail_on, path }) => return lint::lint(path, *fail_on),
+        Some(Command::Serve(serve_args)) => return serve::serve(serve_args).await,
+        Some(Command::Diff { format, old, new }) => return diff::diff(old, new, *format),
+        None => {}
+    }
+
+    check_methods(args.require_method, args.method)?;
+    let reqwest_client = reqwest_client()?;
+
+    let mut user_ids = args.user_id.clone();
+    if let Some(from_file) = &args.from_file {
+        user_ids.extend(batch::read_user_ids(from_file)?);
     }
+
+    if let Some(interval) = args.watch {
+        return watch::watch(&args, user_ids, interval, reqwest_client).await;
+    }
+
+    let lookups = batch::lookup_all(
+        user_ids,
+        reqwest_client,
+        args.batch_limits(),
+        args.method.into(),
+    )
+    .await;
+    let statuses: Vec<LookupStatus> = lookups.iter().map(LookupStatus::from).collect();
+
+    if let Some(dir) = &args.save_key {
+        for saved_key in save::save_keys(dir, &lookups, args.armor)? {
+            eprintln!(
+                "Saved {} (sha256: {})",
+                saved_key.path.display(),
+                saved_key.sha256
+            );
+        }
+    }
+
+    match args.format {
+        OutputFormat::Human => output::print_human(lookups, &statuses, args.details)?,
+        OutputFormat::Json | OutputFormat::Ndjson => output::print_json(lookups, args.format)?,
+        format => report::print_report(&lookups, format, args.fail_on)?,
+    }
+
+    Ok(policy::exit_code(
+        &statuses,
+        args.require_method,
+        args.fail_on,
+    ))
 }
@@ -0,0 +1,192 @@
+use crate::batch::Lookup;
+use crate::policy::{LookupStatus, MethodStatus};
+use clap::ValueEnum;
+use miette::{IntoDiagnostic, Report, Result};
+use openpgp_tester_lib::load::load_key;
+use wkd::fetch::WkdFetchUriResult;
+use wkd::result::WkdResult;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    /// Human readable report
+    #[default]
+    Human,
+    /// Pretty printed JSON array of results with the same shape as the server API
+    Json,
+    /// Newline delimited JSON, one result per line
+    Ndjson,
+    /// JUnit XML with one test case per method and check
+    Junit,
+    /// Test Anything Protocol with one test per method and check
+    Tap,
+    /// SARIF 2.1.0 log with one result per method and check
+    Sarif,
+}
+
+struct SummaryRow<'a> {
+    user_id: &'a str,
+    status: &'a LookupStatus,
+}
+
+pub fn print_json(lookups: Vec<Lookup>, format: OutputFormat) -> Result<()> {
+    let results: Vec<WkdResult> = lookups.into_iter().map(Lookup::into_wkd_result).collect();
+
+    if format == OutputFormat::Ndjson {
+        for result in results {
+            println!("{}", serde_json::to_string(&result).into_diagnostic()?);
+        }
+        return Ok(());
+    }
+
+    // Always an array, so the shape does not depend on how many addresses were looked up
+    let json = serde_json::to_string_pretty(&results).into_diagnostic()?;
+    println!("{json}");
+    Ok(())
+}
+
+/// Prints per-address details when asked for (or for a single lookup) and a summary table for batches
+pub fn print_human(
+    mut lookups: Vec<Lookup>,
+    statuses: &[LookupStatus],
+    details: bool,
+) -> Result<()> {
+    if lookups.len() == 1 {
+        let lookup = lookups.remove(0);
+        let (wkd_uri, wkd_fetch) = lookup.result?;
+        println!("Advanced method URI: {}", wkd_uri.advanced_uri);
+        println!("Direct method URI: {}", wkd_uri.direct_uri);
+
+        unwrap_wkd_fetch(wkd_fetch.advanced_method, "Advanced");
+        unwrap_wkd_fetch(wkd_fetch.direct_method, "Direct");
+        return Ok(());
+    }
+
+    let user_ids: Vec<String> = lookups
+        .iter()
+        .map(|lookup| lookup.user_id.clone())
+        .collect();
+    let rows: Vec<SummaryRow> = user_ids
+        .iter()
+        .zip(statuses)
+        .map(|(user_id, status)| SummaryRow { user_id, status })
+        .collect();
+
+    if details {
+        for lookup in lookups {
+            println!("User ID: {}", lookup.user_id);
+            match lookup.result {
+                Ok((wkd_uri, wkd_fetch)) => {
+                    println!("Advanced method URI: {}", wkd_uri.advanced_uri);
+                    println!("Direct method URI: {}", wkd_uri.direct_uri);
+
+                    unwrap_wkd_fetch(wkd_fetch.advanced_method, "Advanced");
+                    unwrap_wkd_fetch(wkd_fetch.direct_method, "Direct");
+                }
+                Err(err) => println!("{:?}", Report::new(err)),
+            }
+            println!();
+        }
+    }
+
+    print_summary(&rows);
+    Ok(())
+}
+
+fn print_summary(rows: &[SummaryRow]) {
+    let width = rows
+        .iter()
+        .map(|row| row.user_id.chars().count())
+        .chain(["USER ID".len()])
+        .max()
+        .unwrap_or_default();
+
+    println!("{:width$}  {:8}  DIRECT", "USER ID", "ADVANCED");
+    for row in rows {
+        println!(
+            "{:width$}  {:8}  {}",
+            row.user_id, row.status.advanced, row.status.direct
+        );
+    }
+
+    let count = |status: MethodStatus, advanced: bool| {
+        rows.iter()
+            .filter(|row| {
+                if advanced {
+                    row.status.advanced == status
+                } else {
+                    row.status.direct == status
+                }
+            })
+            .count()
+    };
+
+    println!();
+    for (method, advanced) in [("Advanced", true), ("Direct", false)] {
+        println!(
+            "{method} method: {} ok, {} warnings, {} failed, {} invalid, {} skipped",
+            count(MethodStatus::Ok, advanced),
+            count(MethodStatus::Warnings, advanced),
+            count(MethodStatus::Failed, advanced),
+            count(MethodStatus::Invalid, advanced),
+            count(MethodStatus::Skipped, advanced)
+        );
+    }
+}
+
+fn unwrap_wkd_fetch(wkd_fetch: WkdFetchUriResult, method: &str) {
+    if wkd_fetch.skipped {
+        println!("{method} method skipped");
+        return;
+    }
+
+    if wkd_fetch.data.is_none() {
+        println!("{method} method fetch failed with following errors:");
+        for error in wkd_fetch.errors {
+            println!("{:?}", Report::new(error));
+        }
+        return;
+    }
+
+    if !wkd_fetch.errors.is_empty() {
+        println!("{method} method fetch was successful with warnings:");
+        for error in wkd_fetch.errors {
+            println!("{:?}", Report::new(error));
+        }
+    }
+
+    println!("{method} tests:");
+    for success in wkd_fetch.successes {
+        println!(" - {success:?} Passed")
+    }
+
+    if let Some(data) = wkd_fetch.data {
+        match load_key(data) {
+            Ok(key) => {
+                println!(
+                    "{method} method key loading succeed with fingerprint: {}",
+                    key.fingerprint
+                );
+                println!(
+                    "{method} method key loading succeed with revocation status: {}",
+                    key.revocation_status
+                );
+                println!(
+                    "{method} method key loading succeed with expiry status: {}",
+                    key.expiry
+                );
+                println!(
+                    "{method} method key loading succeed with algorithm: {}",
+                    key.algorithm
+                );
+                println!(
+                    "{method} method key loading succeed with randomart:\n{}",
+                    key.randomart
+                );
+            }
+            Err(error) => {
+                println!("{method} method key loading failed with following errors:");
+                println!("{:?}", Report::new(error));
+            }
+        };
+    }
+}
@@ -0,0 +1,174 @@
+use crate::batch::Lookup;
+use clap::ValueEnum;
+use miette::{Diagnostic, Severity};
+use openpgp_tester_lib::load::load_key;
+use wkd::fetch::WkdFetchUriResult;
+
+/// Exit code when every required check passed
+pub const EXIT_PASS: u8 = 0;
+/// Exit code when only warnings were found and `--fail-on warning` is set
+pub const EXIT_WARNINGS: u8 = 1;
+/// Exit code when errors were found or the run itself failed
+pub const EXIT_ERRORS: u8 = 2;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum FailOn {
+    /// Exit non-zero on warnings and errors
+    #[default]
+    Warning,
+    /// Exit non-zero on errors only
+    Error,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum RequireMethod {
+    /// The direct method has to pass
+    Direct,
+    /// The advanced method has to pass
+    Advanced,
+    /// At least one of the methods has to pass
+    #[default]
+    Any,
+}
+
+/// Outcome of one method, ordered from best to worst.
+///
+/// Skipped sorts last so `--require-method any` picks the method that ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MethodStatus {
+    Ok,
+    Warnings,
+    Failed,
+    Invalid,
+    Skipped,
+}
+
+impl std::fmt::Display for MethodStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let status = match self {
+            MethodStatus::Ok => "ok",
+            MethodStatus::Warnings => "warnings",
+            MethodStatus::Failed => "failed",
+            MethodStatus::Invalid => "invalid",
+            MethodStatus::Skipped => "skipped",
+        };
+        f.pad(status)
+    }
+}
+
+impl MethodStatus {
+    /// Failed when no key was served, the key doesn't load or an error level diagnostic was raised
+    pub fn from(wkd_fetch: &WkdFetchUriResult) -> Self {
+        if wkd_fetch.skipped {
+            return MethodStatus::Skipped;
+        }
+        let Some(data) = &wkd_fetch.data else {
+            return MethodStatus::Failed;
+        };
+        if load_key(data.clone()).is_err() {
+            return MethodStatus::Failed;
+        }
+
+        let severities = wkd_fetch.errors.iter().map(Diagnostic::severity);
+        let mut status = MethodStatus::Ok;
+        for severity in severities {
+            match severity {
+                None | Some(Severity::Error) => return MethodStatus::Failed,
+                Some(Severity::Warning) => status = MethodStatus::Warnings,
+                Some(Severity::Advice) => {}
+            }
+        }
+        status
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LookupStatus {
+    pub advanced: MethodStatus,
+    pub direct: MethodStatus,
+}
+
+impl LookupStatus {
+    pub fn from(lookup: &Lookup) -> Self {
+        match &lookup.result {
+            Ok((_, wkd_fetch)) => LookupStatus {
+                advanced: MethodStatus::from(&wkd_fetch.advanced_method),
+                direct: MethodStatus::from(&wkd_fetch.direct_method),
+            },
+            Err(_) => LookupStatus {
+                advanced: MethodStatus::Invalid,
+                direct: MethodStatus::Invalid,
+            },
+        }
+    }
+
+    fn required(&self, require_method: RequireMethod) -> MethodStatus {
+        match require_method {
+            RequireMethod::Direct => self.direct,
+            RequireMethod::Advanced => self.advanced,
+            RequireMethod::Any => self.direct.min(self.advanced),
+        }
+    }
+}
+
+/// Maps the worst required method status of a run to its exit code
+pub fn exit_code(statuses: &[LookupStatus], require_method: RequireMethod, fail_on: FailOn) -> u8 {
+    let worst = statuses
+        .iter()
+        .map(|status| status.required(require_method))
+        .max()
+        .unwrap_or(MethodStatus::Ok);
+
+    match worst {
+        MethodStatus::Ok | MethodStatus::Skipped => EXIT_PASS,
+        MethodStatus::Warnings if fail_on == FailOn::Error => EXIT_PASS,
+        MethodStatus::Warnings => EXIT_WARNINGS,
+        MethodStatus::Failed | MethodStatus::Invalid => EXIT_ERRORS,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(advanced: MethodStatus, direct: MethodStatus) -> LookupStatus {
+        LookupStatus { advanced, direct }
+    }
+
+    #[test]
+    fn exit_code_follows_required_method() {
+        let statuses = [status(MethodStatus::Failed, MethodStatus::Ok)];
+        assert_eq!(exit_code(&statuses, RequireMethod::Any, FailOn::Warning), 0);
+        assert_eq!(
+            exit_code(&statuses, RequireMethod::Direct, FailOn::Warning),
+            0
+        );
+        assert_eq!(
+            exit_code(&statuses, RequireMethod::Advanced, FailOn::Warning),
+            2
+        );
+    }
+
+    #[test]
+    fn exit_code_ignores_skipped_method() {
+        let statuses = [status(MethodStatus::Skipped, MethodStatus::Warnings)];
+        assert_eq!(exit_code(&statuses, RequireMethod::Any, FailOn::Warning), 1);
+        assert_eq!(
+            exit_code(&statuses, RequireMethod::Direct, FailOn::Warning),
+            1
+        );
+    }
+
+    #[test]
+    fn exit_code_follows_fail_on() {
+        let statuses = [
+            status(MethodStatus::Warnings, MethodStatus::Warnings),
+            status(MethodStatus::Ok, MethodStatus::Ok),
+        ];
+        assert_eq!(exit_code(&statuses, RequireMethod::Any, FailOn::Warning), 1);
+        assert_eq!(exit_code(&statuses, RequireMethod::Any, FailOn::Error), 0);
+
+        let statuses = [status(MethodStatus::Invalid, MethodStatus::Invalid)];
+        assert_eq!(exit_code(&statuses, RequireMethod::Any, FailOn::Error), 2);
+    }
+}
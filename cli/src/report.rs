@@ -0,0 +1,564 @@
+use crate::batch::Lookup;
+use crate::output::OutputFormat;
+use crate::policy::FailOn;
+use miette::{Diagnostic, IntoDiagnostic, Result, Severity};
+use openpgp_tester_lib::load::load_key;
+use serde_json::{Value, json};
+use wkd::fetch::{WkdCheck, WkdFetchUriResult};
+
+const KEY_LOAD_CHECK: &str = "key-load";
+
+pub enum Outcome {
+    Passed(Option<String>),
+    Failed(Finding),
+    Skipped(&'static str),
+}
+
+pub struct Finding {
+    pub code: String,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Finding {
+    fn from<Error: Diagnostic + ?Sized>(error: &Error, fallback_code: &str) -> Self {
+        Finding {
+            code: match error.code() {
+                Some(code) => code.to_string(),
+                None => fallback_code.to_string(),
+            },
+            severity: error.severity().unwrap_or(Severity::Error),
+            message: error.to_string(),
+        }
+    }
+
+    fn level(&self) -> &'static str {
+        match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Advice => "note",
+        }
+    }
+
+    /// Advice never fails a check, warnings only do with `--fail-on warning`
+    fn fails(&self, fail_on: FailOn) -> bool {
+        match self.severity {
+            Severity::Error => true,
+            Severity::Warning => fail_on == FailOn::Warning,
+            Severity::Advice => false,
+        }
+    }
+}
+
+/// One check of one method of one address
+pub struct Case {
+    pub user_id: String,
+    pub method: &'static str,
+    pub uri: String,
+    pub check: &'static str,
+    pub outcomes: Vec<Outcome>,
+}
+
+impl Case {
+    fn failures(&self, fail_on: FailOn) -> impl Iterator<Item = &Finding> {
+        self.outcomes
+            .iter()
+            .filter_map(move |outcome| match outcome {
+                Outcome::Failed(finding) if finding.fails(fail_on) => Some(finding),
+                _ => None,
+            })
+    }
+
+    pub fn findings(&self) -> impl Iterator<Item = &Finding> {
+        self.outcomes.iter().filter_map(|outcome| match outcome {
+            Outcome::Failed(finding) => Some(finding),
+            _ => None,
+        })
+    }
+
+    pub fn skipped(&self) -> Option<&'static str> {
+        match self.outcomes.as_slice() {
+            [Outcome::Skipped(reason)] => Some(reason),
+            _ => None,
+        }
+    }
+
+    fn passed_message(&self) -> Option<&str> {
+        self.outcomes.iter().find_map(|outcome| match outcome {
+            Outcome::Passed(message) => message.as_deref(),
+            _ => None,
+        })
+    }
+
+    fn name(&self) -> String {
+        format!("{} {} {}", self.user_id, self.method, self.check)
+    }
+}
+
+/// Every check of every method of the given lookups, invalid addresses get a single `user-id` case
+pub fn cases(lookups: &[Lookup]) -> Vec<Case> {
+    let mut cases = Vec::new();
+
+    for lookup in lookups {
+        let (wkd_uri, wkd_fetch) = match &lookup.result {
+            Ok(result) => result,
+            Err(error) => {
+                cases.push(Case {
+                    user_id: lookup.user_id.clone(),
+                    method: "address",
+                    uri: String::new(),
+                    check: "user-id",
+                    outcomes: vec![Outcome::Failed(Finding::from(error, "wkd_uri"))],
+                });
+                continue;
+            }
+        };
+
+        method_cases(
+            &mut cases,
+            &lookup.user_id,
+            "advanced",
+            wkd_uri.advanced_uri.to_string(),
+            &wkd_fetch.advanced_method,
+        );
+        method_cases(
+            &mut cases,
+            &lookup.user_id,
+            "direct",
+            wkd_uri.direct_uri.to_string(),
+            &wkd_fetch.direct_method,
+        );
+    }
+
+    cases
+}
+
+fn method_cases(
+    cases: &mut Vec<Case>,
+    user_id: &str,
+    method: &'static str,
+    uri: String,
+    wkd_fetch: &WkdFetchUriResult,
+) {
+    let case = |check: &'static str, outcomes: Vec<Outcome>| Case {
+        user_id: user_id.to_string(),
+        method,
+        uri: uri.clone(),
+        check,
+        outcomes,
+    };
+
+    if wkd_fetch.skipped {
+        for check in WkdCheck::ALL {
+            cases.push(case(
+                check.name(),
+                vec![Outcome::Skipped("method not selected")],
+            ));
+        }
+        cases.push(case(
+            KEY_LOAD_CHECK,
+            vec![Outcome::Skipped("method not selected")],
+        ));
+        return;
+    }
+
+    for check in WkdCheck::ALL {
+        let mut outcomes: Vec<Outcome> = wkd_fetch
+            .errors
+            .iter()
+            .filter(|error| error.check() == check)
+            .map(|error| Outcome::Failed(Finding::from(error, check.name())))
+            .collect();
+
+        if outcomes.is_empty() {
+            let passed = wkd_fetch
+                .successes
+                .iter()
+                .any(|success| success.check() == check)
+                || (check == WkdCheck::Fetch && wkd_fetch.data.is_some());
+
+            outcomes.push(if passed {
+                Outcome::Passed(None)
+            } else {
+                Outcome::Skipped("not run because an earlier check failed")
+            });
+        }
+
+        cases.push(case(check.name(), outcomes));
+    }
+
+    let key_load = match &wkd_fetch.data {
+        None => Outcome::Skipped("no key was served"),
+        Some(data) => match load_key(data.clone()) {
+            Ok(key) => Outcome::Passed(Some(format!("fingerprint {}", key.fingerprint))),
+            Err(error) => Outcome::Failed(Finding::from(&error, KEY_LOAD_CHECK)),
+        },
+    };
+    cases.push(case(KEY_LOAD_CHECK, vec![key_load]));
+}
+
+pub fn print_report(lookups: &[Lookup], format: OutputFormat, fail_on: FailOn) -> Result<()> {
+    let cases = cases(lookups);
+
+    match format {
+        OutputFormat::Junit => print!("{}", junit(&cases, fail_on)),
+        OutputFormat::Tap => print!("{}", tap(&cases, fail_on)),
+        OutputFormat::Sarif => println!(
+            "{}",
+            serde_json::to_string_pretty(&sarif(&cases, fail_on)).into_diagnostic()?
+        ),
+        OutputFormat::Human | OutputFormat::Json | OutputFormat::Ndjson => {
+            unreachable!("not a report format")
+        }
+    }
+
+    Ok(())
+}
+
+fn xml_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Double-quoted YAML scalar, non-printable characters use the escapes of
+/// <https://yaml.org/spec/1.2.2/#57-escaped-characters>
+fn yaml_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\u{0}'..='\u{1f}' | '\u{7f}'..='\u{9f}' => {
+                quoted.push_str(&format!("\\x{:02x}", c as u32))
+            }
+            '\u{2028}' | '\u{2029}' | '\u{feff}' | '\u{fffe}' | '\u{ffff}' => {
+                quoted.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// One test suite per address, one test case per method and check
+fn junit(cases: &[Case], fail_on: FailOn) -> String {
+    let mut suites: Vec<(&str, Vec<&Case>)> = Vec::new();
+    for case in cases {
+        match suites.last_mut() {
+            Some((user_id, suite)) if *user_id == case.user_id => suite.push(case),
+            _ => suites.push((&case.user_id, vec![case])),
+        }
+    }
+
+    let total_failures = cases
+        .iter()
+        .filter(|case| case.failures(fail_on).next().is_some())
+        .count();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuites name=\"wkd-tester\" tests=\"{}\" failures=\"{total_failures}\">\n",
+        cases.len()
+    ));
+
+    for (user_id, suite) in suites {
+        let failures = suite
+            .iter()
+            .filter(|case| case.failures(fail_on).next().is_some())
+            .count();
+        let skipped = suite.iter().filter(|case| case.skipped().is_some()).count();
+
+        xml.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{failures}\" skipped=\"{skipped}\">\n",
+            xml_escape(user_id),
+            suite.len()
+        ));
+
+        for case in suite {
+            xml.push_str(&format!(
+                "    <testcase classname=\"{}\" name=\"{}\" file=\"{}\">\n",
+                xml_escape(&format!("{}.{}", case.user_id, case.method)),
+                case.check,
+                xml_escape(&case.uri)
+            ));
+
+            if let Some(reason) = case.skipped() {
+                xml.push_str(&format!(
+                    "      <skipped message=\"{}\"/>\n",
+                    xml_escape(reason)
+                ));
+            }
+
+            for finding in case.failures(fail_on) {
+                xml.push_str(&format!(
+                    "      <failure type=\"{}\" message=\"{}\">{} {}: {}</failure>\n",
+                    xml_escape(&finding.code),
+                    xml_escape(&finding.message),
+                    finding.level(),
+                    xml_escape(&case.uri),
+                    xml_escape(&finding.message)
+                ));
+            }
+
+            let notes: Vec<String> = case
+                .findings()
+                .filter(|finding| !finding.fails(fail_on))
+                .map(|finding| format!("{}: {}", finding.code, finding.message))
+                .chain(case.passed_message().map(str::to_string))
+                .collect();
+            if !notes.is_empty() {
+                xml.push_str(&format!(
+                    "      <system-out>{}</system-out>\n",
+                    xml_escape(&notes.join("\n"))
+                ));
+            }
+
+            xml.push_str("    </testcase>\n");
+        }
+
+        xml.push_str("  </testsuite>\n");
+    }
+
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+fn tap(cases: &[Case], fail_on: FailOn) -> String {
+    let mut tap = format!("TAP version 13\n1..{}\n", cases.len());
+
+    for (index, case) in cases.iter().enumerate() {
+        let number = index + 1;
+        let failed = case.failures(fail_on).next().is_some();
+
+        match (failed, case.skipped()) {
+            (_, Some(reason)) => {
+                tap.push_str(&format!("ok {number} - {} # SKIP {reason}\n", case.name()))
+            }
+            (true, None) => tap.push_str(&format!("not ok {number} - {}\n", case.name())),
+            (false, None) => tap.push_str(&format!("ok {number} - {}\n", case.name())),
+        }
+
+        let findings: Vec<&Finding> = case.findings().collect();
+        if findings.is_empty() {
+            continue;
+        }
+
+        tap.push_str("  ---\n");
+        tap.push_str(&format!("  method: {}\n", case.method));
+        tap.push_str(&format!("  uri: {}\n", yaml_string(&case.uri)));
+        tap.push_str("  diagnostics:\n");
+        for finding in findings {
+            tap.push_str(&format!("    - code: {}\n", yaml_string(&finding.code)));
+            tap.push_str(&format!("      severity: {}\n", finding.level()));
+            tap.push_str(&format!(
+                "      message: {}\n",
+                yaml_string(&finding.message)
+            ));
+        }
+        tap.push_str("  ...\n");
+    }
+
+    tap
+}
+
+/// SARIF 2.1.0 log with one result per check, passing checks are reported with kind `pass`
+fn sarif(cases: &[Case], fail_on: FailOn) -> Value {
+    let mut rules: Vec<Value> = Vec::new();
+    let mut rule_ids: Vec<&str> = Vec::new();
+    let mut results: Vec<Value> = Vec::new();
+
+    let location = |case: &Case| {
+        json!([{
+            "physicalLocation": { "artifactLocation": { "uri": case.uri } },
+            "logicalLocations": [{
+                "name": case.check,
+                "fullyQualifiedName": case.name(),
+            }],
+        }])
+    };
+    let properties = |case: &Case| {
+        json!({
+            "userId": case.user_id,
+            "method": case.method,
+            "check": case.check,
+        })
+    };
+
+    for case in cases {
+        let findings: Vec<&Finding> = case.findings().collect();
+
+        for finding in &findings {
+            if !rule_ids.contains(&finding.code.as_str()) {
+                rule_ids.push(&finding.code);
+                rules.push(json!({
+                    "id": finding.code,
+                    "shortDescription": { "text": finding.message },
+                    "defaultConfiguration": { "level": finding.level() },
+                }));
+            }
+
+            let fails = finding.fails(fail_on);
+            results.push(json!({
+                "ruleId": finding.code,
+                // SARIF only allows a level other than `none` on failing results
+                "kind": if fails { "fail" } else { "review" },
+                "level": if fails { finding.level() } else { "none" },
+                "message": { "text": finding.message },
+                "locations": location(case),
+                "properties": properties(case),
+            }));
+        }
+
+        if !findings.is_empty() {
+            continue;
+        }
+
+        let (kind, text) = match case.skipped() {
+            Some(reason) => ("notApplicable", reason.to_string()),
+            None => (
+                "pass",
+                case.passed_message()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("{} check passed", case.check)),
+            ),
+        };
+        results.push(json!({
+            "ruleId": format!("wkd_check::{}", case.check),
+            "kind": kind,
+            "level": "none",
+            "message": { "text": text },
+            "locations": location(case),
+            "properties": properties(case),
+        }));
+    }
+
+    json!({
+        "version": "2.1.0",
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "wkd-tester",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "rules": rules,
+                }
+            },
+            "results": results,
+        }]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finding(severity: Severity) -> Finding {
+        Finding {
+            code: "wkd_fetch::access_control_allow_origin_not_star".to_string(),
+            severity,
+            message: "Access-Control-Allow-Origin header is not set to '*'".to_string(),
+        }
+    }
+
+    fn case(outcomes: Vec<Outcome>) -> Case {
+        Case {
+            user_id: "joe@example.org".to_string(),
+            method: "direct",
+            uri: "https://example.org/.well-known/openpgpkey/hu/x?l=joe&a=1".to_string(),
+            check: "cors",
+            outcomes,
+        }
+    }
+
+    #[test]
+    fn junit_escapes_and_honours_fail_on() {
+        let cases = [case(vec![Outcome::Failed(finding(Severity::Warning))])];
+
+        let xml = junit(&cases, FailOn::Warning);
+        assert!(xml.contains("failures=\"1\""));
+        assert!(
+            xml.contains("file=\"https://example.org/.well-known/openpgpkey/hu/x?l=joe&amp;a=1\"")
+        );
+        assert!(xml.contains(
+            "message=\"Access-Control-Allow-Origin header is not set to &apos;*&apos;\""
+        ));
+
+        let xml = junit(&cases, FailOn::Error);
+        assert!(xml.contains("failures=\"0\""));
+        assert!(xml.contains("<system-out>"));
+    }
+
+    #[test]
+    fn tap_reports_skips_and_failures() {
+        let cases = [
+            case(vec![Outcome::Failed(finding(Severity::Error))]),
+            case(vec![Outcome::Skipped("no key was served")]),
+            case(vec![Outcome::Passed(None)]),
+        ];
+
+        let tap = tap(&cases, FailOn::Warning);
+        assert!(tap.starts_with("TAP version 13\n1..3\n"));
+        assert!(tap.contains("not ok 1 - joe@example.org direct cors\n"));
+        assert!(tap.contains("ok 2 - joe@example.org direct cors # SKIP no key was served\n"));
+        assert!(tap.contains("ok 3 - joe@example.org direct cors\n"));
+        assert!(
+            tap.contains(
+                "      message: \"Access-Control-Allow-Origin header is not set to '*'\"\n"
+            )
+        );
+    }
+
+    #[test]
+    fn yaml_string_escapes() {
+        assert_eq!(yaml_string("plain"), "\"plain\"");
+        assert_eq!(
+            yaml_string("say \"hi\" \\ bye\n"),
+            "\"say \\\"hi\\\" \\\\ bye\\n\""
+        );
+        // Rust's `{:?}` would write `\u{1b}` and `\u{2028}`, which YAML can't parse
+        assert_eq!(
+            yaml_string("\u{1b}[0m \u{2028} é"),
+            "\"\\x1b[0m \\u2028 é\""
+        );
+    }
+
+    #[test]
+    fn sarif_has_one_result_per_check() {
+        let cases = [
+            case(vec![Outcome::Failed(finding(Severity::Warning))]),
+            case(vec![Outcome::Passed(None)]),
+        ];
+
+        let sarif = sarif(&cases, FailOn::Warning);
+        let results = sarif["runs"][0]["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["level"], "warning");
+        assert_eq!(results[0]["kind"], "fail");
+        assert_eq!(results[1]["kind"], "pass");
+        assert_eq!(results[1]["level"], "none");
+        assert_eq!(
+            sarif["runs"][0]["tool"]["driver"]["rules"][0]["id"],
+            "wkd_fetch::access_control_allow_origin_not_star"
+        );
+
+        let sarif = super::sarif(&cases, FailOn::Error);
+        let results = sarif["runs"][0]["results"].as_array().unwrap();
+        assert_eq!(results[0]["kind"], "review");
+        assert_eq!(results[0]["level"], "none");
+    }
+}
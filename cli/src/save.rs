@@ -0,0 +1,153 @@
+use crate::batch::Lookup;
+use miette::{IntoDiagnostic, Result};
+use openpgp_tester_lib::load::load_key;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use wkd::cert::{self, Cert};
+use wkd::fetch::WkdFetchUriResult;
+
+/// A key file written to disk
+#[derive(Debug)]
+pub struct SavedKey {
+    pub path: PathBuf,
+    pub sha256: String,
+}
+
+/// Writes the response of every method that returned data to `dir`.
+///
+/// Files are named `<method>-<fingerprint>.pgp` (`.asc` when armored). When more than one address
+/// is looked up every address gets its own subdirectory.
+pub fn save_keys(dir: &Path, lookups: &[Lookup], armor: bool) -> Result<Vec<SavedKey>> {
+    let mut saved = Vec::new();
+
+    for lookup in lookups {
+        let Ok((_, wkd_fetch)) = &lookup.result else {
+            continue;
+        };
+
+        let dir = match lookups.len() {
+            1 => dir.to_path_buf(),
+            _ => dir.join(lookup.user_id.replace(['/', '\\'], "_")),
+        };
+
+        for (method, wkd_fetch) in [
+            ("advanced", &wkd_fetch.advanced_method),
+            ("direct", &wkd_fetch.direct_method),
+        ] {
+            if let Some(saved_key) = save_key(&dir, method, wkd_fetch, armor)? {
+                saved.push(saved_key);
+            }
+        }
+    }
+
+    Ok(saved)
+}
+
+fn save_key(
+    dir: &Path,
+    method: &str,
+    wkd_fetch: &WkdFetchUriResult,
+    armor: bool,
+) -> Result<Option<SavedKey>> {
+    let Some(data) = &wkd_fetch.data else {
+        return Ok(None);
+    };
+
+    let fingerprint = match load_key(data.clone()) {
+        Ok(key) => key.fingerprint,
+        Err(_) => "unknown".to_string(),
+    };
+
+    // Already armored responses are kept as served
+    let (contents, extension) = if cert::is_armored(data) {
+        (data.to_vec(), "asc")
+    } else if let Some(armored) = armor.then(|| armored(data)).flatten() {
+        (armored.into_bytes(), "asc")
+    } else {
+        (data.to_vec(), "pgp")
+    };
+
+    std::fs::create_dir_all(dir).into_diagnostic()?;
+    let path = dir.join(format!("{method}-{fingerprint}.{extension}"));
+    std::fs::write(&path, &contents).into_diagnostic()?;
+
+    Ok(Some(SavedKey {
+        path,
+        sha256: sha256_hex(&contents),
+    }))
+}
+
+/// ASCII armors every certificate in `data`, `None` if it holds no certificate that can be read
+fn armored(data: &[u8]) -> Option<String> {
+    Cert::from_bytes(data)
+        .ok()?
+        .iter()
+        .map(Cert::to_armored)
+        .collect::<Result<_, _>>()
+        .ok()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_KEY: &[u8] = include_bytes!("../../test_files/test_key");
+
+    fn wkd_fetch(data: &'static [u8]) -> WkdFetchUriResult {
+        WkdFetchUriResult {
+            errors: vec![],
+            successes: vec![],
+            data: Some(data.to_vec().into()),
+            timestamp: Default::default(),
+            latency: None,
+            skipped: false,
+        }
+    }
+
+    #[test]
+    fn sha256_hex_of_empty_input() {
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn save_key_armors_binary_data() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let saved = save_key(dir.path(), "direct", &wkd_fetch(TEST_KEY), true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(saved.path.extension().unwrap(), "asc");
+        let contents = std::fs::read(&saved.path).unwrap();
+        assert!(cert::is_armored(&contents));
+        assert_eq!(saved.sha256, sha256_hex(&contents));
+        assert_eq!(
+            Cert::from_bytes(&contents).unwrap()[0].to_bytes(),
+            Cert::from_bytes(TEST_KEY).unwrap()[0].to_bytes()
+        );
+
+        // Data that isn't a certificate can't be armored
+        let saved = save_key(dir.path(), "direct", &wkd_fetch(b"\x99\x00\x01"), true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(saved.path.extension().unwrap(), "pgp");
+
+        let saved = save_key(dir.path(), "direct", &wkd_fetch(b"\x99\x00\x01"), false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(saved.path.extension().unwrap(), "pgp");
+        assert_eq!(std::fs::read(&saved.path).unwrap(), b"\x99\x00\x01");
+    }
+}
@@ -0,0 +1,469 @@
+use actix_web::http::header::{
+    ACCESS_CONTROL_ALLOW_ORIGIN, CONTENT_TYPE, ContentType, HeaderValue, LOCATION,
+};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, web};
+use clap::ValueEnum;
+use miette::{IntoDiagnostic, Result, miette};
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+/// Key requests are redirected below this path by the redirect fault
+const REDIRECT_PREFIX: &str = "/redirected";
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// Serve keys as `text/plain` instead of `application/octet-stream`
+    ContentType,
+    /// Leave out the `Access-Control-Allow-Origin: *` header
+    NoCors,
+    /// Serve a listing for directories instead of a 404
+    Index,
+    /// Answer key requests with a redirect to the same key
+    Redirect,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ServeArgs {
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:8080")]
+    pub bind: SocketAddr,
+
+    /// Serve HTTPS with a self-signed certificate
+    #[arg(long)]
+    pub tls: bool,
+
+    /// Host name the certificate is valid for, besides `localhost`, can be repeated
+    #[arg(long, requires = "tls")]
+    pub hostname: Vec<String>,
+
+    /// Write the PEM encoded certificate to this file, to be trusted by the client under test
+    #[arg(long, value_name = "PATH", requires = "tls")]
+    pub cert_out: Option<PathBuf>,
+
+    /// Misbehave like a broken WKD server, can be repeated
+    #[arg(long, value_enum)]
+    pub fault: Vec<Fault>,
+
+    /// Web root holding the `.well-known/openpgpkey` directory
+    pub root: PathBuf,
+}
+
+#[derive(Debug)]
+struct ServeState {
+    root: PathBuf,
+    faults: Vec<Fault>,
+}
+
+impl ServeState {
+    fn has_fault(&self, fault: Fault) -> bool {
+        self.faults.contains(&fault)
+    }
+}
+
+/// Serves the tree until interrupted
+pub async fn serve(args: &ServeArgs) -> Result<u8> {
+    if !args.root.is_dir() {
+        return Err(miette!("{} is not a directory", args.root.display()));
+    }
+
+    let state = web::Data::new(ServeState {
+        root: args.root.clone(),
+        faults: args.fault.clone(),
+    });
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(state.clone())
+            .default_service(web::to(serve_path))
+    })
+    .workers(1);
+
+    let server = match args.tls {
+        true => {
+            let (config, cert_pem) = self_signed_config(&args.hostname)?;
+            if let Some(cert_out) = &args.cert_out {
+                std::fs::write(cert_out, cert_pem).into_diagnostic()?;
+                eprintln!("Wrote certificate to {}", cert_out.display());
+            }
+            eprintln!("Serving {} on https://{}", args.root.display(), args.bind);
+            server.bind_rustls_0_23(args.bind, config)
+        }
+        false => {
+            eprintln!("Serving {} on http://{}", args.root.display(), args.bind);
+            server.bind(args.bind)
+        }
+    };
+
+    for fault in args.fault.iter().filter_map(ValueEnum::to_possible_value) {
+        eprintln!("Fault enabled: {}", fault.get_name());
+    }
+
+    server.into_diagnostic()?.run().await.into_diagnostic()?;
+    Ok(crate::policy::EXIT_PASS)
+}
+
+/// Builds a TLS config with a fresh self-signed certificate, returns it with the PEM certificate
+fn self_signed_config(hostnames: &[String]) -> Result<(rustls::ServerConfig, String)> {
+    let mut names = vec![
+        "localhost".to_string(),
+        "127.0.0.1".to_string(),
+        "::1".to_string(),
+    ];
+    names.extend(hostnames.iter().cloned());
+
+    let certified = rcgen::generate_simple_self_signed(names).into_diagnostic()?;
+    let cert_der = certified.cert.der().clone();
+    let key_der =
+        rustls::pki_types::PrivateKeyDer::Pkcs8(certified.signing_key.serialize_der().into());
+
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::aws_lc_rs::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .into_diagnostic()?
+    .with_no_client_auth()
+    .with_single_cert(vec![cert_der], key_der)
+    .into_diagnostic()?;
+
+    Ok((config, certified.cert.pem()))
+}
+
+/// Maps a request path to an existing file below the root, `..` and other non-normal components are refused and so
+/// are symlinks leading out of the root
+fn resolve(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let relative = Path::new(request_path.trim_start_matches('/'));
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+
+    let root = root.canonicalize().ok()?;
+    let path = root.join(relative).canonicalize().ok()?;
+    path.starts_with(&root).then_some(path)
+}
+
+/// Key files live in a `hu` directory, everything else is served as text
+fn is_key_path(path: &str) -> bool {
+    path.trim_end_matches(|c| c != '/').ends_with("/hu/")
+}
+
+async fn serve_path(req: HttpRequest, state: web::Data<ServeState>) -> HttpResponse {
+    let response = respond(&req, &state);
+    eprintln!(
+        "{} {} -> {}",
+        req.method(),
+        req.uri(),
+        response.status().as_u16()
+    );
+    response
+}
+
+fn respond(req: &HttpRequest, state: &ServeState) -> HttpResponse {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return HttpResponse::MethodNotAllowed().finish();
+    }
+
+    let mut path = req.path();
+    let mut redirected = false;
+    if state.has_fault(Fault::Redirect)
+        && let Some(stripped) = path.strip_prefix(REDIRECT_PREFIX)
+    {
+        path = stripped;
+        redirected = true;
+    }
+
+    let Some(file) = resolve(&state.root, path) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let mut response = if file.is_dir() {
+        if !state.has_fault(Fault::Index) {
+            return HttpResponse::NotFound().finish();
+        }
+        HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(index_page(&file, path))
+    } else if let Ok(data) = std::fs::read(&file) {
+        if is_key_path(path) && state.has_fault(Fault::Redirect) && !redirected {
+            let location = match req.query_string() {
+                "" => format!("{REDIRECT_PREFIX}{path}"),
+                query => format!("{REDIRECT_PREFIX}{path}?{query}"),
+            };
+            return HttpResponse::Found()
+                .insert_header((LOCATION, location))
+                .finish();
+        }
+
+        let content_type = match is_key_path(path) && !state.has_fault(Fault::ContentType) {
+            true => ContentType::octet_stream(),
+            false => ContentType::plaintext(),
+        };
+        HttpResponse::Ok()
+            .insert_header((CONTENT_TYPE, content_type))
+            .body(data)
+    } else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    if !state.has_fault(Fault::NoCors) && response.status() == StatusCode::OK {
+        response
+            .headers_mut()
+            .insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+    }
+    response
+}
+
+fn index_page(dir: &Path, path: &str) -> String {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+
+    let links: String = names
+        .iter()
+        .map(|name| {
+            let href = escape_html(&format!("{}/{name}", path.trim_end_matches('/')));
+            format!("<li><a href=\"{href}\">{}</a></li>", escape_html(name))
+        })
+        .collect();
+    format!(
+        "<html><body><h1>Index of {}</h1><ul>{links}</ul></body></html>",
+        escape_html(path)
+    )
+}
+
+/// Escapes text for HTML element content and quoted attribute values
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wkd::cert::Cert;
+    use wkd::generate::{WkdDirectory, WkdLayout};
+
+    const TEST_KEY: &[u8] = include_bytes!("../../test_files/test_key");
+    const KEY_PATH: &str =
+        "/.well-known/openpgpkey/chimbosonic.com/hu/z9naq3iddua5t55b3hp1w3hwz8eyrc7n";
+
+    fn test_root() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        let certs = Cert::from_bytes(TEST_KEY).unwrap();
+        WkdDirectory::new(&certs, WkdLayout::Advanced, &[])
+            .unwrap()
+            .write(root.path())
+            .unwrap();
+        root
+    }
+
+    async fn get(root: &Path, faults: Vec<Fault>, uri: &str) -> actix_web::dev::ServiceResponse {
+        let state = web::Data::new(ServeState {
+            root: root.to_path_buf(),
+            faults,
+        });
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(state)
+                .default_service(web::to(serve_path)),
+        )
+        .await;
+        actix_web::test::call_service(
+            &app,
+            actix_web::test::TestRequest::get().uri(uri).to_request(),
+        )
+        .await
+    }
+
+    #[test]
+    fn resolve_refuses_parent_components() {
+        let root = test_root();
+        assert_eq!(
+            resolve(
+                root.path(),
+                "/.well-known/openpgpkey/chimbosonic.com/policy"
+            ),
+            Some(
+                root.path()
+                    .canonicalize()
+                    .unwrap()
+                    .join(".well-known/openpgpkey/chimbosonic.com/policy")
+            )
+        );
+        assert_eq!(resolve(root.path(), "/.well-known/../../etc/passwd"), None);
+        assert_eq!(resolve(root.path(), "/.well-known/missing"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_refuses_symlinks_out_of_the_root() {
+        let root = test_root();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), "secret").unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("outside")).unwrap();
+        std::os::unix::fs::symlink(
+            root.path()
+                .join(".well-known/openpgpkey/chimbosonic.com/policy"),
+            root.path().join("policy"),
+        )
+        .unwrap();
+
+        assert_eq!(resolve(root.path(), "/outside/secret"), None);
+        assert!(resolve(root.path(), "/policy").is_some());
+    }
+
+    #[test]
+    fn index_page_escapes_names() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("<b onclick=\"x\">"), "").unwrap();
+
+        let page = index_page(root.path(), "/a&b/");
+        assert!(page.contains("Index of /a&amp;b/"));
+        assert!(page.contains(
+            "<a href=\"/a&amp;b/&lt;b onclick=&quot;x&quot;&gt;\">&lt;b onclick=&quot;x&quot;&gt;</a>"
+        ));
+        assert!(!page.contains("<b "));
+    }
+
+    #[actix_web::test]
+    async fn serves_keys_with_wkd_headers() {
+        let root = test_root();
+
+        let response = get(root.path(), vec![], &format!("{KEY_PATH}?l=alexis.lowe")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/octet-stream"
+        );
+        assert_eq!(
+            response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "*"
+        );
+
+        let response = get(
+            root.path(),
+            vec![],
+            "/.well-known/openpgpkey/chimbosonic.com/hu/",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn faults_break_the_wkd_headers() {
+        let root = test_root();
+
+        let response = get(
+            root.path(),
+            vec![Fault::ContentType, Fault::NoCors],
+            KEY_PATH,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "text/plain; charset=utf-8"
+        );
+        assert!(
+            response
+                .headers()
+                .get(ACCESS_CONTROL_ALLOW_ORIGIN)
+                .is_none()
+        );
+
+        let response = get(
+            root.path(),
+            vec![Fault::Index],
+            "/.well-known/openpgpkey/chimbosonic.com/hu/",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = get(
+            root.path(),
+            vec![Fault::Redirect],
+            &format!("{KEY_PATH}?l=alexis.lowe"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        let location = response.headers().get(LOCATION).unwrap().to_str().unwrap();
+        assert_eq!(
+            location,
+            format!("{REDIRECT_PREFIX}{KEY_PATH}?l=alexis.lowe")
+        );
+        let response = get(root.path(), vec![Fault::Redirect], location).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn wkd_fetch_against_served_tree() {
+        let root = test_root();
+        let hostnames = [
+            "chimbosonic.com".to_string(),
+            "openpgpkey.chimbosonic.com".to_string(),
+        ];
+        let (config, cert_pem) = self_signed_config(&hostnames).unwrap();
+
+        let state = web::Data::new(ServeState {
+            root: root.path().to_path_buf(),
+            faults: vec![Fault::ContentType],
+        });
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(state.clone())
+                .default_service(web::to(serve_path))
+        })
+        .workers(1)
+        .bind_rustls_0_23("127.0.0.1:0", config)
+        .unwrap();
+        let addr = server.addrs()[0];
+        let handle = server.run();
+        let server_handle = handle.handle();
+        actix_web::rt::spawn(handle);
+
+        let client = reqwest::Client::builder()
+            .tls_certs_only(reqwest::Certificate::from_pem_bundle(cert_pem.as_bytes()).unwrap())
+            .resolve(&hostnames[0], addr)
+            .resolve(&hostnames[1], addr)
+            .build()
+            .unwrap();
+        let wkd_uri = wkd::uri::WkdUri::new("alexis.lowe@chimbosonic.com").unwrap();
+        let wkd_fetch = wkd::fetch::WkdFetch::fetch(
+            &wkd_uri,
+            Some(client),
+            wkd::fetch::WkdMethodSelection::Advanced,
+        )
+        .await;
+
+        let advanced = wkd_fetch.advanced_method;
+        let served = std::fs::read(root.path().join(KEY_PATH.trim_start_matches('/'))).unwrap();
+        assert_eq!(advanced.data.unwrap().as_ref(), served);
+        let codes: Vec<String> = advanced
+            .errors
+            .iter()
+            .filter_map(|error| miette::Diagnostic::code(error).map(|code| code.to_string()))
+            .collect();
+        assert_eq!(codes, vec!["wkd_fetch::content_type_not_octet_stream"]);
+
+        server_handle.stop(false).await;
+    }
+}
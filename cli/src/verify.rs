@@ -0,0 +1,131 @@
+use crate::policy::{self, FailOn, LookupStatus, MethodStatus, RequireMethod};
+use miette::{IntoDiagnostic, Report, Result, Severity};
+use reqwest::Client;
+use std::path::Path;
+use wkd::cert::Cert;
+use wkd::fetch::{WkdFetch, WkdFetchUriResult, WkdMethodSelection};
+use wkd::uri::WkdUri;
+
+/// Compares what WKD serves for every user ID of `key_file` with it, returns the exit code of the run
+pub async fn verify(
+    key_file: &Path,
+    reqwest_client: Client,
+    selection: WkdMethodSelection,
+    require_method: RequireMethod,
+    fail_on: FailOn,
+) -> Result<u8> {
+    let data = std::fs::read(key_file).into_diagnostic()?;
+    let certs = Cert::from_bytes(&data)?;
+    let mut statuses = Vec::new();
+
+    for local in &certs {
+        println!("Verifying local certificate: {}", local.fingerprint);
+
+        for user_id in &local.user_ids() {
+            let Some(email) = user_id.email() else {
+                println!(
+                    "Skipping user ID without an e-mail address: {}",
+                    user_id.value
+                );
+                continue;
+            };
+
+            let wkd_uri = match WkdUri::new(email) {
+                Ok(wkd_uri) => wkd_uri,
+                Err(error) => {
+                    println!("Skipping user ID {}:", user_id.value);
+                    println!("{:?}", Report::new(error));
+                    continue;
+                }
+            };
+
+            println!("User ID: {}", user_id.value);
+            println!("Advanced method URI: {}", wkd_uri.advanced_uri);
+            println!("Direct method URI: {}", wkd_uri.direct_uri);
+
+            let wkd_fetch =
+                WkdFetch::fetch(&wkd_uri, Some(reqwest_client.clone()), selection).await;
+
+            statuses.push(LookupStatus {
+                advanced: verify_wkd_fetch(
+                    wkd_fetch.advanced_method,
+                    "Advanced",
+                    local,
+                    &user_id.value,
+                ),
+                direct: verify_wkd_fetch(wkd_fetch.direct_method, "Direct", local, &user_id.value),
+            });
+        }
+    }
+
+    Ok(policy::exit_code(&statuses, require_method, fail_on))
+}
+
+/// Failed when nothing usable was served or the served certificate differs, warnings for warning level differences
+fn verify_wkd_fetch(
+    wkd_fetch: WkdFetchUriResult,
+    method: &str,
+    local: &Cert,
+    user_id: &str,
+) -> MethodStatus {
+    if wkd_fetch.skipped {
+        println!("{method} method skipped");
+        return MethodStatus::Skipped;
+    }
+
+    let Some(data) = wkd_fetch.data else {
+        println!("{method} method fetch failed with following errors:");
+        for error in wkd_fetch.errors {
+            println!("{:?}", Report::new(error));
+        }
+        return MethodStatus::Failed;
+    };
+
+    let served = match Cert::from_bytes(&data) {
+        Ok(served) => served,
+        Err(error) => {
+            println!("{method} method served data could not be parsed:");
+            println!("{:?}", Report::new(error));
+            return MethodStatus::Failed;
+        }
+    };
+
+    // Prefer the served certificate matching the local one, the rest is reported as a mismatch
+    let served = served
+        .iter()
+        .find(|cert| cert.fingerprint == local.fingerprint)
+        .unwrap_or(&served[0]);
+
+    if served.to_bytes() == local.to_bytes() {
+        println!("{method} method serves a byte-identical certificate");
+        return MethodStatus::Ok;
+    }
+
+    let diffs = local.diff(served, user_id);
+    if diffs.is_empty() {
+        println!("{method} method serves an equivalent certificate");
+        return MethodStatus::Ok;
+    }
+
+    let status = diffs
+        .iter()
+        .map(|diff| match miette::Diagnostic::severity(diff) {
+            None | Some(Severity::Error) => MethodStatus::Failed,
+            Some(Severity::Warning) => MethodStatus::Warnings,
+            Some(Severity::Advice) => MethodStatus::Ok,
+        })
+        .max()
+        .unwrap_or(MethodStatus::Ok);
+
+    if status == MethodStatus::Failed {
+        println!("{method} method serves a certificate that differs from the local one:");
+    } else {
+        println!("{method} method serves an equivalent certificate with notes:");
+    }
+
+    for diff in diffs {
+        println!("{:?}", Report::new(diff));
+    }
+
+    status
+}
@@ -0,0 +1,273 @@
+use crate::Args;
+use crate::batch::{self, Lookup};
+use crate::output;
+use crate::policy::{self, LookupStatus};
+use crate::report::{self, Case};
+use miette::Result;
+use openpgp_tester_lib::load::load_key;
+use reqwest::Client;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+/// A latency increase is only reported when it is at least this much slower
+const LATENCY_REGRESSION_MIN: Duration = Duration::from_millis(250);
+/// and this many times the previous latency
+const LATENCY_REGRESSION_FACTOR: f64 = 1.5;
+
+#[derive(Debug, Clone, PartialEq)]
+enum CheckState {
+    Ok,
+    Failed(String),
+    Skipped,
+}
+
+impl std::fmt::Display for CheckState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CheckState::Ok => write!(f, "ok"),
+            CheckState::Failed(codes) => write!(f, "failed ({codes})"),
+            CheckState::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+impl CheckState {
+    fn from(case: &Case) -> Self {
+        let codes: Vec<&str> = case
+            .findings()
+            .map(|finding| finding.code.as_str())
+            .collect();
+
+        if !codes.is_empty() {
+            CheckState::Failed(codes.join(", "))
+        } else if case.skipped().is_some() {
+            CheckState::Skipped
+        } else {
+            CheckState::Ok
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct MethodSnapshot {
+    fingerprint: Option<String>,
+    latency: Option<Duration>,
+    checks: BTreeMap<&'static str, CheckState>,
+}
+
+/// State of every method of every address after one run, keyed by address and method
+#[derive(Debug, Default)]
+struct Snapshot(BTreeMap<(String, &'static str), MethodSnapshot>);
+
+impl Snapshot {
+    fn from(lookups: &[Lookup]) -> Self {
+        let mut snapshot = Snapshot::default();
+
+        for lookup in lookups {
+            let Ok((_, wkd_fetch)) = &lookup.result else {
+                continue;
+            };
+
+            for (method, wkd_fetch) in [
+                ("advanced", &wkd_fetch.advanced_method),
+                ("direct", &wkd_fetch.direct_method),
+            ] {
+                let fingerprint = wkd_fetch
+                    .data
+                    .clone()
+                    .and_then(|data| load_key(data).ok())
+                    .map(|key| key.fingerprint);
+
+                snapshot.0.insert(
+                    (lookup.user_id.clone(), method),
+                    MethodSnapshot {
+                        fingerprint,
+                        latency: wkd_fetch.latency,
+                        checks: BTreeMap::new(),
+                    },
+                );
+            }
+        }
+
+        for case in report::cases(lookups) {
+            if let Some(method) = snapshot.0.get_mut(&(case.user_id.clone(), case.method)) {
+                method.checks.insert(case.check, CheckState::from(&case));
+            }
+        }
+
+        snapshot
+    }
+
+    /// Describes every change from `previous` to this snapshot, one line each
+    fn changes(&self, previous: &Snapshot) -> Vec<String> {
+        let mut changes = Vec::new();
+
+        for ((user_id, method), current) in &self.0 {
+            let Some(previous) = previous.0.get(&(user_id.clone(), *method)) else {
+                continue;
+            };
+            let prefix = format!("{user_id} {method}");
+
+            if current.fingerprint != previous.fingerprint {
+                changes.push(format!(
+                    "{prefix}: fingerprint {} -> {}",
+                    previous.fingerprint.as_deref().unwrap_or("none"),
+                    current.fingerprint.as_deref().unwrap_or("none")
+                ));
+            }
+
+            for (check, state) in &current.checks {
+                match previous.checks.get(check) {
+                    Some(previous_state) if previous_state != state => {
+                        changes.push(format!("{prefix}: {check} {previous_state} -> {state}"))
+                    }
+                    _ => {}
+                }
+            }
+
+            if let (Some(previous_latency), Some(latency)) = (previous.latency, current.latency)
+                && latency >= previous_latency + LATENCY_REGRESSION_MIN
+                && latency.as_secs_f64()
+                    >= previous_latency.as_secs_f64() * LATENCY_REGRESSION_FACTOR
+            {
+                changes.push(format!(
+                    "{prefix}: latency {}ms -> {}ms",
+                    previous_latency.as_millis(),
+                    latency.as_millis()
+                ));
+            }
+        }
+
+        changes
+    }
+}
+
+/// Runs the lookups every `interval` until interrupted and prints what changed between runs.
+///
+/// Returns the exit code of the last completed run.
+pub async fn watch(
+    args: &Args,
+    user_ids: Vec<String>,
+    interval: Duration,
+    reqwest_client: Client,
+) -> Result<u8> {
+    let lookups = batch::lookup_all(
+        user_ids.clone(),
+        reqwest_client.clone(),
+        args.batch_limits(),
+        args.method.into(),
+    )
+    .await;
+    let mut snapshot = Snapshot::from(&lookups);
+    let mut last_exit_code = exit_code(args, &lookups);
+    let statuses: Vec<LookupStatus> = lookups.iter().map(LookupStatus::from).collect();
+    output::print_human(lookups, &statuses, args.details)?;
+
+    eprintln!(
+        "Watching {} address(es) every {}, press Ctrl-C to stop",
+        user_ids.len(),
+        humantime::format_duration(interval)
+    );
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = tokio::signal::ctrl_c() => return Ok(last_exit_code),
+        }
+
+        let lookups = tokio::select! {
+            lookups = batch::lookup_all(
+                user_ids.clone(),
+                reqwest_client.clone(),
+                args.batch_limits(),
+                args.method.into(),
+            ) => lookups,
+            _ = tokio::signal::ctrl_c() => return Ok(last_exit_code),
+        };
+
+        let current = Snapshot::from(&lookups);
+        let changes = current.changes(&snapshot);
+        snapshot = current;
+        last_exit_code = exit_code(args, &lookups);
+
+        if changes.is_empty() {
+            continue;
+        }
+
+        println!("[{}]", humantime::format_rfc3339_seconds(SystemTime::now()));
+        for change in &changes {
+            println!("  {change}");
+        }
+
+        if let Some(on_change) = &args.on_change {
+            run_hook(on_change, &changes, last_exit_code).await;
+        }
+    }
+}
+
+fn exit_code(args: &Args, lookups: &[Lookup]) -> u8 {
+    let statuses: Vec<LookupStatus> = lookups.iter().map(LookupStatus::from).collect();
+    policy::exit_code(&statuses, args.require_method, args.fail_on)
+}
+
+/// Runs the hook through `sh -c` with the changes in `WKD_TESTER_CHANGES`.
+///
+/// A failing hook is reported but doesn't stop watching.
+async fn run_hook(command: &str, changes: &[String], exit_code: u8) {
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("WKD_TESTER_CHANGES", changes.join("\n"))
+        .env("WKD_TESTER_EXIT_CODE", exit_code.to_string())
+        .status()
+        .await;
+
+    match status {
+        Ok(status) if !status.success() => eprintln!("On change hook exited with {status}"),
+        Ok(_) => {}
+        Err(error) => eprintln!("On change hook could not be run: {error}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(fingerprint: &str, cors: CheckState, latency_ms: u64) -> Snapshot {
+        let method = MethodSnapshot {
+            fingerprint: Some(fingerprint.to_string()),
+            latency: Some(Duration::from_millis(latency_ms)),
+            checks: BTreeMap::from([("cors", cors)]),
+        };
+        Snapshot(BTreeMap::from([(
+            ("joe@example.org".to_string(), "direct"),
+            method,
+        )]))
+    }
+
+    #[test]
+    fn unchanged_snapshot_has_no_changes() {
+        let previous = snapshot("AAAA", CheckState::Ok, 100);
+        let current = snapshot("AAAA", CheckState::Ok, 200);
+        assert!(current.changes(&previous).is_empty());
+    }
+
+    #[test]
+    fn changes_report_fingerprint_checks_and_latency() {
+        let previous = snapshot("AAAA", CheckState::Ok, 100);
+        let current = snapshot(
+            "BBBB",
+            CheckState::Failed("wkd_fetch::access_control_allow_origin_not_star".to_string()),
+            900,
+        );
+
+        assert_eq!(
+            current.changes(&previous),
+            vec![
+                "joe@example.org direct: fingerprint AAAA -> BBBB",
+                "joe@example.org direct: cors ok -> failed (wkd_fetch::access_control_allow_origin_not_star)",
+                "joe@example.org direct: latency 100ms -> 900ms",
+            ]
+        );
+    }
+}
@@ -1,142 +1,676 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    hash::Hash,
-    sync::Arc,
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    fmt::Debug,
+    hash::{BuildHasher, Hash, RandomState},
+    mem::size_of,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
     time::{Duration, Instant},
 };
 
-use tokio::{sync::RwLock, time::interval};
+use serde::Serialize;
+use tokio::{sync::OnceCell, time::interval};
 
-#[derive(Debug, Clone)]
-pub struct Entry<T> {
-    pub timestamp: Instant,
-    pub data: T,
+use crate::cache_backend::{CacheBackend, CacheBackendError, StoredEntry};
+
+/// Keys are spread over this many independently locked maps, so lookups of different keys rarely wait on each other
+const SHARDS: usize = 16;
+/// How often expired entries are swept
+const SWEEP_EVERY: Duration = Duration::from_secs(1);
+/// Longest time an entry is kept, longer TTLs are cut to it
+const MAX_TTL: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Approximate heap size of a cached key or value, counted against [`CacheLimits::max_bytes`]
+pub trait ApproxSize {
+    fn approx_size(&self) -> usize;
+}
+
+impl ApproxSize for String {
+    fn approx_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl ApproxSize for usize {
+    fn approx_size(&self) -> usize {
+        0
+    }
+}
+
+impl<A: ApproxSize, B: ApproxSize> ApproxSize for (A, B) {
+    fn approx_size(&self) -> usize {
+        self.0.approx_size() + self.1.approx_size()
+    }
+}
+
+/// Decides how long a cached value is fresh
+pub trait CachePolicy<V>: Debug + Send + Sync {
+    fn ttl(&self, value: &V) -> Duration;
+}
+
+/// The same TTL for every value
+impl<V> CachePolicy<V> for Duration {
+    fn ttl(&self, _value: &V) -> Duration {
+        *self
+    }
+}
+
+/// Bounds of a [`Cache`], `0` disables a bound
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheLimits {
+    pub max_entries: usize,
+    pub max_bytes: usize,
+}
+
+impl CacheLimits {
+    fn exceeded(&self, entries: usize, bytes: usize) -> bool {
+        (self.max_entries > 0 && entries > self.max_entries)
+            || (self.max_bytes > 0 && bytes > self.max_bytes)
+    }
+
+    /// Eviction goes down to 90% of the limits, so not every insert into a full cache evicts
+    fn low_water_mark(&self) -> CacheLimits {
+        CacheLimits {
+            max_entries: self.max_entries - self.max_entries / 10,
+            max_bytes: self.max_bytes - self.max_bytes / 10,
+        }
+    }
+}
+
+/// Counters since the cache was created and its current size
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    /// Hits on entries past their TTL, each starts a refresh unless one is running
+    pub stale_hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub expirations: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    stale_hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
 }
 
 #[derive(Debug)]
-pub struct Cache<K, V>
-where
-    K: Eq + Hash + Send + Sync + Clone,
-    V: Send + Sync + Clone,
-{
-    store: Arc<RwLock<HashMap<K, Entry<V>>>>,
-    key_fifo: Arc<RwLock<VecDeque<K>>>,
-    ttl: Duration,
+pub struct Entry<T> {
+    pub timestamp: Instant,
+    pub data: T,
+    /// End of the TTL, the entry is stale after it
+    fresh_until: Instant,
+    /// End of the stale window, the entry is removed after it
+    expires: Instant,
+    /// Approximate bytes of the entry and its key
+    size: usize,
+    /// Tick of the last set or hit, the lowest is evicted first
+    last_used: AtomicU64,
+    /// Tick of the set, tells apart entries expiring at the same instant in the expiry queue
+    seq: u64,
+    /// Tick the entry is queued under in the usage queue, it lags behind `last_used` until the entry reaches the
+    /// front of the queue
+    queued: u64,
 }
 
 impl<T> Entry<T> {
     pub fn new(data: T) -> Self {
+        let timestamp = Instant::now();
         Entry {
-            timestamp: Instant::now(),
+            timestamp,
             data,
+            fresh_until: timestamp,
+            expires: timestamp,
+            size: 0,
+            last_used: AtomicU64::new(0),
+            seq: 0,
+            queued: 0,
         }
     }
 
-    pub fn is_stale(&self, ttl: Duration) -> bool {
-        self.timestamp.elapsed() > ttl
+    pub fn is_stale(&self) -> bool {
+        Instant::now() > self.fresh_until
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Instant::now() > self.expires
+    }
+
+    fn expiry_key(&self) -> (Instant, u64) {
+        (self.expires, self.seq)
     }
 }
 
-impl<K, V> Cache<K, V>
+/// What [`Cache::lookup`] found
+enum Lookup<V> {
+    Fresh(V),
+    Stale(V),
+    Missing,
+}
+
+/// Entries of one shard, the order they expire in and the order they were used in
+#[derive(Debug)]
+struct Shard<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    expiry: BTreeMap<(Instant, u64), K>,
+    /// Keys by [`Entry::queued`], hits only take a read lock so entries are moved back when they reach the front
+    usage: BTreeMap<u64, K>,
+}
+
+impl<K: Eq + Hash + Clone, V> Shard<K, V> {
+    fn new() -> Self {
+        Shard {
+            entries: HashMap::new(),
+            expiry: BTreeMap::new(),
+            usage: BTreeMap::new(),
+        }
+    }
+
+    /// Inserts `entry` and returns the entry it replaced, a key is queued once however often it is set
+    fn insert(&mut self, key: K, entry: Entry<V>) -> Option<Entry<V>> {
+        let replaced = self.remove(&key);
+        self.expiry.insert(entry.expiry_key(), key.clone());
+        self.usage.insert(entry.queued, key.clone());
+        self.entries.insert(key, entry);
+        replaced
+    }
+
+    fn remove(&mut self, key: &K) -> Option<Entry<V>> {
+        let entry = self.entries.remove(key)?;
+        self.expiry.remove(&entry.expiry_key());
+        self.usage.remove(&entry.queued);
+        Some(entry)
+    }
+
+    /// Removes the entry that expires first if it has expired
+    fn pop_expired(&mut self) -> Option<Entry<V>> {
+        let oldest = self.expiry.first_entry()?;
+        if Instant::now() <= oldest.key().0 {
+            return None;
+        }
+        let key = oldest.remove();
+        self.remove(&key)
+    }
+
+    /// Tick the front of the usage queue was queued under, the entry may have been used since
+    fn least_recently_queued(&self) -> Option<u64> {
+        self.usage.first_key_value().map(|(queued, _)| *queued)
+    }
+
+    /// Removes the front of the usage queue if it wasn't used since it was queued, otherwise queues it again
+    fn pop_least_recently_used(&mut self) -> Option<Entry<V>> {
+        let front = self.usage.first_entry()?;
+        let queued = *front.key();
+        let entry = self.entries.get_mut(front.get())?;
+        let last_used = entry.last_used.load(Ordering::Relaxed);
+        let key = front.remove();
+        if last_used == queued {
+            return self.remove(&key);
+        }
+
+        // Hits hold the read lock, so `last_used` stays put while the entry is queued again
+        entry.queued = last_used;
+        self.usage.insert(last_used, key);
+        None
+    }
+}
+
+/// Result of a fetch shared by every caller that missed the cache while it ran
+type Flight<V, E> = Arc<OnceCell<Result<V, E>>>;
+
+/// Held by every caller of [`Cache::fetch`], removes the flight from `in_flight` once its value was cached or
+/// the last caller waiting for it was dropped, e.g. by a client that disconnected
+struct FlightGuard<'a, K: Eq + Hash, V, E> {
+    in_flight: &'a Mutex<HashMap<K, Flight<V, E>>>,
+    key: K,
+    flight: Option<Flight<V, E>>,
+    done: bool,
+}
+
+impl<K: Eq + Hash, V, E> Drop for FlightGuard<'_, K, V, E> {
+    fn drop(&mut self) {
+        let Some(flight) = self.flight.take() else {
+            return;
+        };
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let Some(current) = in_flight.get(&self.key) else {
+            return;
+        };
+        if !Arc::ptr_eq(current, &flight) {
+            return;
+        }
+        // Dropped while holding the lock, so the map holds the only reference once no other caller waits
+        drop(flight);
+        if self.done || Arc::strong_count(current) == 1 {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+/// `E` is the error of the fetches run by [`Cache::get_or_fetch`], errors are never cached
+#[derive(Debug)]
+pub struct Cache<K, V, E = Infallible>
+where
+    K: Eq + Hash + Send + Sync + Clone + ApproxSize,
+    V: Send + Sync + Clone + ApproxSize,
+{
+    shards: Box<[RwLock<Shard<K, V>>]>,
+    hasher: RandomState,
+    in_flight: Mutex<HashMap<K, Flight<V, E>>>,
+    policy: Box<dyn CachePolicy<V>>,
+    /// How long after its TTL an entry is still served while it is refreshed
+    stale_window: Duration,
+    limits: CacheLimits,
+    /// Number and size of the entries of all shards, changed while holding the write lock of the shard
+    entries: AtomicUsize,
+    bytes: AtomicUsize,
+    tick: AtomicU64,
+    /// Held by the `set` that evicts, others don't wait for it
+    evicting: Mutex<()>,
+    counters: Counters,
+    backend: Arc<dyn CacheBackend<K, V>>,
+}
+
+impl<K, V, E> Cache<K, V, E>
 where
-    K: Eq + Hash + Send + Sync + Clone,
-    V: Send + Sync + Clone,
+    K: Eq + Hash + Send + Sync + Clone + ApproxSize,
+    V: Send + Sync + Clone + ApproxSize,
+    E: Clone,
 {
+    /// An unbounded cache, the server always sets limits
+    #[cfg(test)]
     pub fn new(ttl: Duration) -> Self {
-        let map: HashMap<K, Entry<V>> = HashMap::new();
-        let key_fifo: VecDeque<K> = VecDeque::new();
+        Cache::with_limits(ttl, CacheLimits::default())
+    }
+
+    #[cfg(test)]
+    pub fn with_limits(ttl: Duration, limits: CacheLimits) -> Self {
+        Cache::with_backend(ttl, limits, Arc::new(crate::cache_backend::MemoryBackend))
+    }
 
+    pub fn with_backend(
+        policy: impl CachePolicy<V> + 'static,
+        limits: CacheLimits,
+        backend: Arc<dyn CacheBackend<K, V>>,
+    ) -> Self {
         Cache {
-            store: Arc::new(RwLock::new(map)),
-            key_fifo: Arc::new(RwLock::new(key_fifo)),
-            ttl,
+            shards: (0..SHARDS).map(|_| RwLock::new(Shard::new())).collect(),
+            hasher: RandomState::new(),
+            in_flight: Mutex::new(HashMap::new()),
+            policy: Box::new(policy),
+            stale_window: Duration::ZERO,
+            limits,
+            entries: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            tick: AtomicU64::new(0),
+            evicting: Mutex::new(()),
+            counters: Counters::default(),
+            backend,
         }
     }
 
+    fn shard(&self, key: &K) -> &RwLock<Shard<K, V>> {
+        &self.shards[self.hasher.hash_one(key) as usize % SHARDS]
+    }
+
+    /// Serves entries for `window` after their TTL while [`Cache::get_or_revalidate`] refreshes them
+    pub fn serve_stale_for(mut self, window: Duration) -> Self {
+        self.stale_window = window.min(MAX_TTL);
+        self
+    }
+
+    /// How long `value` is fresh once cached
+    pub fn ttl(&self, value: &V) -> Duration {
+        self.policy.ttl(value).min(MAX_TTL)
+    }
+
+    fn next_tick(&self) -> u64 {
+        self.tick.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn exceeds_limits(&self) -> bool {
+        self.limits.exceeded(
+            self.entries.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Accounts for an entry that left its shard
+    fn removed(&self, entry: &Entry<V>) {
+        self.entries.fetch_sub(1, Ordering::Relaxed);
+        self.bytes.fetch_sub(entry.size, Ordering::Relaxed);
+    }
+
     pub async fn set(&self, key: K, value: V) {
-        let mut store = self.store.write().await;
+        self.insert(key, value, Instant::now());
+    }
+
+    /// Inserts a value fetched at `timestamp`, which is earlier than now for restored entries
+    fn insert(&self, key: K, value: V, timestamp: Instant) {
+        // The key is kept in the map, the expiry queue and the usage queue
+        let size = size_of::<(K, Entry<V>)>()
+            + size_of::<((Instant, u64), K)>()
+            + size_of::<(u64, K)>()
+            + 3 * key.approx_size()
+            + value.approx_size();
+        let tick = self.next_tick();
+        let fresh_until = timestamp + self.ttl(&value);
+        let entry = Entry {
+            timestamp,
+            fresh_until,
+            expires: fresh_until + self.stale_window,
+            size,
+            last_used: AtomicU64::new(tick),
+            seq: tick,
+            queued: tick,
+            ..Entry::new(value)
+        };
+
+        let mut shard = self.shard(&key).write().unwrap();
+        if let Some(replaced) = shard.insert(key, entry) {
+            self.removed(&replaced);
+        }
+        self.entries.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+        drop(shard);
 
-        if store.insert(key.clone(), Entry::new(value)).is_none() {
-            let mut key_fifo = self.key_fifo.write().await;
+        if self.exceeds_limits() {
+            self.evict();
+        }
+    }
 
-            key_fifo.push_front(key.clone());
-            drop(key_fifo);
+    /// Drops the least recently used entries down to the low water mark of the limits, each one is taken from the
+    /// shard whose usage queue starts with the oldest tick, so no step looks at more than the front of each shard
+    fn evict(&self) {
+        let Ok(_evicting) = self.evicting.try_lock() else {
+            return;
         };
-        drop(store);
+        let target = self.limits.low_water_mark();
+
+        while target.exceeded(
+            self.entries.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+        ) {
+            let oldest = self
+                .shards
+                .iter()
+                .enumerate()
+                .filter_map(|(index, shard)| {
+                    let queued = shard.read().unwrap().least_recently_queued()?;
+                    Some((queued, index))
+                })
+                .min();
+            let Some((_, index)) = oldest else {
+                break;
+            };
+
+            let mut shard = self.shards[index].write().unwrap();
+            if let Some(entry) = shard.pop_least_recently_used() {
+                self.removed(&entry);
+                self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
     }
 
+    /// Returns the value of `key` while it is fresh
+    #[cfg(test)]
     pub async fn get(&self, key: &K) -> Option<V> {
-        let store = self.store.read().await;
+        match self.lookup(key) {
+            Lookup::Fresh(value) => Some(value),
+            Lookup::Stale(_) => {
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            Lookup::Missing => None,
+        }
+    }
+
+    /// Counts hits and misses, a stale value is counted by the caller that decides whether to serve it
+    fn lookup(&self, key: &K) -> Lookup<V> {
+        let shard = self.shard(key).read().unwrap();
 
-        let entry = match store.get(key) {
+        let entry = match shard.entries.get(key) {
             Some(entry) => entry,
-            None => return None,
+            None => {
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                return Lookup::Missing;
+            }
         };
 
-        if entry.is_stale(self.ttl) {
-            drop(store); // Explicitly drop the read lock before acquiring the write lock
-            let mut store = self.store.write().await;
-            store.remove(key);
-            return None;
+        if entry.is_expired() {
+            drop(shard); // Explicitly drop the read lock before acquiring the write lock
+            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+            self.remove_expired(key);
+            return Lookup::Missing;
         }
 
-        Some(entry.data.clone())
+        entry.last_used.store(self.next_tick(), Ordering::Relaxed);
+        if entry.is_stale() {
+            Lookup::Stale(entry.data.clone())
+        } else {
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+            Lookup::Fresh(entry.data.clone())
+        }
     }
 
-    async fn cache_size(&self) -> usize {
-        let store = self.store.read().await;
-        store.len()
+    /// Returns the cached value or runs `fetch`, concurrent misses for the same key wait for one fetch and share its
+    /// result. Only values are cached, an error goes to the callers that waited for it and the next miss fetches again.
+    #[cfg(test)]
+    pub async fn get_or_fetch<F, Fut>(&self, key: K, fetch: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        if let Some(value) = self.get(&key).await {
+            return Ok(value);
+        }
+
+        self.fetch(key, fetch).await
     }
 
-    async fn keyfifo_pop_back(&self) -> Option<K> {
-        let mut key_fifo = self.key_fifo.write().await;
+    /// Returns the cached value or runs `fetch` like [`Cache::fetch`], a stale value is returned right away while
+    /// `fetch` refreshes it in the background
+    pub async fn get_or_revalidate<F, Fut>(self: &Arc<Self>, key: K, fetch: F) -> Result<V, E>
+    where
+        K: 'static,
+        V: 'static,
+        E: Send + Sync + 'static,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<V, E>> + Send + 'static,
+    {
+        match self.lookup(&key) {
+            Lookup::Fresh(value) => Ok(value),
+            Lookup::Stale(value) => {
+                self.counters.stale_hits.fetch_add(1, Ordering::Relaxed);
+                let refreshing = self.in_flight.lock().unwrap().contains_key(&key);
+                if !refreshing {
+                    let cache = self.clone();
+                    tokio::spawn(async move {
+                        // Errors are dropped, the stale value is served until its window ends
+                        let _ = cache.fetch(key, fetch).await;
+                    });
+                }
+                Ok(value)
+            }
+            Lookup::Missing => self.fetch(key, fetch).await,
+        }
+    }
+
+    /// Runs `fetch` without looking at the cached value and caches its value, joining a fetch of `key` that is
+    /// already running
+    pub async fn fetch<F, Fut>(&self, key: K, fetch: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        let flight = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let mut guard = FlightGuard {
+            in_flight: &self.in_flight,
+            key,
+            flight: Some(flight),
+            done: false,
+        };
+
+        // When the caller running the fetch is dropped, the next waiting caller runs its own
+        let mut fetched = false;
+        let result = guard
+            .flight
+            .as_ref()
+            .expect("taken on drop only")
+            .get_or_init(|| {
+                fetched = true;
+                fetch()
+            })
+            .await
+            .clone();
+
+        if fetched {
+            if let Ok(value) = &result {
+                self.set(guard.key.clone(), value.clone()).await;
+            }
+            guard.done = true;
+        }
+
+        result
+    }
 
-        key_fifo.pop_back()
+    async fn cache_size(&self) -> usize {
+        self.entries.load(Ordering::Relaxed)
     }
 
-    async fn keyfifo_push_back(&self, key: K) {
-        let mut key_fifo = self.key_fifo.write().await;
+    /// Removes the entry of `key` if it is still expired, it may have been set again since it was checked
+    fn remove_expired(&self, key: &K) {
+        let mut shard = self.shard(key).write().unwrap();
 
-        key_fifo.push_back(key)
+        if shard
+            .entries
+            .get(key)
+            .is_some_and(|entry| entry.is_expired())
+            && let Some(entry) = shard.remove(key)
+        {
+            self.removed(&entry);
+            self.counters.expirations.fetch_add(1, Ordering::Relaxed);
+        }
     }
 
-    async fn get_timestamp(&self, key: &K) -> Option<Instant> {
-        let store = self.store.read().await;
-        store.get(key).map(|entry| entry.timestamp)
+    pub async fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            stale_hits: self.counters.stale_hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+            expirations: self.counters.expirations.load(Ordering::Relaxed),
+            entries: self.entries.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
     }
 
-    async fn store_remove(&self, key: &K) {
-        let mut store = self.store.write().await;
+    /// Loads the entries the backend saved that haven't expired, returns how many were restored
+    pub fn restore(&self) -> Result<usize, CacheBackendError> {
+        let mut restored = 0;
+        for stored in self.backend.load()? {
+            let age = stored.age();
+            let Some(timestamp) = Instant::now().checked_sub(age) else {
+                continue;
+            };
+            if age > self.ttl(&stored.value) + self.stale_window {
+                continue;
+            }
+            self.insert(stored.key, stored.value, timestamp);
+            restored += 1;
+        }
 
-        store.remove(key);
+        Ok(restored)
     }
 
-    async fn sweep(&self) -> bool {
-        log::debug!(
-            "Sweeping cache...Cache size before sweep: {}",
-            self.cache_size().await
-        );
+    /// Saves the entries that haven't expired to the backend, returns how many were saved
+    pub async fn persist(&self) -> Result<usize, CacheBackendError>
+    where
+        K: 'static,
+        V: 'static,
+    {
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            entries.extend(
+                shard
+                    .entries
+                    .iter()
+                    .filter(|(_, entry)| !entry.is_expired())
+                    .map(|(key, entry)| {
+                        StoredEntry::new(key.clone(), entry.data.clone(), entry.timestamp.elapsed())
+                    }),
+            );
+        }
+        let saved = entries.len();
 
-        if let Some(key) = self.keyfifo_pop_back().await
-            && let Some(timestamp) = self.get_timestamp(&key).await
-        {
-            if timestamp.elapsed() > self.ttl {
-                self.store_remove(&key).await;
-                return true;
-            } else {
-                self.keyfifo_push_back(key).await;
+        // Writing a large snapshot would stall the requests handled on this thread
+        let backend = self.backend.clone();
+        tokio::task::spawn_blocking(move || backend.save(&entries))
+            .await
+            .map_err(|err| {
+                log::error!("Cache backend panicked while saving: {err}");
+                CacheBackendError::Task(err)
+            })??;
+
+        Ok(saved)
+    }
+
+    /// Saves the cache every `every` until the server stops
+    pub async fn persist_task(&self, every: Duration)
+    where
+        K: 'static,
+        V: 'static,
+    {
+        let mut tick = interval(every);
+        tick.tick().await;
+        loop {
+            tick.tick().await;
+            match self.persist().await {
+                Ok(saved) => log::debug!("Saved {saved} cached lookups"),
+                Err(err) => log::warn!("{err}"),
+            }
+        }
+    }
+
+    /// Removes the expired entries from the front of every shard's expiry queue, returns how many were removed
+    async fn sweep(&self) -> usize {
+        let mut swept = 0;
+        for shard in self.shards.iter() {
+            let mut shard = shard.write().unwrap();
+            while let Some(entry) = shard.pop_expired() {
+                self.removed(&entry);
+                self.counters.expirations.fetch_add(1, Ordering::Relaxed);
+                swept += 1;
             }
         }
 
-        false
+        log::debug!(
+            "Swept {swept} expired entries from the cache, {} left",
+            self.cache_size().await
+        );
+        swept
     }
 
     pub async fn sweep_task(&self) {
-        let sweep_every = self.ttl.mul_f32(0.1).max(Duration::from_millis(50));
-        let mut tick = interval(sweep_every);
+        let mut tick = interval(SWEEP_EVERY);
         loop {
             tick.tick().await;
-            while self.sweep().await {}
+            self.sweep().await;
         }
     }
 }
@@ -148,7 +682,8 @@ mod tests {
     #[tokio::test]
     async fn test_cache_new() {
         let cache = Cache::<String, String>::new(Duration::from_secs(1));
-        assert!(cache.store.read().await.is_empty());
+        assert_eq!(cache.shards.len(), SHARDS);
+        assert_eq!(cache.cache_size().await, 0);
     }
 
     #[tokio::test]
@@ -187,6 +722,153 @@ mod tests {
         assert!(result.is_none());
     }
 
+    #[tokio::test]
+    async fn test_cache_evicts_least_recently_used() {
+        let limits = CacheLimits {
+            max_entries: 10,
+            max_bytes: 0,
+        };
+        let cache = Cache::<String, String>::with_limits(Duration::from_secs(1), limits);
+
+        for i in 0..10 {
+            cache.set(format!("key{i}"), "value".to_string()).await;
+        }
+        assert!(cache.get(&"key0".to_string()).await.is_some());
+
+        cache.set("key10".to_string(), "value".to_string()).await;
+        assert_eq!(cache.cache_size().await, 9);
+        assert!(cache.get(&"key0".to_string()).await.is_some());
+        assert!(cache.get(&"key1".to_string()).await.is_none());
+        assert!(cache.get(&"key2".to_string()).await.is_none());
+        assert!(cache.get(&"key10".to_string()).await.is_some());
+        let queued: usize = cache
+            .shards
+            .iter()
+            .map(|shard| shard.read().unwrap().expiry.len())
+            .sum();
+        assert_eq!(queued, 9);
+        let used: usize = cache
+            .shards
+            .iter()
+            .map(|shard| shard.read().unwrap().usage.len())
+            .sum();
+        assert_eq!(used, 9);
+        assert_eq!(cache.stats().await.evictions, 2);
+    }
+
+    #[tokio::test]
+    async fn test_cache_respects_byte_budget() {
+        let probe = Cache::<String, String>::new(Duration::from_secs(1));
+        probe.set(format!("key{:02}", 0), "x".repeat(1000)).await;
+        let entry_size = probe.stats().await.bytes;
+        assert!(entry_size > 1000);
+
+        let limits = CacheLimits {
+            max_entries: 0,
+            max_bytes: entry_size * 5,
+        };
+        let cache = Cache::<String, String>::with_limits(Duration::from_secs(1), limits);
+
+        for i in 0..20 {
+            cache.set(format!("key{i:02}"), "x".repeat(1000)).await;
+            assert!(cache.stats().await.bytes <= limits.max_bytes);
+        }
+        let stats = cache.stats().await;
+        assert_eq!(stats.entries, 4);
+        assert_eq!(stats.bytes, 4 * entry_size);
+        assert!(cache.get(&"key19".to_string()).await.is_some());
+
+        // Replacing an entry doesn't count it twice
+        cache.set(format!("key{:02}", 19), "x".repeat(1000)).await;
+        assert_eq!(cache.stats().await.bytes, stats.bytes);
+    }
+
+    #[tokio::test]
+    async fn test_cache_stats() {
+        let cache = Cache::<String, String>::new(Duration::from_millis(100));
+        let key = "test".to_string();
+
+        assert!(cache.get(&key).await.is_none());
+        cache.set(key.clone(), "value".to_string()).await;
+        assert!(cache.get(&key).await.is_some());
+        assert!(cache.get(&key).await.is_some());
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(cache.get(&key).await.is_none());
+
+        assert_eq!(
+            cache.stats().await,
+            CacheStats {
+                hits: 2,
+                stale_hits: 0,
+                misses: 2,
+                evictions: 0,
+                expirations: 1,
+                entries: 0,
+                bytes: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache_get_or_fetch_coalesces() {
+        let cache = Arc::new(Cache::<String, usize, String>::new(Duration::from_secs(1)));
+        let fetches = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let fetch = |result: Result<usize, String>| {
+            let cache = cache.clone();
+            let fetches = fetches.clone();
+            tokio::spawn(async move {
+                cache
+                    .get_or_fetch("test".to_string(), || async move {
+                        fetches.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        result
+                    })
+                    .await
+            })
+        };
+
+        let waiters: Vec<_> = (0..10).map(|_| fetch(Err("failed".to_string()))).collect();
+        for waiter in waiters {
+            assert_eq!(waiter.await.unwrap(), Err("failed".to_string()));
+        }
+        assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(cache.cache_size().await, 0);
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+
+        let waiters: Vec<_> = (0..10).map(|_| fetch(Ok(42))).collect();
+        for waiter in waiters {
+            assert_eq!(waiter.await.unwrap(), Ok(42));
+        }
+        assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert_eq!(cache.get(&"test".to_string()).await, Some(42));
+    }
+
+    #[tokio::test]
+    async fn test_cache_get_or_fetch_drops_abandoned_flight() {
+        let cache = Cache::<String, usize, String>::new(Duration::from_secs(1));
+        let fetch = || {
+            cache.get_or_fetch("test".to_string(), || {
+                std::future::pending::<Result<usize, String>>()
+            })
+        };
+
+        let (first, second) = tokio::join!(
+            tokio::time::timeout(Duration::from_millis(50), fetch()),
+            tokio::time::timeout(Duration::from_millis(100), fetch())
+        );
+        assert!(first.is_err());
+        assert!(second.is_err());
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+
+        let result = cache
+            .get_or_fetch("test".to_string(), || async { Ok(42) })
+            .await;
+        assert_eq!(result, Ok(42));
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+    }
+
     #[tokio::test]
     async fn test_cache_sweep() {
         let cache = Cache::<String, String>::new(Duration::from_millis(200));
@@ -197,13 +879,13 @@ mod tests {
         assert_eq!(cache.cache_size().await, 1);
 
         let sweeped = cache.sweep().await;
-        assert!(!sweeped);
+        assert_eq!(sweeped, 0);
         assert_eq!(cache.cache_size().await, 1);
 
         tokio::time::sleep(Duration::from_millis(300)).await;
 
         let sweeped = cache.sweep().await;
-        assert!(sweeped);
+        assert_eq!(sweeped, 1);
         assert_eq!(cache.cache_size().await, 0);
 
         cache.set(key.clone(), value.clone()).await;
@@ -215,7 +897,189 @@ mod tests {
         assert!(result.is_none());
 
         let sweeped = cache.sweep().await;
-        assert!(!sweeped);
+        assert_eq!(sweeped, 0);
         assert_eq!(cache.cache_size().await, 0);
     }
+
+    #[tokio::test]
+    async fn test_cache_set_again_requeues_key() {
+        let cache = Cache::<String, String>::new(Duration::from_millis(200));
+        let key = "test".to_string();
+
+        cache.set(key.clone(), "old".to_string()).await;
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        cache.set(key.clone(), "new".to_string()).await;
+
+        assert_eq!(cache.shard(&key).read().unwrap().expiry.len(), 1);
+
+        // The first set would have expired by now, the second one hasn't
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(cache.sweep().await, 0);
+        assert_eq!(cache.get(&key).await, Some("new".to_string()));
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(cache.sweep().await, 1);
+        assert_eq!(cache.stats().await.bytes, 0);
+    }
+
+    /// Values stay fresh for a millisecond per byte
+    #[derive(Debug)]
+    struct LengthPolicy;
+
+    impl CachePolicy<String> for LengthPolicy {
+        fn ttl(&self, value: &String) -> Duration {
+            Duration::from_millis(value.len() as u64)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_ttl_per_value() {
+        let cache = Cache::<String, String>::with_backend(
+            LengthPolicy,
+            CacheLimits::default(),
+            Arc::new(crate::cache_backend::MemoryBackend),
+        );
+        cache.set("short".to_string(), "x".repeat(100)).await;
+        cache.set("long".to_string(), "x".repeat(400)).await;
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(cache.get(&"short".to_string()).await.is_none());
+        assert!(cache.get(&"long".to_string()).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cache_serves_stale_while_revalidating() {
+        let cache = Arc::new(
+            Cache::<String, String>::new(Duration::from_millis(100))
+                .serve_stale_for(Duration::from_millis(300)),
+        );
+        let key = "test".to_string();
+        cache.set(key.clone(), "old".to_string()).await;
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        // Stale values aren't returned without a way to refresh them
+        assert!(cache.get(&key).await.is_none());
+
+        let fetches = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let revalidate = || {
+            let fetches = fetches.clone();
+            cache.get_or_revalidate(key.clone(), || async move {
+                fetches.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok::<_, Infallible>("new".to_string())
+            })
+        };
+        assert_eq!(revalidate().await, Ok("old".to_string()));
+        assert_eq!(revalidate().await, Ok("old".to_string()));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(revalidate().await, Ok("new".to_string()));
+        assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(cache.stats().await.stale_hits, 2);
+
+        // Past the window the value is gone and the lookup waits for the fetch
+        tokio::time::sleep(Duration::from_millis(450)).await;
+        assert_eq!(revalidate().await, Ok("new".to_string()));
+        assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cache_persist_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = Arc::new(crate::cache_backend::FileBackend::new(
+            dir.path().join("cache.json"),
+        ));
+        let ttl = Duration::from_millis(300);
+
+        let cache =
+            Cache::<String, String>::with_backend(ttl, CacheLimits::default(), backend.clone());
+        cache.set("old".to_string(), "value".to_string()).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        cache.set("new".to_string(), "value".to_string()).await;
+        assert_eq!(cache.persist().await.unwrap(), 2);
+
+        let restarted = Cache::<String, String>::with_backend(ttl, CacheLimits::default(), backend);
+        assert_eq!(restarted.restore().unwrap(), 2);
+        assert!(restarted.get(&"old".to_string()).await.is_some());
+
+        // Restored entries keep their age, the old one expires first
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(restarted.get(&"old".to_string()).await.is_none());
+        assert!(restarted.get(&"new".to_string()).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cache_persist_reports_panicking_backend() {
+        #[derive(Debug)]
+        struct PanickingBackend;
+
+        impl crate::cache_backend::CacheBackend<String, String> for PanickingBackend {
+            fn load(&self) -> Result<Vec<StoredEntry<String, String>>, CacheBackendError> {
+                Ok(Vec::new())
+            }
+
+            fn save(
+                &self,
+                _entries: &[StoredEntry<String, String>],
+            ) -> Result<(), CacheBackendError> {
+                panic!("disk on fire")
+            }
+        }
+
+        let cache = Cache::<String, String>::with_backend(
+            Duration::from_secs(1),
+            CacheLimits::default(),
+            Arc::new(PanickingBackend),
+        );
+        cache.set("key".to_string(), "value".to_string()).await;
+        assert!(matches!(
+            cache.persist().await,
+            Err(CacheBackendError::Task(_))
+        ));
+    }
+
+    /// Mixed gets and sets from many tasks on a few hot keys, see `benchmark/README.md`
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    async fn bench_cache_contention() {
+        const TASKS: usize = 64;
+        const OPERATIONS: usize = 50_000;
+        const KEYS: usize = 1000;
+
+        let cache = Arc::new(Cache::<String, String>::new(Duration::from_secs(60)));
+        let keys: Arc<Vec<String>> =
+            Arc::new((0..KEYS).map(|i| format!("user{i}@example.org")).collect());
+        for key in keys.iter() {
+            cache.set(key.clone(), "value".to_string()).await;
+        }
+
+        let started = Instant::now();
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|task| {
+                let cache = cache.clone();
+                let keys = keys.clone();
+                tokio::spawn(async move {
+                    for i in 0..OPERATIONS {
+                        let key = &keys[(task * 31 + i * 7) % KEYS];
+                        // One set for every nine gets, about what a warm server sees
+                        if i % 10 == 0 {
+                            cache.set(key.clone(), "value".to_string()).await;
+                        } else {
+                            assert!(cache.get(key).await.is_some());
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let elapsed = started.elapsed();
+        let operations = TASKS * OPERATIONS;
+        println!(
+            "{operations} operations in {elapsed:.2?}, {:.0} ops/s",
+            operations as f64 / elapsed.as_secs_f64()
+        );
+    }
 }
@@ -0,0 +1,155 @@
+use std::{
+    fmt::Debug,
+    fs,
+    io::{self, BufReader, BufWriter, Write},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CacheBackendError {
+    #[error("Failed to access cache file {}: {}", .0.display(), .1)]
+    Io(PathBuf, #[source] io::Error),
+
+    #[error("Invalid cache file {}: {}", .0.display(), .1)]
+    InvalidFile(PathBuf, #[source] serde_json::Error),
+
+    #[error("Cache backend task failed: {0}")]
+    Task(#[source] tokio::task::JoinError),
+}
+
+/// A cached value with the time it was fetched, so a restored entry keeps the rest of its TTL
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StoredEntry<K, V> {
+    pub key: K,
+    pub value: V,
+    /// Milliseconds since the Unix epoch
+    pub fetched_at: u64,
+}
+
+impl<K, V> StoredEntry<K, V> {
+    pub fn new(key: K, value: V, age: Duration) -> Self {
+        let fetched_at = SystemTime::now()
+            .checked_sub(age)
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+
+        StoredEntry {
+            key,
+            value,
+            fetched_at: fetched_at.as_millis() as u64,
+        }
+    }
+
+    /// Time since the value was fetched, zero when the clock went backwards
+    pub fn age(&self) -> Duration {
+        let fetched_at = UNIX_EPOCH + Duration::from_millis(self.fetched_at);
+        SystemTime::now()
+            .duration_since(fetched_at)
+            .unwrap_or_default()
+    }
+}
+
+/// Keeps the entries of a [`Cache`](crate::cache::Cache) across restarts, lookups are always answered from memory
+pub trait CacheBackend<K, V>: Debug + Send + Sync {
+    /// Entries saved by the last run
+    fn load(&self) -> Result<Vec<StoredEntry<K, V>>, CacheBackendError>;
+
+    /// Replaces the saved entries
+    fn save(&self, entries: &[StoredEntry<K, V>]) -> Result<(), CacheBackendError>;
+}
+
+/// The default, nothing outlives the process
+#[derive(Debug, Default)]
+pub struct MemoryBackend;
+
+impl<K, V> CacheBackend<K, V> for MemoryBackend {
+    fn load(&self) -> Result<Vec<StoredEntry<K, V>>, CacheBackendError> {
+        Ok(Vec::new())
+    }
+
+    fn save(&self, _entries: &[StoredEntry<K, V>]) -> Result<(), CacheBackendError> {
+        Ok(())
+    }
+}
+
+/// Snapshots the entries to a JSON file, a missing file loads as empty
+#[derive(Debug)]
+pub struct FileBackend {
+    path: PathBuf,
+}
+
+impl FileBackend {
+    pub fn new(path: PathBuf) -> Self {
+        FileBackend { path }
+    }
+
+    fn io_error(&self, err: io::Error) -> CacheBackendError {
+        CacheBackendError::Io(self.path.clone(), err)
+    }
+}
+
+impl<K, V> CacheBackend<K, V> for FileBackend
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    fn load(&self) -> Result<Vec<StoredEntry<K, V>>, CacheBackendError> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(self.io_error(err)),
+        };
+
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|err| CacheBackendError::InvalidFile(self.path.clone(), err))
+    }
+
+    fn save(&self, entries: &[StoredEntry<K, V>]) -> Result<(), CacheBackendError> {
+        // Written next to the snapshot and renamed over it, so a crash never leaves a truncated file
+        let mut partial = self.path.clone().into_os_string();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+
+        let file = fs::File::create(&partial).map_err(|err| self.io_error(err))?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, entries)
+            .map_err(|err| CacheBackendError::InvalidFile(self.path.clone(), err))?;
+        writer.flush().map_err(|err| self.io_error(err))?;
+        drop(writer);
+
+        fs::rename(&partial, &self.path).map_err(|err| self.io_error(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_backend_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json");
+        let backend = FileBackend::new(path.clone());
+
+        let loaded: Vec<StoredEntry<String, String>> = backend.load().unwrap();
+        assert!(loaded.is_empty());
+
+        let entries = vec![StoredEntry::new(
+            "key".to_string(),
+            "value".to_string(),
+            Duration::from_secs(30),
+        )];
+        backend.save(&entries).unwrap();
+        let loaded: Vec<StoredEntry<String, String>> = backend.load().unwrap();
+        assert_eq!(loaded, entries);
+        assert!(loaded[0].age() >= Duration::from_secs(30));
+
+        fs::write(&path, "not json").unwrap();
+        let result: Result<Vec<StoredEntry<String, String>>, _> = backend.load();
+        assert!(matches!(result, Err(CacheBackendError::InvalidFile(..))));
+    }
+}
@@ -1,48 +1,582 @@
-use serde::Serialize;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use thiserror::Error;
+use wkd::client::{DEFAULT_BLOCKED_NETWORKS, WkdClientError, WkdFetchConfig};
 
-#[derive(Clone, Serialize)]
+/// Environment variable with the path of the config file
+pub const CONFIG_PATH_ENV: &str = "WKD_TESTER_SERVER_CONFIG";
+/// Config file read from the working directory when [`CONFIG_PATH_ENV`] is not set and it exists
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+/// Prefix of the environment variables overriding single settings, e.g. `WKD_TESTER_SERVER_PORT`
+pub const ENV_PREFIX: &str = "WKD_TESTER_SERVER_";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config file {}: {}", .0.display(), .1)]
+    ReadFailed(PathBuf, #[source] std::io::Error),
+
+    #[error("Invalid config file {}: {}", .0.display(), .1)]
+    InvalidFile(PathBuf, #[source] toml::de::Error),
+
+    #[error("Invalid value `{1}` for {0}")]
+    InvalidEnv(String, String),
+
+    #[error("Invalid `{0}`: {1}")]
+    InvalidValue(&'static str, &'static str),
+
+    #[error("Invalid client settings: {0}")]
+    InvalidClient(#[source] WkdClientError),
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
 pub struct StaticContentConfig {
     /// URL to your website used in the footer
-    pub footer_hoster_url: &'static str,
+    pub footer_hoster_url: String,
     /// Your name or organization name used in the footer
-    pub footer_hoster_name: &'static str,
+    pub footer_hoster_name: String,
     /// The base URL ie: protocol://host
-    pub base_url: &'static str,
-    /// This sets the path used in static content. Path must start with a slash but not end with a slash. For `/` use `""`.
-    pub root_path: &'static str,
+    pub base_url: String,
+    /// Path all services are mounted under, also used in static content. Path must start with a slash but not end with a slash. For `/` use `""`.
+    pub root_path: String,
 }
 
+impl Default for StaticContentConfig {
+    fn default() -> Self {
+        StaticContentConfig {
+            footer_hoster_url: "https://chimbosonic.com".to_string(),
+            footer_hoster_name: "Alexis Lowe".to_string(),
+            base_url: "https://wkd.dp42.dev".to_string(),
+            root_path: "".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
 pub struct ServerConfig {
     /// The host address to bind to.
-    pub host: &'static str,
+    pub host: String,
     /// The port to bind to.
     pub port: u16,
     /// Client Timeout in milliseconds
     pub client_timeout: u64,
-    /// Cache TTL in milliseconds
+    /// PEM bundles with extra CA certificates to trust for lookups, for WKD hosts behind a private CA
+    pub client_ca_certs: Vec<PathBuf>,
+    /// Only trust `client_ca_certs`, not the system roots
+    pub client_disable_system_roots: bool,
+    /// PEM file with the client certificate (and key) for mTLS protected WKD hosts
+    pub client_cert: Option<PathBuf>,
+    /// PEM file with the private key of `client_cert`
+    pub client_key: Option<PathBuf>,
+    /// Egress proxy for all lookups, an http://, https://, socks5:// or socks5h:// URL
+    pub client_proxy: Option<String>,
+    /// Proxy for http:// requests only, wins over `client_proxy`
+    pub client_http_proxy: Option<String>,
+    /// Proxy for https:// requests only, wins over `client_proxy`
+    pub client_https_proxy: Option<String>,
+    /// Hosts, domains and IP ranges looked up without the proxies
+    pub client_no_proxy: Vec<String>,
+    /// Networks lookups may not connect to, also after redirects. Empty disables the SSRF guard
+    pub client_blocked_networks: Vec<IpNet>,
+    /// Lookups a client can make at once, 0 disables the per-client limit
+    pub rate_limit_client_burst: u32,
+    /// Lookups a client regains per minute
+    pub rate_limit_client_per_minute: u32,
+    /// Uncached lookups of one domain at once, 0 disables the per-domain limit
+    pub rate_limit_domain_burst: u32,
+    /// Uncached lookups of one domain regained per minute
+    pub rate_limit_domain_per_minute: u32,
+    /// Lookups with `refresh=true` a client can make at once, 0 disables the limit
+    pub rate_limit_refresh_burst: u32,
+    /// Lookups with `refresh=true` a client regains per minute
+    pub rate_limit_refresh_per_minute: u32,
+    /// Reverse proxies whose `rate_limit_client_ip_header` is used for the client address
+    pub rate_limit_trusted_proxies: Vec<IpNet>,
+    /// Header the trusted proxies put the client address in
+    pub rate_limit_client_ip_header: String,
+    /// Cache TTL in milliseconds of lookups that found a key without errors
     #[cfg(feature = "wkd-cache")]
     pub cache_ttl: u64,
+    /// Cache TTL in milliseconds of lookups that found a key but reported errors
+    #[cfg(feature = "wkd-cache")]
+    pub cache_ttl_partial: u64,
+    /// Cache TTL in milliseconds of lookups that found no key
+    #[cfg(feature = "wkd-cache")]
+    pub cache_ttl_failure: u64,
+    /// Milliseconds a lookup past its TTL is still served while it is looked up again, 0 disables it
+    #[cfg(feature = "wkd-cache")]
+    pub cache_stale_while_revalidate: u64,
+    /// Cached lookups kept before the least recently used are evicted, 0 disables the limit
+    #[cfg(feature = "wkd-cache")]
+    pub cache_max_entries: usize,
+    /// Approximate memory in bytes the cached lookups may use, 0 disables the limit
+    #[cfg(feature = "wkd-cache")]
+    pub cache_max_bytes: usize,
+    /// File the cache is saved to and restored from on restart, unset keeps it in memory only
+    #[cfg(feature = "wkd-cache")]
+    pub cache_path: Option<PathBuf>,
+    /// Seconds between saves of the cache to `cache_path`, 0 only saves it on shutdown
+    #[cfg(feature = "wkd-cache")]
+    pub cache_save_interval: u64,
+    /// Serve the cache counters at `/api/cache/stats`, they show how often addresses are looked up
+    #[cfg(feature = "wkd-cache")]
+    pub cache_stats: bool,
 }
 
-/// Make sure to update this with your information if you are self hosting.
-pub static STATIC_CONTENT_CONFIG: StaticContentConfig = StaticContentConfig {
-    footer_hoster_url: "https://chimbosonic.com",
-    footer_hoster_name: "Alexis Lowe",
-    base_url: "https://wkd.dp42.dev",
-    root_path: "",
-};
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: "0.0.0.0".to_string(),
+            port: 7070,
+            client_timeout: 3000,
+            client_ca_certs: Vec::new(),
+            client_disable_system_roots: false,
+            client_cert: None,
+            client_key: None,
+            client_proxy: None,
+            client_http_proxy: None,
+            client_https_proxy: None,
+            client_no_proxy: Vec::new(),
+            client_blocked_networks: DEFAULT_BLOCKED_NETWORKS
+                .iter()
+                .map(|network| network.parse().expect("valid default network"))
+                .collect(),
+            rate_limit_client_burst: 20,
+            rate_limit_client_per_minute: 30,
+            rate_limit_domain_burst: 30,
+            rate_limit_domain_per_minute: 60,
+            rate_limit_refresh_burst: 3,
+            rate_limit_refresh_per_minute: 6,
+            rate_limit_trusted_proxies: Vec::new(),
+            rate_limit_client_ip_header: "X-Forwarded-For".to_string(),
+            #[cfg(feature = "wkd-cache")]
+            cache_ttl: 10000,
+            #[cfg(feature = "wkd-cache")]
+            cache_ttl_partial: 5000,
+            #[cfg(feature = "wkd-cache")]
+            cache_ttl_failure: 2000,
+            #[cfg(feature = "wkd-cache")]
+            cache_stale_while_revalidate: 60000,
+            #[cfg(feature = "wkd-cache")]
+            cache_max_entries: 10_000,
+            #[cfg(feature = "wkd-cache")]
+            cache_max_bytes: 64 * 1024 * 1024,
+            #[cfg(feature = "wkd-cache")]
+            cache_path: None,
+            #[cfg(feature = "wkd-cache")]
+            cache_save_interval: 300,
+            #[cfg(feature = "wkd-cache")]
+            cache_stats: false,
+        }
+    }
+}
 
-/// Make sure to update this with your information if you are self hosting.
-pub static SERVER_CONFIG: ServerConfig = ServerConfig {
-    host: "0.0.0.0",
-    port: 7070,
-    client_timeout: 3000,
-    #[cfg(feature = "wkd-cache")]
-    cache_ttl: 10000,
-};
+impl ServerConfig {
+    /// Settings of the client used for the lookups
+    pub fn fetch_config(&self) -> WkdFetchConfig {
+        WkdFetchConfig {
+            timeout: Some(Duration::from_millis(self.client_timeout)),
+            ca_certs: self.client_ca_certs.clone(),
+            disable_system_roots: self.client_disable_system_roots,
+            client_cert: self.client_cert.clone(),
+            client_key: self.client_key.clone(),
+            proxy: self.client_proxy.clone(),
+            http_proxy: self.client_http_proxy.clone(),
+            https_proxy: self.client_https_proxy.clone(),
+            no_proxy: self.client_no_proxy.clone(),
+            blocked_networks: self.client_blocked_networks.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Everything that can be set per deployment, the `[server]` and `[static_content]` tables of the config file
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub static_content: StaticContentConfig,
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// The config set by [`init`], or the defaults when it was never called
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+/// Makes `config` the one returned by [`get`], only the first call has an effect
+pub fn init(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+fn env_var<T: FromStr>(
+    vars: &impl Fn(&str) -> Option<String>,
+    name: &str,
+) -> Result<Option<T>, ConfigError> {
+    let name = format!("{ENV_PREFIX}{name}");
+    match vars(&name) {
+        Some(value) => match value.parse() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(_) => Err(ConfigError::InvalidEnv(name, value)),
+        },
+        None => Ok(None),
+    }
+}
+
+fn env_list<T: FromStr>(
+    vars: &impl Fn(&str) -> Option<String>,
+    name: &str,
+) -> Result<Option<Vec<T>>, ConfigError> {
+    let name = format!("{ENV_PREFIX}{name}");
+    let Some(value) = vars(&name) else {
+        return Ok(None);
+    };
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            item.parse()
+                .map_err(|_| ConfigError::InvalidEnv(name.clone(), item.to_string()))
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+impl Config {
+    /// Reads the config file and the `WKD_TESTER_SERVER_*` variables, the variables take precedence
+    pub fn load() -> Result<Config, ConfigError> {
+        let path = match std::env::var_os(CONFIG_PATH_ENV) {
+            Some(path) => Some(PathBuf::from(path)),
+            None => Some(PathBuf::from(DEFAULT_CONFIG_PATH)).filter(|path| path.is_file()),
+        };
+        let mut config = match path {
+            Some(path) => Config::from_file(&path)?,
+            None => Config::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| ConfigError::ReadFailed(path.to_path_buf(), err))?;
+        toml::from_str(&contents).map_err(|err| ConfigError::InvalidFile(path.to_path_buf(), err))
+    }
+
+    /// Overrides the settings that have a variable in `vars`, lists are comma separated
+    pub fn apply_env(&mut self, vars: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        let server = &mut self.server;
+        if let Some(host) = env_var(&vars, "HOST")? {
+            server.host = host;
+        }
+        if let Some(port) = env_var(&vars, "PORT")? {
+            server.port = port;
+        }
+        if let Some(client_timeout) = env_var(&vars, "CLIENT_TIMEOUT")? {
+            server.client_timeout = client_timeout;
+        }
+        if let Some(client_ca_certs) = env_list(&vars, "CLIENT_CA_CERTS")? {
+            server.client_ca_certs = client_ca_certs;
+        }
+        if let Some(client_disable_system_roots) = env_var(&vars, "CLIENT_DISABLE_SYSTEM_ROOTS")? {
+            server.client_disable_system_roots = client_disable_system_roots;
+        }
+        if let Some(client_cert) = env_var(&vars, "CLIENT_CERT")? {
+            server.client_cert = Some(client_cert);
+        }
+        if let Some(client_key) = env_var(&vars, "CLIENT_KEY")? {
+            server.client_key = Some(client_key);
+        }
+        if let Some(client_proxy) = env_var(&vars, "CLIENT_PROXY")? {
+            server.client_proxy = Some(client_proxy);
+        }
+        if let Some(client_http_proxy) = env_var(&vars, "CLIENT_HTTP_PROXY")? {
+            server.client_http_proxy = Some(client_http_proxy);
+        }
+        if let Some(client_https_proxy) = env_var(&vars, "CLIENT_HTTPS_PROXY")? {
+            server.client_https_proxy = Some(client_https_proxy);
+        }
+        if let Some(client_no_proxy) = env_list(&vars, "CLIENT_NO_PROXY")? {
+            server.client_no_proxy = client_no_proxy;
+        }
+        if let Some(client_blocked_networks) = env_list(&vars, "CLIENT_BLOCKED_NETWORKS")? {
+            server.client_blocked_networks = client_blocked_networks;
+        }
+        if let Some(burst) = env_var(&vars, "RATE_LIMIT_CLIENT_BURST")? {
+            server.rate_limit_client_burst = burst;
+        }
+        if let Some(per_minute) = env_var(&vars, "RATE_LIMIT_CLIENT_PER_MINUTE")? {
+            server.rate_limit_client_per_minute = per_minute;
+        }
+        if let Some(burst) = env_var(&vars, "RATE_LIMIT_DOMAIN_BURST")? {
+            server.rate_limit_domain_burst = burst;
+        }
+        if let Some(per_minute) = env_var(&vars, "RATE_LIMIT_DOMAIN_PER_MINUTE")? {
+            server.rate_limit_domain_per_minute = per_minute;
+        }
+        if let Some(burst) = env_var(&vars, "RATE_LIMIT_REFRESH_BURST")? {
+            server.rate_limit_refresh_burst = burst;
+        }
+        if let Some(per_minute) = env_var(&vars, "RATE_LIMIT_REFRESH_PER_MINUTE")? {
+            server.rate_limit_refresh_per_minute = per_minute;
+        }
+        if let Some(trusted_proxies) = env_list(&vars, "RATE_LIMIT_TRUSTED_PROXIES")? {
+            server.rate_limit_trusted_proxies = trusted_proxies;
+        }
+        if let Some(header) = env_var(&vars, "RATE_LIMIT_CLIENT_IP_HEADER")? {
+            server.rate_limit_client_ip_header = header;
+        }
+        #[cfg(feature = "wkd-cache")]
+        if let Some(cache_ttl) = env_var(&vars, "CACHE_TTL")? {
+            server.cache_ttl = cache_ttl;
+        }
+        #[cfg(feature = "wkd-cache")]
+        if let Some(cache_ttl) = env_var(&vars, "CACHE_TTL_PARTIAL")? {
+            server.cache_ttl_partial = cache_ttl;
+        }
+        #[cfg(feature = "wkd-cache")]
+        if let Some(cache_ttl) = env_var(&vars, "CACHE_TTL_FAILURE")? {
+            server.cache_ttl_failure = cache_ttl;
+        }
+        #[cfg(feature = "wkd-cache")]
+        if let Some(window) = env_var(&vars, "CACHE_STALE_WHILE_REVALIDATE")? {
+            server.cache_stale_while_revalidate = window;
+        }
+        #[cfg(feature = "wkd-cache")]
+        if let Some(max_entries) = env_var(&vars, "CACHE_MAX_ENTRIES")? {
+            server.cache_max_entries = max_entries;
+        }
+        #[cfg(feature = "wkd-cache")]
+        if let Some(max_bytes) = env_var(&vars, "CACHE_MAX_BYTES")? {
+            server.cache_max_bytes = max_bytes;
+        }
+        #[cfg(feature = "wkd-cache")]
+        if let Some(cache_path) = env_var(&vars, "CACHE_PATH")? {
+            server.cache_path = Some(cache_path);
+        }
+        #[cfg(feature = "wkd-cache")]
+        if let Some(save_interval) = env_var(&vars, "CACHE_SAVE_INTERVAL")? {
+            server.cache_save_interval = save_interval;
+        }
+        #[cfg(feature = "wkd-cache")]
+        if let Some(cache_stats) = env_var(&vars, "CACHE_STATS")? {
+            server.cache_stats = cache_stats;
+        }
+
+        let static_content = &mut self.static_content;
+        if let Some(footer_hoster_url) = env_var(&vars, "FOOTER_HOSTER_URL")? {
+            static_content.footer_hoster_url = footer_hoster_url;
+        }
+        if let Some(footer_hoster_name) = env_var(&vars, "FOOTER_HOSTER_NAME")? {
+            static_content.footer_hoster_name = footer_hoster_name;
+        }
+        if let Some(base_url) = env_var(&vars, "BASE_URL")? {
+            static_content.base_url = base_url;
+        }
+        if let Some(root_path) = env_var(&vars, "ROOT_PATH")? {
+            static_content.root_path = root_path;
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let Config {
+            server,
+            static_content,
+        } = self;
+
+        if server.host.is_empty() {
+            return Err(ConfigError::InvalidValue("host", "must not be empty"));
+        }
+        if server.client_timeout == 0 {
+            return Err(ConfigError::InvalidValue(
+                "client_timeout",
+                "must be greater than 0",
+            ));
+        }
+        if server.rate_limit_client_burst > 0 && server.rate_limit_client_per_minute == 0 {
+            return Err(ConfigError::InvalidValue(
+                "rate_limit_client_per_minute",
+                "must be greater than 0 while the limit is enabled",
+            ));
+        }
+        if server.rate_limit_domain_burst > 0 && server.rate_limit_domain_per_minute == 0 {
+            return Err(ConfigError::InvalidValue(
+                "rate_limit_domain_per_minute",
+                "must be greater than 0 while the limit is enabled",
+            ));
+        }
+        if server.rate_limit_refresh_burst > 0 && server.rate_limit_refresh_per_minute == 0 {
+            return Err(ConfigError::InvalidValue(
+                "rate_limit_refresh_per_minute",
+                "must be greater than 0 while the limit is enabled",
+            ));
+        }
+        if !server.client_blocked_networks.is_empty()
+            && (server.client_proxy.is_some()
+                || server.client_http_proxy.is_some()
+                || server.client_https_proxy.is_some())
+        {
+            return Err(ConfigError::InvalidValue(
+                "client_blocked_networks",
+                "must be empty while a client proxy is set, block the networks in the proxy",
+            ));
+        }
+        // Builds the client once, so unreadable certificates or invalid proxy URLs fail here
+        server
+            .fetch_config()
+            .client()
+            .map_err(ConfigError::InvalidClient)?;
+        if actix_web::http::header::HeaderName::try_from(
+            server.rate_limit_client_ip_header.as_str(),
+        )
+        .is_err()
+        {
+            return Err(ConfigError::InvalidValue(
+                "rate_limit_client_ip_header",
+                "must be a valid header name",
+            ));
+        }
+        if !(static_content.base_url.starts_with("http://")
+            || static_content.base_url.starts_with("https://"))
+        {
+            return Err(ConfigError::InvalidValue(
+                "base_url",
+                "must start with http:// or https://",
+            ));
+        }
+        if static_content.base_url.ends_with('/') {
+            return Err(ConfigError::InvalidValue(
+                "base_url",
+                "must not end with a slash",
+            ));
+        }
+        let root_path = &static_content.root_path;
+        if !root_path.is_empty() && !root_path.starts_with('/') {
+            return Err(ConfigError::InvalidValue(
+                "root_path",
+                "must start with a slash, use \"\" for the site root",
+            ));
+        }
+        if root_path.ends_with('/') {
+            return Err(ConfigError::InvalidValue(
+                "root_path",
+                "must not end with a slash, use \"\" for the site root",
+            ));
+        }
+
+        Ok(())
+    }
+}
 
 #[cfg(feature = "embed-static")]
 pub static INDEX_HBS: &str = include_str!("../static/index.hbs");
 
 #[cfg(feature = "embed-static")]
 pub static SITEMAP_HBS: &str = include_str!("../static/sitemap.hbs");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn file_and_env_override_defaults() {
+        let mut config: Config = toml::from_str(
+            r#"
+[server]
+port = 8080
+client_https_proxy = "socks5h://proxy.example.org:1080"
+client_no_proxy = ["localhost"]
+client_blocked_networks = []
+
+[static_content]
+base_url = "https://wkd.example.org"
+"#,
+        )
+        .unwrap();
+        let vars = HashMap::from([
+            ("WKD_TESTER_SERVER_HOST", "127.0.0.1"),
+            ("WKD_TESTER_SERVER_CLIENT_NO_PROXY", "a.example, .b.example"),
+            ("WKD_TESTER_SERVER_ROOT_PATH", "/wkd"),
+            (
+                "WKD_TESTER_SERVER_CLIENT_HTTP_PROXY",
+                "http://proxy.example.org:3128",
+            ),
+        ]);
+        config
+            .apply_env(|name| vars.get(name).map(|value| value.to_string()))
+            .unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.server.host, "127.0.0.1");
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.server.client_timeout, 3000);
+        assert_eq!(
+            config.server.client_no_proxy,
+            vec!["a.example", ".b.example"]
+        );
+        assert_eq!(
+            config.server.client_http_proxy.as_deref(),
+            Some("http://proxy.example.org:3128")
+        );
+        assert_eq!(
+            config.server.client_https_proxy.as_deref(),
+            Some("socks5h://proxy.example.org:1080")
+        );
+        assert_eq!(config.static_content.base_url, "https://wkd.example.org");
+        assert_eq!(config.static_content.root_path, "/wkd");
+        assert_eq!(config.static_content.footer_hoster_name, "Alexis Lowe");
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        assert!(toml::from_str::<Config>("[server]\nports = 8080\n").is_err());
+
+        let mut config = Config::default();
+        assert!(
+            config
+                .apply_env(|name| (name == "WKD_TESTER_SERVER_PORT").then(|| "http".to_string()))
+                .is_err()
+        );
+
+        for root_path in ["/wkd/", "/", "wkd"] {
+            let mut config = Config::default();
+            config.static_content.root_path = root_path.to_string();
+            assert!(config.validate().is_err(), "{root_path}");
+        }
+        let mut config = Config::default();
+        config.static_content.base_url = "https://wkd.example.org/".to_string();
+        assert!(config.validate().is_err());
+
+        // The proxy would resolve the WKD hosts past the blocked networks
+        let mut config = Config::default();
+        config.server.client_proxy = Some("http://proxy.example.org:3128".to_string());
+        assert!(config.validate().is_err());
+        config.server.client_blocked_networks.clear();
+        config.validate().unwrap();
+
+        let mut config = Config::default();
+        config.server.client_ca_certs = vec![PathBuf::from("/nonexistent/ca.pem")];
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidClient(_))
+        ));
+        let mut config = Config::default();
+        config.server.client_blocked_networks.clear();
+        config.server.client_proxy = Some("not a proxy url".to_string());
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidClient(_))
+        ));
+    }
+}
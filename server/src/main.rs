@@ -1,4 +1,5 @@
 mod config;
+mod rate_limit;
 mod render;
 mod routes;
 mod wkd_result;
@@ -8,23 +9,34 @@ mod telemetry;
 
 #[cfg(feature = "wkd-cache")]
 mod cache;
+#[cfg(feature = "wkd-cache")]
+mod cache_backend;
 
 #[cfg(feature = "wkd-cache")]
 use {
-    crate::{cache::Cache, wkd_result::WkdResult},
-    std::time::Duration,
+    crate::{
+        cache::{Cache, CacheLimits},
+        cache_backend::{CacheBackend, FileBackend, MemoryBackend},
+        rate_limit::RateLimited,
+        wkd_result::{LookupKey, OutcomeTtls, WkdResult},
+    },
+    std::{sync::Arc, time::Duration},
+    wkd::fetch::WkdMethodSelection,
 };
 
 use actix_web::http::StatusCode;
 use actix_web::http::header::{CACHE_CONTROL, HeaderValue};
 use actix_web::middleware::ErrorHandlerResponse;
 use actix_web::{App, HttpServer, Result, middleware, web};
-use config::SERVER_CONFIG;
+use config::Config;
 use handlebars::Handlebars;
+use rate_limit::RateLimits;
 use reqwest::Client;
 use routes::{ApiDoc, api, lookup, serve_sitemap};
 use utoipa::OpenApi;
+use utoipa::openapi::Server;
 use utoipa_swagger_ui::SwaggerUi;
+use wkd::client::WkdClientError;
 
 #[cfg(not(feature = "embed-static"))]
 use handlebars::DirectorySourceOptions;
@@ -54,13 +66,10 @@ fn setup_handlebars() -> web::Data<Handlebars<'static>> {
     web::Data::new(handlebars)
 }
 
-fn setup_reqwest_client() -> web::Data<Client> {
-    let client = Client::builder()
-        .timeout(Duration::from_millis(SERVER_CONFIG.client_timeout))
-        .build()
-        .expect("Failed to build reqwest client");
+fn setup_reqwest_client() -> Result<web::Data<Client>, WkdClientError> {
+    let client = config::get().server.fetch_config().client()?;
 
-    web::Data::new(client)
+    Ok(web::Data::new(client))
 }
 
 fn add_error_header<B>(
@@ -89,14 +98,68 @@ fn setup_error_handlers_middleware<B: 'static>() -> middleware::ErrorHandlers<B>
         .handler(StatusCode::BAD_REQUEST, add_error_header)
 }
 
+/// Mounts the pages and the API under `root_path` and the Swagger UI under `{root_path}/api-docs/`,
+/// `root_path` itself redirects to `{root_path}/`
+fn setup_services(
+    root_path: &str,
+    mut openapi: utoipa::openapi::OpenApi,
+) -> impl FnOnce(&mut web::ServiceConfig) {
+    if !root_path.is_empty() {
+        openapi.servers = Some(vec![Server::new(root_path)]);
+    }
+    let scope = web::scope(root_path)
+        .service(lookup)
+        .service(api)
+        .service(serve_sitemap);
+    #[cfg(feature = "wkd-cache")]
+    let scope = if config::get().server.cache_stats {
+        scope.service(routes::cache_stats)
+    } else {
+        scope
+    };
+    let swagger_ui = SwaggerUi::new(format!("{root_path}/api-docs/ui/{{_:.*}}"))
+        .url(format!("{root_path}/api-docs/openapi.json"), openapi);
+
+    let index_redirect = (!root_path.is_empty())
+        .then(|| web::redirect(root_path.to_string(), format!("{root_path}/")));
+
+    move |cfg| {
+        if let Some(index_redirect) = index_redirect {
+            cfg.service(index_redirect);
+        }
+        cfg.service(swagger_ui).service(scope);
+    }
+}
+
+fn setup_rate_limits() -> web::Data<RateLimits> {
+    web::Data::new(RateLimits::new(&config::get().server))
+}
+
 #[cfg(feature = "wkd-cache")]
-type WebCache = Cache<String, WkdResult>;
+type WebCache = Cache<(LookupKey, WkdMethodSelection), WkdResult, RateLimited>;
 
 #[cfg(feature = "wkd-cache")]
 fn setup_cache() -> web::Data<WebCache> {
-    let time_to_live = Duration::from_millis(SERVER_CONFIG.cache_ttl);
+    let server = &config::get().server;
+    let ttls = OutcomeTtls {
+        healthy: Duration::from_millis(server.cache_ttl),
+        partial: Duration::from_millis(server.cache_ttl_partial),
+        failure: Duration::from_millis(server.cache_ttl_failure),
+    };
+    let limits = CacheLimits {
+        max_entries: server.cache_max_entries,
+        max_bytes: server.cache_max_bytes,
+    };
 
-    web::Data::new(WebCache::new(time_to_live))
+    let backend: Arc<dyn CacheBackend<_, _>> = match &server.cache_path {
+        Some(path) => Arc::new(FileBackend::new(path.clone())),
+        None => Arc::new(MemoryBackend),
+    };
+
+    web::Data::new(
+        WebCache::with_backend(ttls, limits, backend)
+            .serve_stale_for(Duration::from_millis(server.cache_stale_while_revalidate)),
+    )
 }
 
 #[actix_web::main]
@@ -107,50 +170,94 @@ async fn main() -> std::io::Result<()> {
     #[cfg(not(feature = "otel"))]
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
+    match Config::load() {
+        Ok(config) => config::init(config),
+        Err(err) => {
+            log::error!("{err}");
+            std::process::exit(1);
+        }
+    }
+
     #[cfg(feature = "wkd-cache")]
     let cache = setup_cache();
 
     #[cfg(feature = "wkd-cache")]
     {
-        let cache = cache.clone();
+        match cache.restore() {
+            Ok(0) => {}
+            Ok(restored) => log::info!("Restored {restored} cached lookups"),
+            Err(err) => log::warn!("{err}"),
+        }
+
+        let sweep_cache = cache.clone();
         tokio::spawn(async move {
-            cache.sweep_task().await;
+            sweep_cache.sweep_task().await;
         });
+
+        let save_interval = config::get().server.cache_save_interval;
+        if config::get().server.cache_path.is_some() && save_interval > 0 {
+            let persist_cache = cache.clone();
+            tokio::spawn(async move {
+                persist_cache
+                    .persist_task(Duration::from_secs(save_interval))
+                    .await;
+            });
+        }
     }
 
-    let host = SERVER_CONFIG.host;
-    let port = SERVER_CONFIG.port;
+    let host = config::get().server.host.as_str();
+    let port = config::get().server.port;
+    let root_path = config::get().static_content.root_path.as_str();
 
     let handlebars_ref = setup_handlebars();
-    let reqwest_client_ref = setup_reqwest_client();
+    let reqwest_client_ref = match setup_reqwest_client() {
+        Ok(client) => client,
+        Err(err) => {
+            log::error!("{err}");
+            std::process::exit(1);
+        }
+    };
+    let rate_limits_ref = setup_rate_limits();
+    let sweep_rate_limits = rate_limits_ref.clone();
+    actix_web::rt::spawn(async move {
+        sweep_rate_limits.sweep_task().await;
+    });
     let openapi = ApiDoc::openapi();
 
-    log::info!("Starting server on http://{host}:{port}");
-    log::info!("Swagger UI available at http://{host}:{port}/api-docs/ui/");
+    log::info!("Starting server on http://{host}:{port}{root_path}/");
+    log::info!("Swagger UI available at http://{host}:{port}{root_path}/api-docs/ui/");
+    #[cfg(feature = "wkd-cache")]
+    let app_cache = cache.clone();
     HttpServer::new(move || {
         let app = App::new()
             .app_data(handlebars_ref.clone())
             .app_data(reqwest_client_ref.clone())
-            .service(lookup)
-            .service(api)
-            .service(serve_sitemap)
-            .service(
-                SwaggerUi::new("/api-docs/ui/{_:.*}")
-                    .url("/api-docs/openapi.json", openapi.clone()),
-            )
+            .app_data(rate_limits_ref.clone())
+            .configure(setup_services(root_path, openapi.clone()))
             .wrap(setup_error_handlers_middleware())
             .wrap(setup_logging_middleware())
             .wrap(setup_compression_middleware())
             .wrap(setup_default_headers_middleware());
 
         #[cfg(feature = "wkd-cache")]
-        let app = app.app_data(cache.clone());
+        let app = app.app_data(app_cache.clone());
 
         app
     })
     .bind((host, port))?
     .run()
-    .await
+    .await?;
+
+    // Saved after the workers stopped, so the snapshot has every lookup
+    #[cfg(feature = "wkd-cache")]
+    if config::get().server.cache_path.is_some() {
+        match cache.persist().await {
+            Ok(saved) => log::info!("Saved {saved} cached lookups"),
+            Err(err) => log::warn!("{err}"),
+        }
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
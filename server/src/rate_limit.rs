@@ -0,0 +1,392 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    HttpRequest, HttpResponse, ResponseError,
+    http::{
+        StatusCode,
+        header::{CACHE_CONTROL, RETRY_AFTER},
+    },
+};
+use ipnet::IpNet;
+use thiserror::Error;
+
+use crate::config::ServerConfig;
+
+/// Buckets kept before the least recently used one is dropped, a full bucket behaves like a missing one
+const MAX_BUCKETS: usize = 10_000;
+/// How often buckets that refilled are dropped
+const SWEEP_EVERY: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum RateLimited {
+    #[error("Too many lookups from your address, try again in {} seconds", retry_after_secs(.0))]
+    Client(Duration),
+
+    #[error("Too many lookups for {}, try again in {} seconds", .0, retry_after_secs(.1))]
+    Domain(String, Duration),
+
+    #[error("Too many refreshes from your address, try again in {} seconds", retry_after_secs(.0))]
+    Refresh(Duration),
+}
+
+fn retry_after_secs(retry_after: &Duration) -> u64 {
+    retry_after.as_secs_f64().ceil().max(1.0) as u64
+}
+
+impl RateLimited {
+    /// Whole seconds for the `Retry-After` header
+    pub fn retry_after_secs(&self) -> u64 {
+        match self {
+            RateLimited::Client(retry_after)
+            | RateLimited::Domain(_, retry_after)
+            | RateLimited::Refresh(retry_after) => retry_after_secs(retry_after),
+        }
+    }
+}
+
+impl ResponseError for RateLimited {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::TOO_MANY_REQUESTS
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::TooManyRequests()
+            .insert_header((RETRY_AFTER, self.retry_after_secs()))
+            .insert_header((CACHE_CONTROL, "no-store"))
+            .body(self.to_string())
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Tells apart buckets updated at the same instant in the usage order
+    seq: u64,
+}
+
+impl Bucket {
+    fn order_key(&self) -> (Instant, u64) {
+        (self.updated, self.seq)
+    }
+}
+
+#[derive(Debug)]
+struct Buckets<K> {
+    buckets: HashMap<K, Bucket>,
+    /// Keys by last use, the first one is dropped when the map is full
+    order: BTreeMap<(Instant, u64), K>,
+    seq: u64,
+}
+
+/// Token bucket per key, holding up to `burst` tokens and refilling `per_minute` tokens a minute
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    burst: f64,
+    per_second: f64,
+    buckets: Mutex<Buckets<K>>,
+}
+
+impl<K: Eq + Hash + Clone> RateLimiter<K> {
+    /// `None` when `burst` is 0, which disables the limit
+    pub fn new(burst: u32, per_minute: u32) -> Option<Self> {
+        (burst > 0).then(|| RateLimiter {
+            burst: burst.into(),
+            per_second: f64::from(per_minute) / 60.0,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                order: BTreeMap::new(),
+                seq: 0,
+            }),
+        })
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.updated = now;
+    }
+
+    /// Takes a token for `key`, or returns how long until the next one is available
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut guard = self.buckets.lock().unwrap();
+        let Buckets {
+            buckets,
+            order,
+            seq,
+        } = &mut *guard;
+        *seq += 1;
+
+        if !buckets.contains_key(&key)
+            && buckets.len() >= MAX_BUCKETS
+            && let Some((_, oldest)) = order.pop_first()
+        {
+            buckets.remove(&oldest);
+        }
+
+        let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+            seq: *seq,
+        });
+        order.remove(&bucket.order_key());
+        self.refill(bucket, now);
+        bucket.seq = *seq;
+        order.insert(bucket.order_key(), key);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+
+    /// Drops the buckets unused for long enough to have refilled, returns how many were dropped
+    pub fn sweep(&self) -> usize {
+        let idle = Duration::from_secs_f64(self.burst / self.per_second.max(f64::MIN_POSITIVE))
+            .min(Duration::from_secs(24 * 60 * 60));
+        let Some(refilled_before) = Instant::now().checked_sub(idle) else {
+            return 0;
+        };
+
+        let mut guard = self.buckets.lock().unwrap();
+        let Buckets { buckets, order, .. } = &mut *guard;
+        let mut swept = 0;
+        while let Some(entry) = order.first_entry()
+            && entry.key().0 <= refilled_before
+        {
+            buckets.remove(&entry.remove());
+            swept += 1;
+        }
+        swept
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.buckets.lock().unwrap().buckets.len()
+    }
+}
+
+/// The address a client is limited by, IPv6 clients by their /64 since they get at least that many addresses
+fn client_key(client_ip: IpAddr) -> IpAddr {
+    match client_ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from_bits(ip.to_bits() & !(u64::MAX as u128))),
+        ip => ip,
+    }
+}
+
+/// Limits on lookups per client address and per looked up domain, and on cache bypasses per client address
+#[derive(Debug)]
+pub struct RateLimits {
+    client: Option<RateLimiter<IpAddr>>,
+    domain: Option<RateLimiter<String>>,
+    refresh: Option<RateLimiter<IpAddr>>,
+    trusted_proxies: Vec<IpNet>,
+    client_ip_header: String,
+}
+
+impl RateLimits {
+    pub fn new(config: &ServerConfig) -> Self {
+        RateLimits {
+            client: RateLimiter::new(
+                config.rate_limit_client_burst,
+                config.rate_limit_client_per_minute,
+            ),
+            domain: RateLimiter::new(
+                config.rate_limit_domain_burst,
+                config.rate_limit_domain_per_minute,
+            ),
+            refresh: RateLimiter::new(
+                config.rate_limit_refresh_burst,
+                config.rate_limit_refresh_per_minute,
+            ),
+            trusted_proxies: config.rate_limit_trusted_proxies.clone(),
+            client_ip_header: config.rate_limit_client_ip_header.clone(),
+        }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.trusted_proxies
+            .iter()
+            .any(|network| network.contains(&ip))
+    }
+
+    /// The peer address, or the address a trusted proxy forwarded the request for
+    fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        let mut client_ip = req.peer_addr()?.ip();
+        if !self.is_trusted(client_ip) {
+            return Some(client_ip);
+        }
+
+        // Proxies append to the header, so it is read from the right up to the first untrusted hop
+        let forwarded: Vec<IpAddr> = req
+            .headers()
+            .get_all(self.client_ip_header.as_str())
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|hop| {
+                let hop = hop.trim();
+                hop.parse::<IpAddr>()
+                    .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+                    .ok()
+            })
+            .collect();
+        for hop in forwarded.into_iter().rev() {
+            client_ip = hop;
+            if !self.is_trusted(hop) {
+                break;
+            }
+        }
+
+        Some(client_ip)
+    }
+
+    /// Counts a lookup by the client of `req`, requests without a peer address aren't limited
+    pub fn check_client(&self, req: &HttpRequest) -> Result<(), RateLimited> {
+        match (&self.client, self.client_ip(req)) {
+            (Some(limiter), Some(client_ip)) => limiter
+                .check(client_key(client_ip))
+                .map_err(RateLimited::Client),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a lookup that bypasses the cache, on top of [`RateLimits::check_client`]
+    pub fn check_refresh(&self, req: &HttpRequest) -> Result<(), RateLimited> {
+        match (&self.refresh, self.client_ip(req)) {
+            (Some(limiter), Some(client_ip)) => limiter
+                .check(client_key(client_ip))
+                .map_err(RateLimited::Refresh),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drops the buckets of every limiter that refilled
+    pub fn sweep(&self) {
+        let swept = self.client.as_ref().map_or(0, RateLimiter::sweep)
+            + self.domain.as_ref().map_or(0, RateLimiter::sweep)
+            + self.refresh.as_ref().map_or(0, RateLimiter::sweep);
+        log::debug!("Swept {swept} refilled rate limit buckets");
+    }
+
+    pub async fn sweep_task(&self) {
+        let mut tick = actix_web::rt::time::interval(SWEEP_EVERY);
+        loop {
+            tick.tick().await;
+            self.sweep();
+        }
+    }
+
+    /// Counts a lookup that sends requests to `domain`
+    pub fn check_domain(&self, domain: &str) -> Result<(), RateLimited> {
+        match &self.domain {
+            Some(limiter) => limiter
+                .check(domain.to_string())
+                .map_err(|retry_after| RateLimited::Domain(domain.to_string(), retry_after)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_rate_limiter_burst_and_refill() {
+        let limiter = RateLimiter::new(2, 60).unwrap();
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_ok());
+        let retry_after = limiter.check("a").unwrap_err();
+        assert!(retry_after <= Duration::from_secs(1));
+        assert!(limiter.check("b").is_ok());
+
+        std::thread::sleep(retry_after);
+        assert!(limiter.check("a").is_ok());
+
+        assert!(RateLimiter::<&str>::new(0, 60).is_none());
+    }
+
+    #[test]
+    fn test_rate_limiter_drops_least_recently_used() {
+        let limiter = RateLimiter::new(1, 60).unwrap();
+        for key in 0..MAX_BUCKETS {
+            assert!(limiter.check(key).is_ok());
+        }
+        assert!(limiter.check(0).is_err());
+
+        // Key 1 is the least recently used now, so it makes room for the new key
+        assert!(limiter.check(MAX_BUCKETS).is_ok());
+        assert_eq!(limiter.len(), MAX_BUCKETS);
+        assert!(limiter.check(0).is_err());
+        assert!(limiter.check(1).is_ok());
+
+        let limiter = RateLimiter::new(1, 6000).unwrap();
+        assert!(limiter.check("a").is_ok());
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(limiter.sweep(), 1);
+        assert_eq!(limiter.len(), 0);
+    }
+
+    #[test]
+    fn test_ipv6_clients_share_their_64() {
+        let config = ServerConfig {
+            rate_limit_client_burst: 1,
+            ..Default::default()
+        };
+        let limits = RateLimits::new(&config);
+        let request = |peer_addr: &str| {
+            TestRequest::default()
+                .peer_addr(peer_addr.parse().unwrap())
+                .to_http_request()
+        };
+
+        assert!(limits.check_client(&request("[2001:db8::1]:4000")).is_ok());
+        assert!(
+            limits
+                .check_client(&request("[2001:db8::ffff:1]:4000"))
+                .is_err()
+        );
+        assert!(
+            limits
+                .check_client(&request("[2001:db8:0:1::1]:4000"))
+                .is_ok()
+        );
+        assert!(limits.check_client(&request("192.0.2.1:4000")).is_ok());
+        assert!(limits.check_client(&request("192.0.2.2:4000")).is_ok());
+    }
+
+    #[test]
+    fn test_client_ip_behind_trusted_proxy() {
+        let config = ServerConfig {
+            rate_limit_trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        };
+        let limits = RateLimits::new(&config);
+
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.2:4000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "198.51.100.1, 203.0.113.7, 10.0.0.3"))
+            .to_http_request();
+        assert_eq!(limits.client_ip(&req), Some("203.0.113.7".parse().unwrap()));
+
+        // The header of an untrusted peer is ignored
+        let req = TestRequest::default()
+            .peer_addr("192.0.2.1:4000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "203.0.113.7"))
+            .to_http_request();
+        assert_eq!(limits.client_ip(&req), Some("192.0.2.1".parse().unwrap()));
+    }
+}
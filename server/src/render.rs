@@ -5,22 +5,34 @@ use serde::Serialize;
 
 use crate::wkd_result::WkdResult;
 
-use crate::config::{STATIC_CONTENT_CONFIG, StaticContentConfig};
+use crate::config::{self, StaticContentConfig};
 
 #[derive(Serialize)]
 struct RenderData<'a, T> {
-    config: StaticContentConfig,
+    config: &'a StaticContentConfig,
     data: &'a T,
+    /// Message shown above the results, e.g. when a lookup was rate limited
+    notice: Option<&'a str>,
 }
 
 pub fn render(
     hb: web::Data<Handlebars<'_>>,
     page_path: &str,
     data: &Option<WkdResult>,
+) -> HttpResponse {
+    render_with_notice(hb, page_path, data, None)
+}
+
+pub fn render_with_notice(
+    hb: web::Data<Handlebars<'_>>,
+    page_path: &str,
+    data: &Option<WkdResult>,
+    notice: Option<&str>,
 ) -> HttpResponse {
     let render_data = RenderData {
-        config: STATIC_CONTENT_CONFIG.clone(),
+        config: &config::get().static_content,
         data,
+        notice,
     };
 
     match hb.render(page_path, &render_data) {
@@ -1,16 +1,24 @@
 #[cfg(feature = "wkd-cache")]
 use crate::WebCache;
 
+use crate::rate_limit::{RateLimited, RateLimits};
 use crate::render;
 use crate::wkd_result;
 use actix_web::error::ErrorBadRequest;
-use actix_web::http::header::{CACHE_CONTROL, CONTENT_TYPE, HeaderValue};
-use actix_web::{HttpResponse, Responder, Result, get, web};
+use actix_web::http::StatusCode;
+use actix_web::http::header::{
+    AGE, CACHE_CONTROL, CONTENT_TYPE, HeaderName, HeaderValue, RETRY_AFTER,
+};
+use actix_web::{HttpRequest, HttpResponse, Responder, Result, get, web};
+use chrono::Utc;
 use handlebars::Handlebars;
-use render::render;
+use render::{render, render_with_notice};
 use reqwest::Client;
 use serde::Deserialize;
 use utoipa::OpenApi;
+use wkd::fetch::WkdMethodSelection;
+
+const X_CACHE: HeaderName = HeaderName::from_static("x-cache");
 
 #[derive(OpenApi)]
 #[openapi(
@@ -20,8 +28,10 @@ use utoipa::OpenApi;
         wkd_result::WkdUriResult,
         wkd_result::WkdMethodType,
         wkd_result::WkdError,
+        wkd_result::WkdSeverity,
         wkd_result::WkdKey,
-        wkd_result::WkdSuccess
+        wkd_result::WkdSuccess,
+        WkdMethodSelection
     )),
     info(
         title = "WKD Tester API",
@@ -40,6 +50,47 @@ pub struct ApiDoc;
 struct FormData {
     /// Email address to lookup in WKD
     email: Option<String>,
+    /// Methods to run, the other method is reported as skipped
+    method: Option<WkdMethodSelection>,
+    /// Look the address up again instead of answering from the cache
+    refresh: Option<bool>,
+}
+
+impl FormData {
+    /// Counts the lookup against the client's limits, and against its refresh limit when it bypasses the cache
+    fn check_rate_limits(
+        &self,
+        req: &HttpRequest,
+        rate_limits: &RateLimits,
+    ) -> Result<(), RateLimited> {
+        rate_limits.check_client(req)?;
+        if self.refresh == Some(true) {
+            rate_limits.check_refresh(req)?;
+        }
+        Ok(())
+    }
+}
+
+/// `X-Cache` and, for cached results, `Age` headers telling where `result` came from
+fn cache_headers(result: &wkd_result::WkdResult) -> Vec<(HeaderName, HeaderValue)> {
+    let Some(cached_at) = result.cached_at else {
+        return Vec::new();
+    };
+    if !result.cached {
+        return vec![(X_CACHE, HeaderValue::from_static("MISS"))];
+    }
+
+    let now = Utc::now();
+    // A stale result is served while it is looked up again in the background
+    let hit = match result.expires_at {
+        Some(expires_at) if expires_at <= now => "STALE",
+        _ => "HIT",
+    };
+    let age = (now - cached_at).num_seconds().max(0);
+    vec![
+        (X_CACHE, HeaderValue::from_static(hit)),
+        (AGE, HeaderValue::from(age)),
+    ]
 }
 
 #[utoipa::path(
@@ -47,16 +98,22 @@ struct FormData {
     path = "/api/lookup",
     params(FormData),
     responses(
-        (status = 200, description = "WKD lookup successful", body = wkd_result::WkdResult),
-        (status = 400, description = "Missing email parameter")
+        (status = 200, description = "WKD lookup successful", body = wkd_result::WkdResult, headers(
+            ("X-Cache" = String, description = "HIT when the result was answered from the cache, STALE when it was answered from the cache past its TTL while it is looked up again, MISS when it was looked up"),
+            ("Age" = u64, description = "Seconds since a cached result was looked up")
+        )),
+        (status = 400, description = "Missing email parameter or invalid method"),
+        (status = 429, description = "Too many lookups or refreshes from this client or for this domain, see the Retry-After header")
     ),
     tag = "WKD Lookup"
 )]
 #[get("/api/lookup")]
 #[cfg_attr(feature = "otel", tracing::instrument)]
 pub async fn api(
+    req: HttpRequest,
     form: web::Query<FormData>,
     rq: web::Data<Client>,
+    rate_limits: web::Data<RateLimits>,
     #[cfg(feature = "wkd-cache")] cache: web::Data<WebCache>,
 ) -> Result<impl Responder> {
     let email = match &form.email {
@@ -65,30 +122,53 @@ pub async fn api(
             return Err(ErrorBadRequest("Missing email parameter"));
         }
     };
+    let selection = form.method.unwrap_or_default();
+    form.check_rate_limits(&req, &rate_limits)?;
 
     #[cfg(feature = "wkd-cache")]
-    let (result, cache_set_future) =
-        wkd_result::get_wkd_cached(email, &cache, rq.as_ref().clone()).await;
+    let result = wkd_result::get_wkd_cached(
+        email,
+        selection,
+        &cache,
+        rq.as_ref().clone(),
+        &rate_limits,
+        form.refresh.unwrap_or_default(),
+    )
+    .await?;
 
     #[cfg(not(feature = "wkd-cache"))]
-    let result = wkd_result::get_wkd(email, rq.as_ref().clone()).await;
+    let result =
+        wkd_result::get_wkd_limited(email, rq.as_ref().clone(), selection, &rate_limits).await?;
 
-    let result = web::Json(result)
+    let headers = cache_headers(&result);
+    let mut result = web::Json(result)
         .customize()
         .insert_header((CACHE_CONTROL, "no-store"));
-
-    #[cfg(feature = "wkd-cache")]
-    wkd_result::unwrap_cache_future(cache_set_future).await;
+    for header in headers {
+        result = result.insert_header(header);
+    }
 
     Ok(result)
 }
 
+/// Hit, miss, eviction and expiration counters of the lookup cache and its current size
+#[cfg(feature = "wkd-cache")]
+#[get("/api/cache/stats")]
+#[cfg_attr(feature = "otel", tracing::instrument)]
+pub async fn cache_stats(cache: web::Data<WebCache>) -> impl Responder {
+    web::Json(cache.stats().await)
+        .customize()
+        .insert_header((CACHE_CONTROL, "no-store"))
+}
+
 #[get("/")]
 #[cfg_attr(feature = "otel", tracing::instrument)]
 pub async fn lookup(
+    req: HttpRequest,
     form: web::Query<FormData>,
     hb: web::Data<Handlebars<'_>>,
     rq: web::Data<Client>,
+    rate_limits: web::Data<RateLimits>,
     #[cfg(feature = "wkd-cache")] cache: web::Data<WebCache>,
 ) -> HttpResponse {
     let email = match &form.email {
@@ -105,15 +185,44 @@ pub async fn lookup(
             return response;
         }
     };
+    let selection = form.method.unwrap_or_default();
+    if let Err(limited) = form.check_rate_limits(&req, &rate_limits) {
+        return rate_limited_page(hb, &limited);
+    }
 
     #[cfg(feature = "wkd-cache")]
-    let (result, cache_set_future) =
-        wkd_result::get_wkd_cached(email, &cache, rq.as_ref().clone()).await;
+    let result = match wkd_result::get_wkd_cached(
+        email,
+        selection,
+        &cache,
+        rq.as_ref().clone(),
+        &rate_limits,
+        form.refresh.unwrap_or_default(),
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(limited) => return rate_limited_page(hb, &limited),
+    };
 
     #[cfg(not(feature = "wkd-cache"))]
-    let result = wkd_result::get_wkd(email, rq.as_ref().clone()).await;
+    let result = match wkd_result::get_wkd_limited(
+        email,
+        rq.as_ref().clone(),
+        selection,
+        &rate_limits,
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(limited) => return rate_limited_page(hb, &limited),
+    };
 
+    let headers = cache_headers(&result);
     let mut response = render(hb, "index", &Some(result));
+    for (name, value) in headers {
+        response.headers_mut().insert(name, value);
+    }
     response
         .headers_mut()
         .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
@@ -121,9 +230,17 @@ pub async fn lookup(
         .headers_mut()
         .insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
 
-    #[cfg(feature = "wkd-cache")]
-    wkd_result::unwrap_cache_future(cache_set_future).await;
+    response
+}
 
+/// The lookup page with the reason instead of results, answered with a `429`
+fn rate_limited_page(hb: web::Data<Handlebars<'_>>, limited: &RateLimited) -> HttpResponse {
+    let mut response = render_with_notice(hb, "index", &None, Some(&limited.to_string()));
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    let headers = response.headers_mut();
+    headers.insert(RETRY_AFTER, HeaderValue::from(limited.retry_after_secs()));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
     response
 }
 
@@ -1,11 +1,18 @@
 use actix_web::{
-    http::{StatusCode, header::CONTENT_TYPE},
+    http::{
+        StatusCode,
+        header::{CONTENT_TYPE, LOCATION, RETRY_AFTER},
+    },
     test,
 };
 
 use super::*;
 
-use crate::{config::STATIC_CONTENT_CONFIG, wkd_result::WkdResult};
+use crate::{
+    config::{ServerConfig, StaticContentConfig},
+    rate_limit::RateLimits,
+    wkd_result::WkdResult,
+};
 
 #[actix_web::test]
 async fn test_lookup_not_index() {
@@ -32,7 +39,8 @@ async fn test_lookup_no_email() {
 
     let app = App::new()
         .app_data(handlebars_ref.clone())
-        .app_data(setup_reqwest_client())
+        .app_data(setup_reqwest_client().unwrap())
+        .app_data(setup_rate_limits())
         .service(lookup)
         .wrap(setup_error_handlers_middleware())
         .wrap(setup_logging_middleware())
@@ -65,7 +73,8 @@ async fn test_lookup_email() {
     let handlebars_ref = setup_handlebars();
     let app = App::new()
         .app_data(handlebars_ref.clone())
-        .app_data(setup_reqwest_client())
+        .app_data(setup_reqwest_client().unwrap())
+        .app_data(setup_rate_limits())
         .service(lookup)
         .wrap(setup_error_handlers_middleware())
         .wrap(setup_logging_middleware())
@@ -114,7 +123,8 @@ async fn test_api_not_found() {
 #[actix_web::test]
 async fn test_api_no_email() {
     let app = App::new()
-        .app_data(setup_reqwest_client())
+        .app_data(setup_reqwest_client().unwrap())
+        .app_data(setup_rate_limits())
         .service(api)
         .wrap(setup_error_handlers_middleware())
         .wrap(setup_logging_middleware())
@@ -141,7 +151,8 @@ async fn test_api_no_email() {
 #[actix_web::test]
 async fn test_api_email() {
     let app = App::new()
-        .app_data(setup_reqwest_client())
+        .app_data(setup_reqwest_client().unwrap())
+        .app_data(setup_rate_limits())
         .service(api)
         .wrap(setup_error_handlers_middleware())
         .wrap(setup_logging_middleware())
@@ -169,6 +180,69 @@ async fn test_api_email() {
     assert!(serde_json::from_str::<WkdResult>(body_str).is_ok());
 }
 
+#[actix_web::test]
+async fn test_api_blocks_internal_addresses() {
+    let app = App::new()
+        .app_data(setup_reqwest_client().unwrap())
+        .app_data(setup_rate_limits())
+        .service(api);
+
+    #[cfg(feature = "wkd-cache")]
+    let app = app.app_data(setup_cache());
+
+    let app = test::init_service(app).await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/lookup?email=joe@localhost&method=direct")
+        .to_request();
+    let result: WkdResult = test::call_and_read_body_json(&app, req).await;
+    let direct = &result.methods[0];
+    assert!(
+        direct
+            .errors
+            .iter()
+            .any(|error| error.code == "wkd_fetch::address_blocked"),
+        "{direct:?}"
+    );
+}
+
+#[actix_web::test]
+async fn test_api_method() {
+    let app = App::new()
+        .app_data(setup_reqwest_client().unwrap())
+        .app_data(setup_rate_limits())
+        .service(api)
+        .wrap(setup_error_handlers_middleware())
+        .wrap(setup_logging_middleware())
+        .wrap(setup_compression_middleware())
+        .wrap(setup_default_headers_middleware());
+
+    #[cfg(feature = "wkd-cache")]
+    let app = {
+        let cache = setup_cache();
+        app.app_data(cache.clone())
+    };
+
+    let app = test::init_service(app).await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/lookup?email=joe@doesnotexist.invalid&method=advanced")
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = test::read_body(res).await;
+    let result = serde_json::from_slice::<WkdResult>(&body).unwrap();
+    assert!(result.methods[0].skipped);
+    assert!(result.methods[0].errors.is_empty());
+    assert!(!result.methods[1].skipped);
+
+    let req = test::TestRequest::get()
+        .uri("/api/lookup?email=joe@doesnotexist.invalid&method=neither")
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
 #[actix_web::test]
 async fn test_sitemap() {
     let handlebars_ref = setup_handlebars();
@@ -200,8 +274,225 @@ async fn test_sitemap() {
         body_str,
         format!(
             "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\r\n<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\"\r\n  xsi:schemaLocation=\"http://www.sitemaps.org/schemas/sitemap/0.9\r\n      http://www.sitemaps.org/schemas/sitemap/0.9/sitemap.xsd\">\r\n  <url>\r\n    <loc>{base_url}{rel_path}/</loc>\r\n    <lastmod>2025-11-25T10:01:01+00:00</lastmod>\r\n    <priority>1.00</priority>\r\n  </url>\r\n  <url>\r\n    <loc>{base_url}{rel_path}/api-docs/ui/</loc>\r\n    <lastmod>2025-11-25T10:01:01+00:00</lastmod>\r\n    <priority>0.80</priority>\r\n  </url>\r\n</urlset>",
-            base_url = STATIC_CONTENT_CONFIG.base_url,
-            rel_path = STATIC_CONTENT_CONFIG.root_path
+            base_url = StaticContentConfig::default().base_url,
+            rel_path = StaticContentConfig::default().root_path
         )
     );
 }
+
+#[actix_web::test]
+async fn test_services_under_root_path() {
+    let app = App::new()
+        .app_data(setup_handlebars())
+        .app_data(setup_reqwest_client().unwrap())
+        .app_data(setup_rate_limits())
+        .configure(setup_services("/wkd", ApiDoc::openapi()));
+
+    #[cfg(feature = "wkd-cache")]
+    let app = app.app_data(setup_cache());
+
+    let app = test::init_service(app).await;
+
+    let req = test::TestRequest::get().uri("/wkd").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(res.headers().get(LOCATION).unwrap(), "/wkd/");
+
+    for uri in ["/wkd/", "/wkd/.well-known/sitemap.xml", "/wkd/api-docs/ui/"] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK, "{uri}");
+    }
+    // The cache counters are only served when `cache_stats` is set
+    for uri in [
+        "/",
+        "/.well-known/sitemap.xml",
+        "/api-docs/openapi.json",
+        "/wkd/api/cache/stats",
+    ] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{uri}");
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/wkd/api-docs/openapi.json")
+        .to_request();
+    let openapi: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(openapi["servers"][0]["url"], "/wkd");
+    assert!(openapi["paths"]["/api/lookup"].is_object());
+}
+
+#[actix_web::test]
+async fn test_lookups_are_rate_limited() {
+    let rate_limits = RateLimits::new(&ServerConfig {
+        rate_limit_client_burst: 1,
+        rate_limit_client_per_minute: 1,
+        ..Default::default()
+    });
+    let app = App::new()
+        .app_data(setup_handlebars())
+        .app_data(setup_reqwest_client().unwrap())
+        .app_data(web::Data::new(rate_limits))
+        .service(api)
+        .service(lookup);
+
+    #[cfg(feature = "wkd-cache")]
+    let app = app.app_data(setup_cache());
+
+    let app = test::init_service(app).await;
+    let peer_addr = "192.0.2.1:4000".parse().unwrap();
+
+    let req = test::TestRequest::get()
+        .uri("/api/lookup?email=not-an-address")
+        .peer_addr(peer_addr)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get()
+        .uri("/api/lookup?email=not-an-address")
+        .peer_addr(peer_addr)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.headers().get(RETRY_AFTER).unwrap(), "60");
+
+    let req = test::TestRequest::get()
+        .uri("/?email=not-an-address")
+        .peer_addr(peer_addr)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.headers().get(CONTENT_TYPE).unwrap(), "text/html");
+    let body = test::read_body(res).await;
+    let body_str = std::str::from_utf8(&body).unwrap();
+    assert!(body_str.contains("Too many lookups from your address, try again in 60 seconds"));
+}
+
+#[cfg(feature = "wkd-cache")]
+#[actix_web::test]
+async fn test_cache_stats() {
+    let app = test::init_service(
+        App::new()
+            .app_data(setup_reqwest_client().unwrap())
+            .app_data(setup_rate_limits())
+            .app_data(setup_cache())
+            .service(api)
+            .service(routes::cache_stats),
+    )
+    .await;
+
+    for _ in 0..2 {
+        let req = test::TestRequest::get()
+            .uri("/api/lookup?email=not-an-address")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/api/cache/stats")
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.headers().get(CACHE_CONTROL).unwrap(), "no-store");
+    let stats: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(stats["hits"], 1);
+    assert_eq!(stats["misses"], 1);
+    assert_eq!(stats["entries"], 1);
+    assert!(stats["bytes"].as_u64().unwrap() > 0);
+}
+
+#[cfg(feature = "wkd-cache")]
+#[actix_web::test]
+async fn test_cache_refresh() {
+    let rate_limits = RateLimits::new(&ServerConfig {
+        rate_limit_refresh_burst: 1,
+        rate_limit_refresh_per_minute: 1,
+        ..Default::default()
+    });
+    let app = test::init_service(
+        App::new()
+            .app_data(setup_handlebars())
+            .app_data(setup_reqwest_client().unwrap())
+            .app_data(web::Data::new(rate_limits))
+            .app_data(setup_cache())
+            .service(api)
+            .service(lookup),
+    )
+    .await;
+    let peer_addr = "192.0.2.1:4000".parse().unwrap();
+    let get = |uri: &str| {
+        test::TestRequest::get()
+            .uri(uri)
+            .peer_addr(peer_addr)
+            .to_request()
+    };
+
+    let res = test::call_service(&app, get("/api/lookup?email=not-an-address")).await;
+    assert_eq!(res.headers().get("X-Cache").unwrap(), "MISS");
+    assert!(res.headers().get("Age").is_none());
+    let result: WkdResult = test::read_body_json(res).await;
+    assert!(!result.cached);
+    let cached_at = result.cached_at.unwrap();
+    // An invalid address finds no key, so it is cached for the failure TTL
+    assert_eq!(
+        result.expires_at.unwrap() - cached_at,
+        chrono::TimeDelta::milliseconds(2000)
+    );
+
+    let res = test::call_service(&app, get("/api/lookup?email=not-an-address")).await;
+    assert_eq!(res.headers().get("X-Cache").unwrap(), "HIT");
+    assert_eq!(res.headers().get("Age").unwrap(), "0");
+    let result: WkdResult = test::read_body_json(res).await;
+    assert!(result.cached);
+    assert_eq!(result.cached_at, Some(cached_at));
+
+    let res = test::call_service(&app, get("/?email=not-an-address")).await;
+    assert_eq!(res.headers().get("X-Cache").unwrap(), "HIT");
+    let body = test::read_body(res).await;
+    assert!(std::str::from_utf8(&body).unwrap().contains("Re-check now"));
+
+    let res = test::call_service(&app, get("/api/lookup?email=not-an-address&refresh=true")).await;
+    assert_eq!(res.headers().get("X-Cache").unwrap(), "MISS");
+    let result: WkdResult = test::read_body_json(res).await;
+    assert!(!result.cached);
+    assert!(result.cached_at.unwrap() > cached_at);
+
+    let res = test::call_service(&app, get("/api/lookup?email=not-an-address&refresh=true")).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[cfg(feature = "wkd-cache")]
+#[actix_web::test]
+async fn test_cache_shares_canonical_address() {
+    let app = test::init_service(
+        App::new()
+            .app_data(setup_reqwest_client().unwrap())
+            .app_data(setup_rate_limits())
+            .app_data(setup_cache())
+            .service(api),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/lookup?email=joe@LOCALHOST&method=direct")
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.headers().get("X-Cache").unwrap(), "MISS");
+
+    // The domain is case insensitive, the answer keeps the address as it was asked for
+    let req = test::TestRequest::get()
+        .uri("/api/lookup?email=joe@localhost&method=direct")
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.headers().get("X-Cache").unwrap(), "HIT");
+    let result: WkdResult = test::read_body_json(res).await;
+    assert_eq!(result.user_id, "joe@localhost");
+
+    let req = test::TestRequest::get()
+        .uri("/api/lookup?email=Joe@localhost&method=direct")
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.headers().get("X-Cache").unwrap(), "MISS");
+}
@@ -1,267 +1,282 @@
-use chrono::{DateTime, Utc};
-use openpgp_tester_lib::load;
+use crate::rate_limit::{RateLimited, RateLimits};
 use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use utoipa::ToSchema;
-
-#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
-pub enum WkdMethodType {
-    Direct,
-    Advanced,
-}
+use wkd::fetch::WkdMethodSelection;
+use wkd::uri::WkdUri;
+#[cfg(feature = "wkd-cache")]
+use {
+    crate::cache::CachePolicy,
+    actix_web::web,
+    chrono::{TimeDelta, Utc},
+    serde::{Deserialize, Serialize},
+    std::time::Duration,
+};
 
-#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
-pub struct WkdResult {
-    user_id: String,
-    methods: Vec<WkdUriResult>,
-}
+pub use wkd::result::{
+    WkdError, WkdKey, WkdMethodType, WkdResult, WkdSeverity, WkdSuccess, WkdUriResult, get_wkd,
+};
 
-#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
-pub struct WkdUriResult {
-    uri: String,
-    key: Option<WkdKey>,
-    errors: Vec<WkdError>,
-    method_type: WkdMethodType,
-    successes: Vec<WkdSuccess>,
-    #[schema(value_type = String)]
-    timestamp: DateTime<Utc>,
+/// Looks up `email` unless its domain is over the per-domain limit, invalid addresses aren't fetched nor counted
+pub async fn get_wkd_limited(
+    email: &str,
+    reqwest_client: Client,
+    selection: WkdMethodSelection,
+    rate_limits: &RateLimits,
+) -> Result<WkdResult, RateLimited> {
+    if let Ok(wkd_uri) = WkdUri::new(email) {
+        rate_limits.check_domain(&wkd_uri.domain_part)?;
+    }
+    Ok(get_wkd(email, reqwest_client, selection).await)
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema, Clone)]
-pub struct WkdSuccess(String);
-
-use wkd::fetch::WkdFetchSuccess;
-
-impl From<&str> for WkdSuccess {
-    fn from(value: &str) -> Self {
-        WkdSuccess(value.to_string())
-    }
+/// What a cached lookup is keyed on, addresses that resolve to the same WKD URIs share an entry
+#[cfg(feature = "wkd-cache")]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LookupKey {
+    /// The advanced method URI, it has the normalized domain, the user hash and the local part as typed
+    Wkd(String),
+    /// An address that doesn't parse, kept as typed
+    Invalid(String),
 }
 
-impl From<&WkdFetchSuccess> for WkdSuccess {
-    fn from(value: &WkdFetchSuccess) -> Self {
-        match value {
-            WkdFetchSuccess::AccessControlAllowOriginStar => {
-                WkdSuccess::from("Access-Control-Allow: *")
-            }
-            WkdFetchSuccess::ContentTypeOctetStream => {
-                WkdSuccess::from("Content-Type: application/octet-stream")
-            }
-            WkdFetchSuccess::HeadMethod => WkdSuccess::from("HTTP Head Method"),
-            WkdFetchSuccess::NoIndex => WkdSuccess::from("No Index found"),
-            WkdFetchSuccess::PolicyFile => WkdSuccess::from("Policy File Found"),
+#[cfg(feature = "wkd-cache")]
+impl LookupKey {
+    pub fn new(email: &str) -> Self {
+        match WkdUri::new(email) {
+            Ok(wkd_uri) => LookupKey::Wkd(wkd_uri.advanced_uri.to_string()),
+            Err(_) => LookupKey::Invalid(email.to_string()),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
-pub struct WkdError {
-    name: String,
-    message: String,
-}
-
-#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
-pub struct WkdKey {
-    fingerprint: String,
-    revocation_status: String,
-    expiry: String,
-    algorithm: String,
-    randomart: String,
+/// How a lookup went, each outcome is cached for its own TTL
+#[cfg(feature = "wkd-cache")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// A key was found and no method reported an error
+    Healthy,
+    /// A key was found but a method reported an error
+    Partial,
+    /// No method found a key
+    Failure,
 }
 
 #[cfg(feature = "wkd-cache")]
-pub async fn get_wkd_cached(
-    email: &String,
-    cache: &actix_web::web::Data<crate::WebCache>,
-    reqwest_client: Client,
-) -> (WkdResult, Option<impl Future<Output = ()>>) {
-    match cache.get(email).await {
-        Some(result) => (result, None),
-        None => {
-            let res = get_wkd(email, reqwest_client).await;
-            let cache_set_future = cache.set(email.to_string(), res.clone());
-            (res, Some(cache_set_future))
+impl Outcome {
+    pub fn of(result: &WkdResult) -> Self {
+        let mut selected = result.methods.iter().filter(|method| !method.skipped);
+        if !selected.clone().any(|method| method.key.is_some()) {
+            return Outcome::Failure;
+        }
+
+        if selected.any(|method| {
+            method
+                .errors
+                .iter()
+                .any(|error| error.severity == WkdSeverity::Error)
+        }) {
+            Outcome::Partial
+        } else {
+            Outcome::Healthy
         }
     }
 }
 
+/// TTLs of the lookup cache by [`Outcome`]
+#[cfg(feature = "wkd-cache")]
+#[derive(Debug, Clone, Copy)]
+pub struct OutcomeTtls {
+    pub healthy: Duration,
+    pub partial: Duration,
+    pub failure: Duration,
+}
+
 #[cfg(feature = "wkd-cache")]
-pub async fn unwrap_cache_future(cache_set_future: Option<impl Future<Output = ()>>) {
-    if let Some(fut) = cache_set_future {
-        fut.await
+impl CachePolicy<WkdResult> for OutcomeTtls {
+    fn ttl(&self, result: &WkdResult) -> Duration {
+        match Outcome::of(result) {
+            Outcome::Healthy => self.healthy,
+            Outcome::Partial => self.partial,
+            Outcome::Failure => self.failure,
+        }
     }
 }
 
-pub async fn get_wkd(user_id: &str, reqwest_client: Client) -> WkdResult {
-    let wkd_uri = match wkd::uri::WkdUri::new(user_id) {
-        Ok(wkd_uri) => wkd_uri,
-        Err(err) => {
-            return WkdResult {
-                user_id: user_id.to_string(),
-                methods: vec![
-                    WkdUriResult {
-                        uri: "".to_string(),
-                        key: None,
-                        errors: vec![WkdError::from(&err)],
-                        method_type: WkdMethodType::Direct,
-                        successes: vec![],
-                        timestamp: Utc::now(),
-                    },
-                    WkdUriResult {
-                        uri: "".to_string(),
-                        key: None,
-                        errors: vec![WkdError::from(&err)],
-                        method_type: WkdMethodType::Advanced,
-                        successes: vec![],
-                        timestamp: Utc::now(),
-                    },
-                ],
-            };
+/// Looks up `email` through the cache, `refresh` skips the cached result and replaces it
+#[cfg(feature = "wkd-cache")]
+pub async fn get_wkd_cached(
+    email: &str,
+    selection: WkdMethodSelection,
+    cache: &web::Data<crate::WebCache>,
+    reqwest_client: Client,
+    rate_limits: &web::Data<RateLimits>,
+    refresh: bool,
+) -> Result<WkdResult, RateLimited> {
+    let requested_at = Utc::now();
+    let key = (LookupKey::new(email), selection);
+    // Owns what it uses, a stale result is looked up again after the request is answered
+    let fetch = {
+        let email = email.to_string();
+        let rate_limits = rate_limits.clone();
+        let cache = cache.clone();
+        move || async move {
+            let mut result =
+                get_wkd_limited(&email, reqwest_client, selection, &rate_limits).await?;
+            let cached_at = Utc::now();
+            let ttl = TimeDelta::from_std(cache.ttl(&result)).unwrap_or(TimeDelta::MAX);
+            result.cached_at = Some(cached_at);
+            result.expires_at = cached_at.checked_add_signed(ttl);
+            Ok(result)
         }
     };
 
-    let wkd_fetch = wkd::fetch::WkdFetch::fetch(&wkd_uri, Some(reqwest_client.clone())).await;
-    let methods = vec![
-        WkdUriResult::from(
-            wkd_fetch.direct_method,
-            wkd_uri.direct_uri,
-            WkdMethodType::Direct,
-        ),
-        WkdUriResult::from(
-            wkd_fetch.advanced_method,
-            wkd_uri.advanced_uri,
-            WkdMethodType::Advanced,
-        ),
-    ];
-    WkdResult {
-        user_id: user_id.to_string(),
-        methods,
-    }
+    let cache = cache.clone().into_inner();
+    let mut result = if refresh {
+        cache.fetch(key, fetch).await?
+    } else {
+        cache.get_or_revalidate(key, fetch).await?
+    };
+    // The cached result may have been looked up under another spelling of the address
+    result.user_id = email.to_string();
+    // A result fetched for a request that came in earlier was answered from the cache
+    result.cached = result
+        .cached_at
+        .is_some_and(|cached_at| cached_at < requested_at);
+    Ok(result)
 }
 
-impl WkdUriResult {
-    pub fn from(
-        wkd_fetch: wkd::fetch::WkdFetchUriResult,
-        uri: impl std::string::ToString,
-        method_type: WkdMethodType,
-    ) -> Self {
-        let key: Option<WkdKey> = match wkd_fetch.data {
-            Some(data) => load::load_key(data).ok().map(WkdKey::from),
-            None => None,
-        };
-
-        WkdUriResult {
-            uri: uri.to_string(),
-            key,
-            errors: wkd_fetch.errors.iter().map(WkdError::from).collect(),
-            successes: wkd_fetch.successes.iter().map(WkdSuccess::from).collect(),
-            method_type,
-            timestamp: wkd_fetch.timestamp,
+#[cfg(feature = "wkd-cache")]
+impl crate::cache::ApproxSize for LookupKey {
+    fn approx_size(&self) -> usize {
+        match self {
+            LookupKey::Wkd(uri) => uri.capacity(),
+            LookupKey::Invalid(email) => email.capacity(),
         }
     }
 }
 
-impl WkdError {
-    pub fn from<Error: std::error::Error>(error: Error) -> Self {
-        WkdError {
-            name: format!("{error:?}"),
-            message: format!("{error}"),
-        }
+#[cfg(feature = "wkd-cache")]
+impl crate::cache::ApproxSize for WkdMethodSelection {
+    fn approx_size(&self) -> usize {
+        0
     }
 }
 
-impl WkdKey {
-    pub fn from(wkd_key: load::Key) -> Self {
-        WkdKey {
-            fingerprint: wkd_key.fingerprint,
-            revocation_status: wkd_key.revocation_status,
-            expiry: wkd_key.expiry,
-            algorithm: wkd_key.algorithm,
-            randomart: wkd_key.randomart,
-        }
+/// Counts the strings of a result, most of its size is the randomart and error messages
+#[cfg(feature = "wkd-cache")]
+impl crate::cache::ApproxSize for WkdResult {
+    fn approx_size(&self) -> usize {
+        use std::mem::size_of;
+
+        let method_size = |method: &WkdUriResult| {
+            let key_size = method.key.as_ref().map_or(0, |key| {
+                key.fingerprint.capacity()
+                    + key.revocation_status.capacity()
+                    + key.expiry.capacity()
+                    + key.algorithm.capacity()
+                    + key.randomart.capacity()
+            });
+            let errors_size: usize = method
+                .errors
+                .iter()
+                .map(|error| {
+                    size_of::<WkdError>()
+                        + error.name.capacity()
+                        + error.code.capacity()
+                        + error.message.capacity()
+                })
+                .sum();
+            let successes_size: usize = method
+                .successes
+                .iter()
+                .map(|success| size_of::<WkdSuccess>() + success.0.capacity())
+                .sum();
+            size_of::<WkdUriResult>()
+                + method.uri.capacity()
+                + key_size
+                + errors_size
+                + successes_size
+        };
+
+        self.user_id.capacity() + self.methods.iter().map(method_size).sum::<usize>()
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "wkd-cache"))]
 mod tests {
     use super::*;
     use chrono::Utc;
+    use wkd::result::WkdMethodType;
 
-    #[test]
-    fn test_wkd_key_from() {
-        let wkd_key = load::Key {
-            fingerprint: "fingerprint".to_string(),
-            revocation_status: "revocation_status".to_string(),
-            expiry: "expiry".to_string(),
-            algorithm: "algorithm".to_string(),
-            randomart: "randomart".to_string(),
-        };
-        let key = WkdKey::from(wkd_key);
-        assert_eq!(key.fingerprint, "fingerprint");
-        assert_eq!(key.revocation_status, "revocation_status");
-        assert_eq!(key.expiry, "expiry");
-        assert_eq!(key.algorithm, "algorithm");
-        assert_eq!(key.randomart, "randomart");
+    fn method(key: bool, severity: Option<WkdSeverity>, skipped: bool) -> WkdUriResult {
+        WkdUriResult {
+            uri: String::new(),
+            key: key.then(|| WkdKey {
+                fingerprint: String::new(),
+                revocation_status: String::new(),
+                expiry: String::new(),
+                algorithm: String::new(),
+                randomart: String::new(),
+            }),
+            errors: severity
+                .into_iter()
+                .map(|severity| WkdError {
+                    name: String::new(),
+                    code: String::new(),
+                    severity,
+                    message: String::new(),
+                })
+                .collect(),
+            method_type: WkdMethodType::Direct,
+            successes: Vec::new(),
+            timestamp: Utc::now(),
+            skipped,
+        }
     }
 
-    #[test]
-    fn test_wkd_error_from() {
-        let error = wkd::fetch::WkdFetchError::AccessControlAllowOriginNotStar;
-        let wkd_error = WkdError::from(error);
-        assert_eq!(wkd_error.name, "AccessControlAllowOriginNotStar");
-        assert_eq!(
-            wkd_error.message,
-            "Access-Control-Allow-Origin header is not set to '*'. This may cause issues with CORS"
-        );
+    fn result(methods: Vec<WkdUriResult>) -> WkdResult {
+        WkdResult {
+            user_id: String::new(),
+            methods,
+            cached: false,
+            cached_at: None,
+            expires_at: None,
+        }
     }
 
     #[test]
-    fn test_wkd_uri_result_from() {
-        let wkd_fetch = wkd::fetch::WkdFetchUriResult {
-            successes: vec![],
-            errors: vec![wkd::fetch::WkdFetchError::AccessControlAllowOriginNotStar],
-            data: None,
-            timestamp: Utc::now(),
-        };
-        let wkd_uri_result = WkdUriResult::from(wkd_fetch, "uri", WkdMethodType::Direct);
-        assert!(wkd_uri_result.key.is_none());
-        assert_eq!(wkd_uri_result.errors.len(), 1);
+    fn test_lookup_key_is_canonical() {
         assert_eq!(
-            wkd_uri_result.errors[0].name,
-            "AccessControlAllowOriginNotStar"
+            LookupKey::new("Joe.Doe@EXAMPLE.org"),
+            LookupKey::new("Joe.Doe@example.org")
+        );
+        assert_ne!(
+            LookupKey::new("joe.doe@example.org"),
+            LookupKey::new("Joe.Doe@example.org")
         );
         assert_eq!(
-            wkd_uri_result.errors[0].message,
-            "Access-Control-Allow-Origin header is not set to '*'. This may cause issues with CORS"
+            LookupKey::new("not an address"),
+            LookupKey::Invalid("not an address".to_string())
         );
     }
 
-    #[tokio::test]
-    async fn test_get_wkd() {
-        let wkd_result = get_wkd("test@chimbosonic.com", Client::new()).await;
-        println!("{:#?}", wkd_result);
-        assert_eq!(wkd_result.user_id, "test@chimbosonic.com");
-        assert_eq!(
-            wkd_result.methods.as_slice()[0].uri,
-            "https://chimbosonic.com/.well-known/openpgpkey/hu/iffe93qcsgp4c8ncbb378rxjo6cn9q6u?l=test"
-        );
-        assert_eq!(
-            wkd_result.methods.as_slice()[1].uri,
-            "https://openpgpkey.chimbosonic.com/.well-known/openpgpkey/chimbosonic.com/hu/iffe93qcsgp4c8ncbb378rxjo6cn9q6u?l=test"
-        );
-        assert!(wkd_result.methods.as_slice()[0].key.is_none());
-        assert!(wkd_result.methods.as_slice()[1].key.is_none());
-        assert_eq!(wkd_result.methods.as_slice()[1].errors.len(), 2);
-        assert_eq!(wkd_result.methods.as_slice()[0].errors.len(), 2);
-        println!("{:?}", wkd_result.methods.as_slice()[0].successes);
+    #[test]
+    fn test_outcome_of_result() {
+        let healthy = result(vec![
+            method(true, Some(WkdSeverity::Warning), false),
+            method(false, Some(WkdSeverity::Error), true),
+        ]);
+        assert_eq!(Outcome::of(&healthy), Outcome::Healthy);
 
-        assert_eq!(
-            wkd_result.methods.as_slice()[0].successes[0],
-            WkdSuccess::from("No Index found")
-        );
-        assert_eq!(
-            wkd_result.methods.as_slice()[1].successes[0],
-            WkdSuccess::from("No Index found")
-        );
+        let partial = result(vec![
+            method(true, None, false),
+            method(false, Some(WkdSeverity::Error), false),
+        ]);
+        assert_eq!(Outcome::of(&partial), Outcome::Partial);
+
+        let failure = result(vec![
+            method(false, Some(WkdSeverity::Error), false),
+            method(true, None, true),
+        ]);
+        assert_eq!(Outcome::of(&failure), Outcome::Failure);
     }
 }
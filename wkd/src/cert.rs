@@ -0,0 +1,630 @@
+use chrono::{DateTime, Utc};
+use pgp::composed::{
+    ArmorOptions, Deserializable, SignedPublicKey, SignedPublicSubKey, SignedSecretKey, SignedUser,
+};
+use pgp::packet::{Signature, SignatureType};
+use pgp::ser::Serialize;
+use pgp::types::{KeyDetails, Tag};
+
+use miette::Diagnostic;
+use thiserror::Error;
+
+const SIG_TYPES_CERTIFICATION: [SignatureType; 4] = [
+    SignatureType::CertGeneric,
+    SignatureType::CertPersona,
+    SignatureType::CertCasual,
+    SignatureType::CertPositive,
+];
+const SIG_TYPES_REVOCATION: [SignatureType; 3] = [
+    SignatureType::KeyRevocation,
+    SignatureType::SubkeyRevocation,
+    SignatureType::CertRevocation,
+];
+
+#[derive(Error, Diagnostic, Debug)]
+pub enum WkdCertError {
+    #[error("Data is not a valid OpenPGP certificate")]
+    #[diagnostic(code(wkd_cert::invalid_certificate))]
+    InvalidCertificate(#[source] pgp::errors::Error),
+
+    #[error("No OpenPGP certificate found")]
+    #[diagnostic(code(wkd_cert::no_certificate))]
+    NoCertificate,
+
+    #[error("Data contains secret key material")]
+    #[diagnostic(
+        code(wkd_cert::secret_key_material),
+        help("Only publish the public part of a key, for example with `gpg --export`")
+    )]
+    SecretKeyMaterial,
+}
+
+/// Differences found when comparing a served certificate against a local one
+#[derive(Error, Diagnostic, Debug, PartialEq)]
+pub enum WkdCertDiff {
+    #[error("Served certificate {served} does not match local certificate {local}")]
+    #[diagnostic(code(wkd_cert::fingerprint_mismatch))]
+    FingerprintMismatch { local: String, served: String },
+
+    #[error("User ID '{0}' is missing from the served certificate")]
+    #[diagnostic(code(wkd_cert::missing_user_id))]
+    MissingUserId(String),
+
+    #[error("User ID '{0}' is not served, it was most likely stripped for this address")]
+    #[diagnostic(severity(Advice), code(wkd_cert::stripped_user_id))]
+    StrippedUserId(String),
+
+    #[error("Served certificate has user ID '{0}' which is not in the local certificate")]
+    #[diagnostic(severity(Warning), code(wkd_cert::extra_user_id))]
+    ExtraUserId(String),
+
+    #[error("Subkey {0} is missing from the served certificate")]
+    #[diagnostic(code(wkd_cert::missing_subkey))]
+    MissingSubkey(String),
+
+    #[error("Served certificate has subkey {0} which is not in the local certificate")]
+    #[diagnostic(severity(Warning), code(wkd_cert::extra_subkey))]
+    ExtraSubkey(String),
+
+    #[error("Served self-signature on {0} is older than the local one")]
+    #[diagnostic(code(wkd_cert::stale_self_signature))]
+    StaleSelfSignature(String),
+
+    #[error("Served self-signature on {0} does not verify")]
+    #[diagnostic(
+        code(wkd_cert::invalid_self_signature),
+        help("Clients ignore components whose self-signatures don't verify")
+    )]
+    InvalidSelfSignature(String),
+
+    #[error("Revocation on {0} is missing from the served certificate")]
+    #[diagnostic(code(wkd_cert::missing_revocation))]
+    MissingRevocation(String),
+}
+
+/// A user ID of a certificate
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertUserId {
+    /// The user ID as UTF-8 (lossy)
+    pub value: String,
+}
+
+/// Transferable public key, see <https://www.rfc-editor.org/rfc/rfc9580#section-10.1>
+#[derive(Debug, Clone)]
+pub struct Cert {
+    key: SignedPublicKey,
+    /// Fingerprint of the primary key in upper case hex
+    pub fingerprint: String,
+    data: Vec<u8>,
+}
+
+/// Component of a certificate that self-signatures are bound to
+#[derive(Clone, Copy)]
+enum Component<'a> {
+    PrimaryKey,
+    UserId(&'a SignedUser),
+    Subkey(&'a SignedPublicSubKey),
+}
+
+/// Returns true if the data looks like an ASCII armored OpenPGP message
+pub fn is_armored(data: &[u8]) -> bool {
+    data.trim_ascii_start().starts_with(b"-----BEGIN PGP")
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02X}")).collect()
+}
+
+fn fingerprint(key: &impl KeyDetails) -> String {
+    to_hex(key.fingerprint().as_bytes())
+}
+
+impl CertUserId {
+    fn from(user: &SignedUser) -> Self {
+        CertUserId {
+            value: String::from_utf8_lossy(user.id.id()).to_string(),
+        }
+    }
+
+    /// Returns the e-mail address of a user ID in the form `Name <local@domain>` or `local@domain`
+    pub fn email(&self) -> Option<&str> {
+        let value = self.value.trim();
+        let email = match (value.rfind('<'), value.rfind('>')) {
+            (Some(start), Some(end)) if start < end => &value[start + 1..end],
+            _ => value,
+        };
+
+        if email.contains('@') && !email.contains(char::is_whitespace) {
+            Some(email)
+        } else {
+            None
+        }
+    }
+}
+
+impl Component<'_> {
+    /// Checks the signature over this component against the primary key
+    fn verifies(&self, key: &SignedPublicKey, signature: &Signature) -> bool {
+        let primary_key = &key.primary_key;
+        match self {
+            Component::PrimaryKey => signature.verify_key(primary_key),
+            Component::UserId(user) => {
+                signature.verify_certification(primary_key, Tag::UserId, &user.id)
+            }
+            Component::Subkey(subkey) => signature.verify_key_binding(primary_key, &subkey.key),
+        }
+        .is_ok()
+    }
+}
+
+/// Returns true if the signature has one of the given types, signatures of unknown types never do
+fn has_type(signature: &Signature, sig_types: &[SignatureType]) -> bool {
+    signature
+        .typ()
+        .is_some_and(|sig_type| sig_types.contains(&sig_type))
+}
+
+/// Returns true if a certification self-signature that verifies binds the user ID to `key`
+fn is_bound(key: &SignedPublicKey, user: &SignedUser) -> bool {
+    user.signatures.iter().any(|signature| {
+        has_type(signature, &SIG_TYPES_CERTIFICATION)
+            && Component::UserId(user).verifies(key, signature)
+    })
+}
+
+/// Returns true if the signature names the primary key of `key` as its issuer
+fn is_issued_by(signature: &Signature, key: &SignedPublicKey) -> bool {
+    let primary_key = &key.primary_key;
+    signature
+        .issuer_fingerprint()
+        .iter()
+        .any(|issuer| **issuer == primary_key.fingerprint())
+        || signature
+            .issuer_key_id()
+            .iter()
+            .any(|issuer| **issuer == primary_key.key_id())
+}
+
+/// Creation time of the newest self-signature of the given types that verifies
+fn newest_self_signature(
+    key: &SignedPublicKey,
+    component: Component,
+    signatures: &[Signature],
+    sig_types: &[SignatureType],
+) -> Option<DateTime<Utc>> {
+    signatures
+        .iter()
+        .filter(|signature| has_type(signature, sig_types))
+        .filter(|signature| component.verifies(key, signature))
+        .filter_map(|signature| signature.created().cloned())
+        .max()
+}
+
+/// Compares the signatures of one component, the primary key of both certificates is `key`
+fn diff_signatures(
+    key: &SignedPublicKey,
+    local: (Component, &[Signature]),
+    served: (Component, &[Signature]),
+    self_sig_types: &[SignatureType],
+    label: &str,
+    diffs: &mut Vec<WkdCertDiff>,
+) {
+    let (local_component, local) = local;
+    let (served_component, served) = served;
+
+    // A self-signature that doesn't verify was tampered with or bound to another component
+    if served
+        .iter()
+        .any(|signature| is_issued_by(signature, key) && !served_component.verifies(key, signature))
+    {
+        diffs.push(WkdCertDiff::InvalidSelfSignature(label.to_string()));
+    } else if let Some(local_created) =
+        newest_self_signature(key, local_component, local, self_sig_types)
+    {
+        let served_created = newest_self_signature(key, served_component, served, self_sig_types);
+        if served_created.is_none_or(|served_created| served_created < local_created) {
+            diffs.push(WkdCertDiff::StaleSelfSignature(label.to_string()));
+        }
+    }
+
+    if local
+        .iter()
+        .filter(|signature| has_type(signature, &SIG_TYPES_REVOCATION))
+        .filter(|signature| local_component.verifies(key, signature))
+        .any(|signature| !served.contains(signature))
+    {
+        diffs.push(WkdCertDiff::MissingRevocation(label.to_string()));
+    }
+}
+
+impl Cert {
+    fn new(key: SignedPublicKey) -> Result<Cert, WkdCertError> {
+        let data = key.to_bytes().map_err(WkdCertError::InvalidCertificate)?;
+        Ok(Cert {
+            fingerprint: fingerprint(&key.primary_key),
+            key,
+            data,
+        })
+    }
+
+    /// Parses every certificate found in armored or binary data.
+    ///
+    /// A certificate that can't be read, for example because of an unsupported key version, is
+    /// skipped so the rest of a keyring stays usable.
+    pub fn from_bytes(data: &[u8]) -> Result<Vec<Cert>, WkdCertError> {
+        if let Ok((mut secret_keys, _)) = SignedSecretKey::from_reader_many(data)
+            && secret_keys.any(|secret_key| secret_key.is_ok())
+        {
+            return Err(WkdCertError::SecretKeyMaterial);
+        }
+
+        let (keys, _) =
+            SignedPublicKey::from_reader_many(data).map_err(WkdCertError::InvalidCertificate)?;
+
+        let mut certs = Vec::new();
+        let mut first_error = None;
+        for key in keys {
+            match key
+                .map_err(WkdCertError::InvalidCertificate)
+                .and_then(Cert::new)
+            {
+                Ok(cert) => certs.push(cert),
+                Err(error) => {
+                    first_error.get_or_insert(error);
+                }
+            }
+        }
+
+        match first_error {
+            _ if !certs.is_empty() => Ok(certs),
+            Some(error) => Err(error),
+            None => Err(WkdCertError::NoCertificate),
+        }
+    }
+
+    /// Serialises the certificate to its binary transferable public key form
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    /// User IDs bound to the primary key by a self-signature that verifies, clients ignore the others
+    pub fn user_ids(&self) -> Vec<CertUserId> {
+        self.key
+            .details
+            .users
+            .iter()
+            .filter(|user| is_bound(&self.key, user))
+            .map(CertUserId::from)
+            .collect()
+    }
+
+    /// ASCII armors the certificate, see <https://www.rfc-editor.org/rfc/rfc9580#section-6.2>
+    pub fn to_armored(&self) -> Result<String, WkdCertError> {
+        self.key
+            .to_armored_string(ArmorOptions::default())
+            .map_err(WkdCertError::InvalidCertificate)
+    }
+
+    /// Returns the certificate as it should be published over WKD for `email`.
+    ///
+    /// Only the user IDs of [`Cert::user_ids`] with that e-mail address are kept and user attributes
+    /// are dropped, `None` if no user ID matches.
+    pub fn for_user_id(&self, email: &str) -> Option<Cert> {
+        let mut key = self.key.clone();
+        key.details.users.retain(|user| {
+            is_bound(&self.key, user)
+                && CertUserId::from(user)
+                    .email()
+                    .is_some_and(|user_id_email| user_id_email.eq_ignore_ascii_case(email))
+        });
+        key.details.user_attributes.clear();
+
+        if key.details.users.is_empty() {
+            return None;
+        }
+        Cert::new(key).ok()
+    }
+
+    /// Compares a certificate served over WKD for `user_id` against this local certificate.
+    ///
+    /// Only self-signatures that verify count, a served one that doesn't is reported.
+    pub fn diff(&self, served: &Cert, user_id: &str) -> Vec<WkdCertDiff> {
+        let mut diffs = Vec::new();
+
+        if self.fingerprint != served.fingerprint {
+            diffs.push(WkdCertDiff::FingerprintMismatch {
+                local: self.fingerprint.clone(),
+                served: served.fingerprint.clone(),
+            });
+            return diffs;
+        }
+
+        let (local, served) = (&self.key, &served.key);
+
+        let local_direct = [
+            local.details.direct_signatures.as_slice(),
+            local.details.revocation_signatures.as_slice(),
+        ]
+        .concat();
+        let served_direct = [
+            served.details.direct_signatures.as_slice(),
+            served.details.revocation_signatures.as_slice(),
+        ]
+        .concat();
+        diff_signatures(
+            local,
+            (Component::PrimaryKey, &local_direct),
+            (Component::PrimaryKey, &served_direct),
+            &[SignatureType::Key],
+            &format!("primary key {}", self.fingerprint),
+            &mut diffs,
+        );
+
+        for local_user in &local.details.users {
+            let local_uid = CertUserId::from(local_user);
+            match served
+                .details
+                .users
+                .iter()
+                .find(|served_user| served_user.id == local_user.id)
+            {
+                Some(served_user) => diff_signatures(
+                    local,
+                    (Component::UserId(local_user), &local_user.signatures),
+                    (Component::UserId(served_user), &served_user.signatures),
+                    &SIG_TYPES_CERTIFICATION,
+                    &format!("user ID '{}'", local_uid.value),
+                    &mut diffs,
+                ),
+                None if local_uid.value == user_id => {
+                    diffs.push(WkdCertDiff::MissingUserId(local_uid.value))
+                }
+                None => diffs.push(WkdCertDiff::StrippedUserId(local_uid.value)),
+            }
+        }
+
+        for served_user in &served.details.users {
+            if !local
+                .details
+                .users
+                .iter()
+                .any(|local_user| local_user.id == served_user.id)
+            {
+                diffs.push(WkdCertDiff::ExtraUserId(
+                    CertUserId::from(served_user).value,
+                ));
+            }
+        }
+
+        for local_subkey in &local.public_subkeys {
+            let subkey_fingerprint = fingerprint(&local_subkey.key);
+            match served
+                .public_subkeys
+                .iter()
+                .find(|subkey| fingerprint(&subkey.key) == subkey_fingerprint)
+            {
+                Some(served_subkey) => diff_signatures(
+                    local,
+                    (Component::Subkey(local_subkey), &local_subkey.signatures),
+                    (Component::Subkey(served_subkey), &served_subkey.signatures),
+                    &[SignatureType::SubkeyBinding],
+                    &format!("subkey {subkey_fingerprint}"),
+                    &mut diffs,
+                ),
+                None => diffs.push(WkdCertDiff::MissingSubkey(subkey_fingerprint)),
+            }
+        }
+
+        for served_subkey in &served.public_subkeys {
+            let subkey_fingerprint = fingerprint(&served_subkey.key);
+            if !local
+                .public_subkeys
+                .iter()
+                .any(|subkey| fingerprint(&subkey.key) == subkey_fingerprint)
+            {
+                diffs.push(WkdCertDiff::ExtraSubkey(subkey_fingerprint));
+            }
+        }
+
+        diffs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_KEY: &[u8] = include_bytes!("../../test_files/test_key");
+    const TEST_EXPIRED_KEY: &[u8] = include_bytes!("../../test_files/test_expired_key");
+    const TEST_KEY_FINGERPRINT: &str = "AC48BC1F029B6188D97E2D807C855DB4466DF0C6";
+    const TEST_SUBKEY_FINGERPRINT: &str = "0A01A35E50BABE0CEE5FDA94F6DDD189DA6F24B1";
+    const TEST_UID: &str = "Alexis Lowe <alexis.lowe@chimbosonic.com>";
+
+    fn test_cert() -> Cert {
+        Cert::from_bytes(TEST_KEY).unwrap().remove(0)
+    }
+
+    /// Copy of `cert` with `change` applied to its packets
+    fn modified(cert: &Cert, change: impl FnOnce(&mut SignedPublicKey)) -> Cert {
+        let mut key = cert.key.clone();
+        change(&mut key);
+        Cert::new(key).unwrap()
+    }
+
+    #[test]
+    fn cert_from_bytes_binary() {
+        let certs = Cert::from_bytes(TEST_KEY).unwrap();
+        assert_eq!(certs.len(), 1);
+
+        let cert = &certs[0];
+        assert_eq!(cert.fingerprint, TEST_KEY_FINGERPRINT);
+        let user_ids = cert.user_ids();
+        assert_eq!(user_ids.len(), 2);
+        assert_eq!(user_ids[0].value, TEST_UID);
+        assert_eq!(user_ids[0].email(), Some("alexis.lowe@chimbosonic.com"));
+        assert_eq!(cert.key.public_subkeys.len(), 1);
+        assert_eq!(
+            fingerprint(&cert.key.public_subkeys[0].key),
+            TEST_SUBKEY_FINGERPRINT
+        );
+    }
+
+    #[test]
+    fn cert_for_user_id_strips_other_user_ids() {
+        let cert = test_cert();
+        let stripped = cert.for_user_id("Alexis.Lowe@chimbosonic.com").unwrap();
+        assert_eq!(stripped.user_ids().len(), 1);
+        assert_eq!(stripped.user_ids()[0].value, TEST_UID);
+        assert_eq!(
+            stripped.key.public_subkeys.len(),
+            cert.key.public_subkeys.len()
+        );
+
+        let diffs = cert.diff(&stripped, TEST_UID);
+        assert!(
+            diffs
+                .iter()
+                .all(|diff| matches!(diff, WkdCertDiff::StrippedUserId(_))),
+            "{diffs:?}"
+        );
+
+        assert!(cert.for_user_id("joe@example.org").is_none());
+    }
+
+    #[test]
+    fn cert_for_user_id_skips_unbound_user_ids() {
+        let cert = modified(&test_cert(), |key| key.details.users[0].signatures.clear());
+        assert!(cert.for_user_id("alexis.lowe@chimbosonic.com").is_none());
+        assert!(cert.for_user_id("alexis.lowe@protonmail.com").is_some());
+    }
+
+    #[test]
+    fn cert_from_bytes_armored() {
+        let armored = test_cert().to_armored().unwrap();
+        assert!(is_armored(armored.as_bytes()));
+
+        let certs = Cert::from_bytes(armored.as_bytes()).unwrap();
+        assert_eq!(certs[0].fingerprint, TEST_KEY_FINGERPRINT);
+    }
+
+    #[test]
+    fn cert_from_bytes_keyring() {
+        let keyring = [TEST_KEY, TEST_EXPIRED_KEY].concat();
+        let certs = Cert::from_bytes(&keyring).unwrap();
+        assert_eq!(certs.len(), 2);
+        assert_eq!(
+            certs[1].fingerprint,
+            "A03351F7677A6D0B94F224A636CB3789EAC25E50"
+        );
+    }
+
+    #[test]
+    fn cert_from_bytes_skips_unreadable_keys() {
+        // A public key packet of an unknown version followed by a user ID
+        let unknown_version = [0xc6, 0x05, 0x09, 0x00, 0x00, 0x00, 0x00, 0xcd, 0x01, b'x'];
+        let certs = Cert::from_bytes(&[&unknown_version, TEST_KEY].concat()).unwrap();
+        assert_eq!(certs.len(), 1);
+        assert_eq!(certs[0].fingerprint, TEST_KEY_FINGERPRINT);
+
+        assert!(Cert::from_bytes(&unknown_version).is_err());
+    }
+
+    #[test]
+    fn cert_from_bytes_errors() {
+        assert!(Cert::from_bytes(b"not a key").is_err());
+        assert!(Cert::from_bytes(&TEST_KEY[..100]).is_err());
+        assert!(Cert::from_bytes(&[]).is_err());
+    }
+
+    #[test]
+    fn user_id_email() {
+        let mut user_id = test_cert().user_ids().remove(0);
+        user_id.value = "joe.doe@example.org".to_string();
+        assert_eq!(user_id.email(), Some("joe.doe@example.org"));
+        user_id.value = "Joe Doe".to_string();
+        assert_eq!(user_id.email(), None);
+    }
+
+    #[test]
+    fn diff_identical() {
+        let cert = test_cert();
+        assert!(cert.diff(&cert, TEST_UID).is_empty());
+    }
+
+    #[test]
+    fn diff_fingerprint_mismatch() {
+        let served = Cert::from_bytes(TEST_EXPIRED_KEY).unwrap().remove(0);
+        let diffs = test_cert().diff(&served, TEST_UID);
+        assert_eq!(diffs.len(), 1);
+        assert!(matches!(diffs[0], WkdCertDiff::FingerprintMismatch { .. }));
+    }
+
+    #[test]
+    fn diff_user_ids() {
+        let local = test_cert();
+        let served = modified(&local, |key| key.details.users.truncate(1));
+
+        let diffs = local.diff(&served, TEST_UID);
+        assert_eq!(
+            diffs,
+            vec![WkdCertDiff::StrippedUserId(
+                "Alexis Lowe <alexis.lowe@protonmail.com>".to_string()
+            )]
+        );
+
+        let diffs = local.diff(&served, "Alexis Lowe <alexis.lowe@protonmail.com>");
+        assert!(matches!(diffs[0], WkdCertDiff::MissingUserId(_)));
+
+        let diffs = served.diff(&local, TEST_UID);
+        assert!(matches!(diffs[0], WkdCertDiff::ExtraUserId(_)));
+    }
+
+    #[test]
+    fn diff_subkeys() {
+        let local = test_cert();
+        let served = modified(&local, |key| key.public_subkeys.clear());
+
+        let diffs = local.diff(&served, TEST_UID);
+        assert_eq!(
+            diffs,
+            vec![WkdCertDiff::MissingSubkey(
+                TEST_SUBKEY_FINGERPRINT.to_string()
+            )]
+        );
+
+        let diffs = served.diff(&local, TEST_UID);
+        assert!(matches!(diffs[0], WkdCertDiff::ExtraSubkey(_)));
+    }
+
+    #[test]
+    fn diff_stale_self_signature() {
+        let local = test_cert();
+        let served = modified(&local, |key| key.public_subkeys[0].signatures.clear());
+
+        let diffs = local.diff(&served, TEST_UID);
+        assert_eq!(
+            diffs,
+            vec![WkdCertDiff::StaleSelfSignature(format!(
+                "subkey {TEST_SUBKEY_FINGERPRINT}"
+            ))]
+        );
+    }
+
+    #[test]
+    fn diff_invalid_self_signature() {
+        let local = test_cert();
+        // The self-signature of the other user ID names the right issuer but doesn't cover this one
+        let served = modified(&local, |key| {
+            key.details.users[0].signatures = key.details.users[1].signatures.clone()
+        });
+        assert_eq!(served.user_ids().len(), 1);
+
+        let diffs = local.diff(&served, TEST_UID);
+        assert_eq!(
+            diffs,
+            vec![WkdCertDiff::InvalidSelfSignature(format!(
+                "user ID '{TEST_UID}'"
+            ))]
+        );
+    }
+}
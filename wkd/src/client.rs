@@ -0,0 +1,452 @@
+use ipnet::IpNet;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{Certificate, Client, Identity, NoProxy, Proxy, redirect};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use url::{Host, Url};
+
+use miette::Diagnostic;
+use thiserror::Error;
+
+#[derive(Error, Diagnostic, Debug)]
+pub enum WkdClientError {
+    #[error("Failed to build the HTTP client")]
+    #[diagnostic(code(wkd_client::build_failed))]
+    BuildFailed(#[source] reqwest::Error),
+
+    #[error("Failed to read {}", .0.display())]
+    #[diagnostic(code(wkd_client::read_failed))]
+    ReadFailed(PathBuf, #[source] std::io::Error),
+
+    #[error("No PEM certificate found in {}", .0.display())]
+    #[diagnostic(code(wkd_client::invalid_ca_certificate))]
+    InvalidCaCertificate(PathBuf),
+
+    #[error("Client certificate {} could not be loaded", .0.display())]
+    #[diagnostic(
+        code(wkd_client::invalid_client_identity),
+        help("The PEM data needs the certificate chain and an RSA, SEC1 or PKCS#8 private key")
+    )]
+    InvalidClientIdentity(PathBuf, #[source] reqwest::Error),
+
+    #[error("A client key needs a client certificate")]
+    #[diagnostic(code(wkd_client::client_key_without_certificate))]
+    ClientKeyWithoutCertificate,
+
+    #[error("Invalid proxy URL `{0}`")]
+    #[diagnostic(
+        code(wkd_client::invalid_proxy),
+        help("Use an http://, https://, socks5:// or socks5h:// URL")
+    )]
+    InvalidProxy(String),
+
+    #[error("A proxy can't be used while addresses are blocked")]
+    #[diagnostic(
+        code(wkd_client::proxy_with_blocked_networks),
+        help(
+            "The proxy resolves the WKD hosts, so the blocked networks can't be checked. Block them in the proxy and clear the blocked networks"
+        )
+    )]
+    ProxyWithBlockedNetworks,
+
+    #[error("System roots are disabled but no CA certificates are configured")]
+    #[diagnostic(
+        code(wkd_client::no_trust_anchors),
+        help("Add a CA certificate or keep the system roots enabled")
+    )]
+    NoTrustAnchors,
+}
+
+/// Networks a public instance shouldn't reach: loopback, unspecified, link-local (cloud metadata services
+/// included), RFC 1918, shared address space, ULA, multicast and broadcast
+pub const DEFAULT_BLOCKED_NETWORKS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "64:ff9b::/96",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Maximum number of redirects followed, the same as reqwest's default
+const MAX_REDIRECTS: usize = 10;
+
+/// Source of the request error when a lookup was refused because of [`WkdFetchConfig::blocked_networks`]
+#[derive(Error, Debug)]
+#[error("{0} is in a blocked network")]
+pub struct AddressBlocked(pub IpAddr);
+
+impl AddressBlocked {
+    /// Blocked address in the source chain of `error`
+    pub fn find(error: &(dyn std::error::Error + 'static)) -> Option<IpAddr> {
+        let mut source = Some(error);
+        while let Some(error) = source {
+            if let Some(blocked) = error.downcast_ref::<AddressBlocked>() {
+                return Some(blocked.0);
+            }
+            source = error.source();
+        }
+        None
+    }
+}
+
+fn is_blocked(blocked_networks: &[IpNet], ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    blocked_networks.iter().any(|network| network.contains(&ip))
+}
+
+/// Resolves with the system resolver and drops blocked addresses, so a name can't be rebound to one between
+/// the check and the connect
+struct GuardedResolver {
+    blocked_networks: Arc<[IpNet]>,
+}
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let blocked_networks = self.blocked_networks.clone();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            let allowed: Vec<SocketAddr> = addrs
+                .iter()
+                .filter(|addr| !is_blocked(&blocked_networks, addr.ip()))
+                .copied()
+                .collect();
+            match addrs.first() {
+                Some(addr) if allowed.is_empty() => Err(AddressBlocked(addr.ip()).into()),
+                _ => Ok(Box::new(allowed.into_iter()) as Addrs),
+            }
+        })
+    }
+}
+
+/// Redirects to IP literals skip the resolver, so they are checked here
+fn guarded_redirect_policy(blocked_networks: Arc<[IpNet]>) -> redirect::Policy {
+    redirect::Policy::custom(move |attempt| {
+        let ip = match attempt.url().host() {
+            Some(Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+            Some(Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+            _ => None,
+        };
+        match ip {
+            Some(ip) if is_blocked(&blocked_networks, ip) => attempt.error(AddressBlocked(ip)),
+            _ if attempt.previous().len() >= MAX_REDIRECTS => attempt.error("too many redirects"),
+            _ => attempt.follow(),
+        }
+    })
+}
+
+/// Settings of the HTTP client used by [`crate::fetch::WkdFetch`], unset fields keep the reqwest defaults
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WkdFetchConfig {
+    /// Timeout of every request, from connecting until the body was read
+    pub timeout: Option<Duration>,
+    /// Timeout of the connect phase only
+    pub connect_timeout: Option<Duration>,
+    /// `User-Agent` header sent with every request
+    pub user_agent: Option<String>,
+    /// PEM bundles with extra trust anchors, for WKD hosts behind a private CA
+    pub ca_certs: Vec<PathBuf>,
+    /// Only trust `ca_certs`, not the system roots
+    pub disable_system_roots: bool,
+    /// PEM file with the client certificate chain for mTLS, and its private key unless `client_key` is set
+    pub client_cert: Option<PathBuf>,
+    /// PEM file with the private key of `client_cert`
+    pub client_key: Option<PathBuf>,
+    /// Proxy for all requests, takes `http://`, `https://`, `socks5://` and `socks5h://` URLs
+    pub proxy: Option<String>,
+    /// Proxy for `http://` requests only, wins over `proxy`
+    pub http_proxy: Option<String>,
+    /// Proxy for `https://` requests only, wins over `proxy`
+    pub https_proxy: Option<String>,
+    /// Hosts, domains (`.example.org`) and IP ranges reached without the proxies
+    pub no_proxy: Vec<String>,
+    /// Addresses lookups may not connect to, checked after DNS resolution and on every redirect.
+    /// Empty disables the guard, see [`DEFAULT_BLOCKED_NETWORKS`]. A proxy resolves the hosts itself, so the guard
+    /// can't be combined with the proxy settings and ignores the proxies of the environment.
+    pub blocked_networks: Vec<IpNet>,
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, WkdClientError> {
+    std::fs::read(path).map_err(|err| WkdClientError::ReadFailed(path.to_path_buf(), err))
+}
+
+/// reqwest only rejects unsupported proxy schemes once a request is sent
+fn proxy_url(url: &str) -> Option<Url> {
+    Url::parse(url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h"))
+}
+
+impl WkdFetchConfig {
+    /// Builds the reqwest client for these settings
+    pub fn client(&self) -> Result<Client, WkdClientError> {
+        let mut builder = Client::builder();
+
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+
+        let mut ca_certs = Vec::new();
+        for path in &self.ca_certs {
+            match Certificate::from_pem_bundle(&read(path)?) {
+                Ok(certs) if !certs.is_empty() => ca_certs.extend(certs),
+                _ => return Err(WkdClientError::InvalidCaCertificate(path.clone())),
+            }
+        }
+        builder = match self.disable_system_roots {
+            true if ca_certs.is_empty() => return Err(WkdClientError::NoTrustAnchors),
+            true => builder.tls_certs_only(ca_certs),
+            false => builder.tls_certs_merge(ca_certs),
+        };
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert_path), key_path) => {
+                let mut pem = read(cert_path)?;
+                if let Some(key_path) = key_path {
+                    pem.push(b'\n');
+                    pem.extend(read(key_path)?);
+                }
+                let identity = Identity::from_pem(&pem)
+                    .map_err(|err| WkdClientError::InvalidClientIdentity(cert_path.clone(), err))?;
+                builder = builder.identity(identity);
+            }
+            (None, Some(_)) => return Err(WkdClientError::ClientKeyWithoutCertificate),
+            (None, None) => {}
+        }
+
+        let has_proxy =
+            self.proxy.is_some() || self.http_proxy.is_some() || self.https_proxy.is_some();
+        if !self.blocked_networks.is_empty() {
+            if has_proxy {
+                return Err(WkdClientError::ProxyWithBlockedNetworks);
+            }
+            let blocked_networks: Arc<[IpNet]> = self.blocked_networks.clone().into();
+            builder = builder
+                .dns_resolver(GuardedResolver {
+                    blocked_networks: blocked_networks.clone(),
+                })
+                .redirect(guarded_redirect_policy(blocked_networks))
+                // A proxy from `HTTP_PROXY` or `HTTPS_PROXY` would resolve the hosts past the guard
+                .no_proxy();
+        }
+
+        // Any explicit proxy replaces the ones reqwest reads from the environment
+        let no_proxy = NoProxy::from_string(&self.no_proxy.join(","));
+        let proxies = [
+            (
+                &self.http_proxy,
+                Proxy::http as fn(Url) -> reqwest::Result<Proxy>,
+            ),
+            (&self.https_proxy, Proxy::https),
+            (&self.proxy, Proxy::all),
+        ];
+        for (url, proxy) in proxies {
+            if let Some(url) = url {
+                let proxy = proxy_url(url)
+                    .and_then(|parsed| proxy(parsed).ok())
+                    .ok_or_else(|| WkdClientError::InvalidProxy(url.clone()))?;
+                builder = builder.proxy(proxy.no_proxy(no_proxy.clone()));
+            }
+        }
+
+        builder.build().map_err(WkdClientError::BuildFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn client_sends_user_agent() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/")
+            .match_header("user-agent", "wkd-tester-test")
+            .create_async()
+            .await;
+
+        let config = WkdFetchConfig {
+            timeout: Some(Duration::from_secs(5)),
+            user_agent: Some("wkd-tester-test".to_string()),
+            ..Default::default()
+        };
+        config
+            .client()
+            .unwrap()
+            .get(server.url())
+            .send()
+            .await
+            .unwrap();
+
+        mock.assert_async().await;
+    }
+
+    #[test]
+    fn client_rejects_invalid_tls_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let not_pem = dir.path().join("not-pem");
+        std::fs::write(&not_pem, "not a certificate").unwrap();
+
+        let client = |config: WkdFetchConfig| config.client().map(|_| ()).unwrap_err();
+
+        assert!(matches!(
+            client(WkdFetchConfig {
+                ca_certs: vec![not_pem.clone()],
+                ..Default::default()
+            }),
+            WkdClientError::InvalidCaCertificate(_)
+        ));
+        assert!(matches!(
+            client(WkdFetchConfig {
+                ca_certs: vec![dir.path().join("missing")],
+                ..Default::default()
+            }),
+            WkdClientError::ReadFailed(..)
+        ));
+        assert!(matches!(
+            client(WkdFetchConfig {
+                disable_system_roots: true,
+                ..Default::default()
+            }),
+            WkdClientError::NoTrustAnchors
+        ));
+        assert!(matches!(
+            client(WkdFetchConfig {
+                client_cert: Some(not_pem.clone()),
+                ..Default::default()
+            }),
+            WkdClientError::InvalidClientIdentity(..)
+        ));
+        assert!(matches!(
+            client(WkdFetchConfig {
+                client_key: Some(not_pem),
+                ..Default::default()
+            }),
+            WkdClientError::ClientKeyWithoutCertificate
+        ));
+    }
+
+    #[tokio::test]
+    async fn client_uses_proxy() {
+        let mut proxy = mockito::Server::new_async().await;
+        let mock = proxy
+            .mock("GET", "/.well-known/openpgpkey/policy")
+            .match_header("host", "openpgpkey.example.org")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let config = WkdFetchConfig {
+            http_proxy: Some(proxy.url()),
+            ..Default::default()
+        };
+        let client = config.client().unwrap();
+        let response = client
+            .get("http://openpgpkey.example.org/.well-known/openpgpkey/policy")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        mock.assert_async().await;
+
+        let config = WkdFetchConfig {
+            proxy: Some("ftp://proxy.example.org".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.client(),
+            Err(WkdClientError::InvalidProxy(..))
+        ));
+    }
+
+    #[test]
+    fn client_rejects_proxy_with_blocked_networks() {
+        let blocked_networks = vec!["10.0.0.0/8".parse().unwrap()];
+        for config in [
+            WkdFetchConfig {
+                proxy: Some("socks5h://proxy.example.org:1080".to_string()),
+                ..Default::default()
+            },
+            WkdFetchConfig {
+                http_proxy: Some("http://proxy.example.org:3128".to_string()),
+                ..Default::default()
+            },
+            WkdFetchConfig {
+                https_proxy: Some("http://proxy.example.org:3128".to_string()),
+                ..Default::default()
+            },
+        ] {
+            assert!(config.client().is_ok());
+            let config = WkdFetchConfig {
+                blocked_networks: blocked_networks.clone(),
+                ..config
+            };
+            assert!(matches!(
+                config.client(),
+                Err(WkdClientError::ProxyWithBlockedNetworks)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn client_blocks_internal_addresses() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/")
+            .with_status(302)
+            .with_header("location", "http://10.0.0.1/")
+            .create_async()
+            .await;
+
+        let blocked_networks = DEFAULT_BLOCKED_NETWORKS
+            .iter()
+            .map(|network| network.parse().unwrap())
+            .collect();
+        let client = WkdFetchConfig {
+            blocked_networks,
+            ..Default::default()
+        }
+        .client()
+        .unwrap();
+        let port = server.socket_address().port();
+        let err = client
+            .get(format!("http://localhost:{port}/"))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(AddressBlocked::find(&err).unwrap().is_loopback());
+
+        // The loopback server itself is allowed, the redirect target isn't
+        let client = WkdFetchConfig {
+            blocked_networks: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        }
+        .client()
+        .unwrap();
+        let err = client.get(server.url()).send().await.unwrap_err();
+        assert_eq!(
+            AddressBlocked::find(&err),
+            Some("10.0.0.1".parse().unwrap())
+        );
+    }
+}
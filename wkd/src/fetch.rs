@@ -1,7 +1,9 @@
+use super::client::AddressBlocked;
 use super::uri::{Uri, WkdUri};
 use bytes::Bytes;
 use chrono::{DateTime, Utc};
 use reqwest::{Client, Url};
+use std::time::{Duration, Instant};
 
 use miette::Diagnostic;
 use thiserror::Error;
@@ -14,51 +16,61 @@ pub static MAX_KEY_SIZE: usize = 2 + 1024 * 1024; // 2 MB
 #[derive(Error, Diagnostic, Debug)]
 pub enum WkdFetchError {
     #[error("WKD URI provided is not a valid URL")]
-    #[diagnostic(code(wkd_fetch))]
+    #[diagnostic(code(wkd_fetch::uri_not_valid_url))]
     WkdUriNotValidUrl(#[from] url::ParseError),
 
     #[error("Failed to fetch given URL")]
-    #[diagnostic(code(wkd_fetch))]
+    #[diagnostic(code(wkd_fetch::failed_to_fetch_url))]
     FailedToFetchUrl(#[from] reqwest::Error),
 
+    #[error("Refused to connect to {0}, the address is in a blocked network")]
+    #[diagnostic(
+        code(wkd_fetch::address_blocked),
+        help("This server doesn't look up domains that resolve or redirect to internal addresses")
+    )]
+    AddressBlocked(std::net::IpAddr, WkdCheck),
+
     #[error(
         "Content-Type header is not set to 'application/octet-stream'. This may cause issues with parsing"
     )]
-    #[diagnostic(severity(Warning), code(wkd_fetch))]
+    #[diagnostic(severity(Warning), code(wkd_fetch::content_type_not_octet_stream))]
     ContentTypeNotOctetStream,
 
     #[error(
         "Access-Control-Allow-Origin header is not set to '*'. This may cause issues with CORS"
     )]
-    #[diagnostic(severity(Warning), code(wkd_fetch))]
+    #[diagnostic(
+        severity(Warning),
+        code(wkd_fetch::access_control_allow_origin_not_star)
+    )]
     AccessControlAllowOriginNotStar,
 
     #[error("Error whilst extracting body from response")]
-    #[diagnostic(code(wkd_fetch))]
+    #[diagnostic(code(wkd_fetch::no_data_returned))]
     NoDataReturned,
 
     #[error("Status code is not 200")]
-    #[diagnostic(code(wkd_fetch))]
+    #[diagnostic(code(wkd_fetch::status_not_200))]
     StatusNot200(u16),
 
     #[error("Failed existence cheack with HEAD Method")]
-    #[diagnostic(code(wkd_fetch))]
+    #[diagnostic(code(wkd_fetch::failed_head_method))]
     FailedHeadMethod,
 
     #[error("Well-Known Path shouldn't have a index")]
-    #[diagnostic(severity(Warning), code(wkd_fetch))]
+    #[diagnostic(severity(Warning), code(wkd_fetch::path_should_not_have_index))]
     WkdPathShouldNotHaveIndex,
 
     #[error("Policy file not found")]
-    #[diagnostic(severity(Warning), code(wkd_fetch))]
+    #[diagnostic(severity(Warning), code(wkd_fetch::policy_file_not_found))]
     WkdPolicyFileNotFound,
 
     #[error("Could not generate policy file path from URL")]
-    #[diagnostic(severity(Warning), code(wkd_fetch))]
+    #[diagnostic(severity(Warning), code(wkd_fetch::policy_file_path_generation_failed))]
     WkdPolicyFilePathGenerationFailed,
 
     #[error("Response size exceeded 2 MB")]
-    #[diagnostic(severity(Error), code(wkd_fetch))]
+    #[diagnostic(severity(Error), code(wkd_fetch::response_size_exceeded))]
     ResponseSizeExceeded,
 }
 
@@ -71,6 +83,97 @@ pub enum WkdFetchSuccess {
     AccessControlAllowOriginStar,
 }
 
+/// Check performed against a WKD URI, each success and error belongs to one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WkdCheck {
+    HeadMethod,
+    NoIndex,
+    PolicyFile,
+    Fetch,
+    ContentType,
+    AccessControlAllowOrigin,
+}
+
+impl WkdCheck {
+    /// Every check in the order they are run
+    pub const ALL: [WkdCheck; 6] = [
+        WkdCheck::HeadMethod,
+        WkdCheck::NoIndex,
+        WkdCheck::PolicyFile,
+        WkdCheck::Fetch,
+        WkdCheck::ContentType,
+        WkdCheck::AccessControlAllowOrigin,
+    ];
+
+    /// Short stable name, for example `content-type`
+    pub fn name(&self) -> &'static str {
+        match self {
+            WkdCheck::HeadMethod => "head",
+            WkdCheck::NoIndex => "index",
+            WkdCheck::PolicyFile => "policy",
+            WkdCheck::Fetch => "fetch",
+            WkdCheck::ContentType => "content-type",
+            WkdCheck::AccessControlAllowOrigin => "cors",
+        }
+    }
+}
+
+impl WkdFetchError {
+    pub fn check(&self) -> WkdCheck {
+        match self {
+            WkdFetchError::WkdUriNotValidUrl(_)
+            | WkdFetchError::FailedToFetchUrl(_)
+            | WkdFetchError::NoDataReturned
+            | WkdFetchError::StatusNot200(_)
+            | WkdFetchError::ResponseSizeExceeded => WkdCheck::Fetch,
+            WkdFetchError::AddressBlocked(_, check) => *check,
+            WkdFetchError::ContentTypeNotOctetStream => WkdCheck::ContentType,
+            WkdFetchError::AccessControlAllowOriginNotStar => WkdCheck::AccessControlAllowOrigin,
+            WkdFetchError::FailedHeadMethod => WkdCheck::HeadMethod,
+            WkdFetchError::WkdPathShouldNotHaveIndex => WkdCheck::NoIndex,
+            WkdFetchError::WkdPolicyFileNotFound
+            | WkdFetchError::WkdPolicyFilePathGenerationFailed => WkdCheck::PolicyFile,
+        }
+    }
+}
+
+impl WkdFetchSuccess {
+    pub fn check(&self) -> WkdCheck {
+        match self {
+            WkdFetchSuccess::HeadMethod => WkdCheck::HeadMethod,
+            WkdFetchSuccess::NoIndex => WkdCheck::NoIndex,
+            WkdFetchSuccess::PolicyFile => WkdCheck::PolicyFile,
+            WkdFetchSuccess::ContentTypeOctetStream => WkdCheck::ContentType,
+            WkdFetchSuccess::AccessControlAllowOriginStar => WkdCheck::AccessControlAllowOrigin,
+        }
+    }
+}
+
+/// Which methods [`WkdFetch::fetch`] runs, the other one is marked as skipped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "result", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "result", serde(rename_all = "lowercase"))]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub enum WkdMethodSelection {
+    Direct,
+    Advanced,
+    #[default]
+    Both,
+}
+
+impl WkdMethodSelection {
+    pub fn direct(&self) -> bool {
+        matches!(self, WkdMethodSelection::Direct | WkdMethodSelection::Both)
+    }
+
+    pub fn advanced(&self) -> bool {
+        matches!(
+            self,
+            WkdMethodSelection::Advanced | WkdMethodSelection::Both
+        )
+    }
+}
+
 pub struct WkdFetch {
     pub direct_method: WkdFetchUriResult,
     pub advanced_method: WkdFetchUriResult,
@@ -78,13 +181,25 @@ pub struct WkdFetch {
 
 impl WkdFetch {
     #[cfg_attr(feature = "tracing", tracing::instrument)]
-    pub async fn fetch(wkd_uri: &WkdUri, reqwest_client: Option<Client>) -> WkdFetch {
+    pub async fn fetch(
+        wkd_uri: &WkdUri,
+        reqwest_client: Option<Client>,
+        selection: WkdMethodSelection,
+    ) -> WkdFetch {
         let reqwest_client = reqwest_client.unwrap_or_default();
 
-        let direct_method = fetch_uri(&wkd_uri.direct_uri, reqwest_client.clone()).await;
+        let direct_method = if selection.direct() {
+            fetch_uri(&wkd_uri.direct_uri, reqwest_client.clone()).await
+        } else {
+            WkdFetchUriResult::skipped()
+        };
         #[cfg(feature = "tracing")]
         event!(Level::TRACE, "Fetched Direct URI: {:?}", direct_method);
-        let advanced_method = fetch_uri(&wkd_uri.advanced_uri, reqwest_client).await;
+        let advanced_method = if selection.advanced() {
+            fetch_uri(&wkd_uri.advanced_uri, reqwest_client).await
+        } else {
+            WkdFetchUriResult::skipped()
+        };
 
         #[cfg(feature = "tracing")]
         event!(Level::TRACE, "Fetched Direct URI: {:?}", advanced_method);
@@ -101,6 +216,23 @@ pub struct WkdFetchUriResult {
     pub successes: Vec<WkdFetchSuccess>,
     pub data: Option<Bytes>,
     pub timestamp: DateTime<Utc>,
+    /// Time until the response headers of the key request arrived, `None` when it wasn't answered
+    pub latency: Option<Duration>,
+    /// The method wasn't selected, no requests were made
+    pub skipped: bool,
+}
+
+impl WkdFetchUriResult {
+    pub fn skipped() -> Self {
+        WkdFetchUriResult {
+            errors: Vec::new(),
+            successes: Vec::new(),
+            data: None,
+            timestamp: Utc::now(),
+            latency: None,
+            skipped: true,
+        }
+    }
 }
 
 fn trim_uri(url: &str) -> &str {
@@ -116,17 +248,23 @@ fn get_policy_url(url: &str) -> Option<String> {
         .map(|pos| format!("{}policy", &url[..=pos]))
 }
 
+/// The error of a request the client refused because its address is blocked, for the check that sent it
+fn address_blocked(err: &reqwest::Error, check: WkdCheck) -> Option<WkdFetchError> {
+    AddressBlocked::find(err).map(|ip| WkdFetchError::AddressBlocked(ip, check))
+}
+
 async fn check_head_method(
     client: &reqwest::Client,
     url: &str,
 ) -> Result<WkdFetchSuccess, WkdFetchError> {
-    if let Ok(response) = client.head(url).send().await
-        && response.status().as_u16() == 200
-    {
-        return Ok(WkdFetchSuccess::HeadMethod);
+    match client.head(url).send().await {
+        Ok(response) if response.status().as_u16() == 200 => Ok(WkdFetchSuccess::HeadMethod),
+        Err(err) => {
+            Err(address_blocked(&err, WkdCheck::HeadMethod)
+                .unwrap_or(WkdFetchError::FailedHeadMethod))
+        }
+        Ok(_) => Err(WkdFetchError::FailedHeadMethod),
     }
-
-    Err(WkdFetchError::FailedHeadMethod)
 }
 
 async fn check_for_indexing(
@@ -134,13 +272,16 @@ async fn check_for_indexing(
     url: &str,
 ) -> Result<WkdFetchSuccess, WkdFetchError> {
     let index_url = trim_uri(url);
-    if let Ok(response) = client.get(index_url).send().await
-        && response.status().as_u16() == 200
-    {
-        return Err(WkdFetchError::WkdPathShouldNotHaveIndex);
+    match client.get(index_url).send().await {
+        Ok(response) if response.status().as_u16() == 200 => {
+            Err(WkdFetchError::WkdPathShouldNotHaveIndex)
+        }
+        Err(err) => match address_blocked(&err, WkdCheck::NoIndex) {
+            Some(blocked) => Err(blocked),
+            None => Ok(WkdFetchSuccess::NoIndex),
+        },
+        Ok(_) => Ok(WkdFetchSuccess::NoIndex),
     }
-
-    Ok(WkdFetchSuccess::NoIndex)
 }
 
 async fn check_policy_file(
@@ -152,13 +293,12 @@ async fn check_policy_file(
         None => return Err(WkdFetchError::WkdPolicyFilePathGenerationFailed),
     };
 
-    if let Ok(response) = client.get(&policy_url).send().await
-        && response.status().as_u16() == 200
-    {
-        return Ok(WkdFetchSuccess::PolicyFile);
+    match client.get(&policy_url).send().await {
+        Ok(response) if response.status().as_u16() == 200 => Ok(WkdFetchSuccess::PolicyFile),
+        Err(err) => Err(address_blocked(&err, WkdCheck::PolicyFile)
+            .unwrap_or(WkdFetchError::WkdPolicyFileNotFound)),
+        Ok(_) => Err(WkdFetchError::WkdPolicyFileNotFound),
     }
-
-    Err(WkdFetchError::WkdPolicyFileNotFound)
 }
 
 async fn fetch_uri<T>(
@@ -170,6 +310,8 @@ async fn fetch_uri<T>(
         successes: Vec::new(),
         data: None,
         timestamp: Utc::now(),
+        latency: None,
+        skipped: false,
     };
 
     let url = match Url::parse(&uri.to_string()) {
@@ -195,10 +337,17 @@ async fn fetch_uri<T>(
         Err(error) => result.errors.push(error),
     }
 
+    let started = Instant::now();
     let response = match reqwest_client.get(url).send().await {
-        Ok(response) => response,
+        Ok(response) => {
+            result.latency = Some(started.elapsed());
+            response
+        }
         Err(err) => {
-            result.errors.push(WkdFetchError::FailedToFetchUrl(err));
+            result.errors.push(
+                address_blocked(&err, WkdCheck::Fetch)
+                    .unwrap_or(WkdFetchError::FailedToFetchUrl(err)),
+            );
             return result;
         }
     };
@@ -252,7 +401,7 @@ async fn fetch_uri<T>(
 
 #[cfg(test)]
 mod tests {
-    use super::super::uri::UserHash;
+    use super::super::uri::{AdvancedUri, DirectUri, UserHash};
     use super::*;
     use chrono::TimeDelta;
     use mockito::ServerGuard;
@@ -344,9 +493,45 @@ mod tests {
         assert_eq!(result.errors.len(), 0);
         assert!(result.data.is_some());
         assert!(result.timestamp - Utc::now() < TimeDelta::seconds(10));
+        assert!(result.latency.is_some());
         mock_server.reset();
     }
 
+    #[tokio::test]
+    async fn fetch_skips_unselected_method() {
+        let mut mock_server = mockito::Server::new_async().await;
+        let direct_mock = mock_server
+            .mock("GET", mockito::Matcher::Regex("^/direct/".to_string()))
+            .expect(0)
+            .create_async()
+            .await;
+        let advanced_mock = mock_server
+            .mock("GET", mockito::Matcher::Regex("^/advanced/".to_string()))
+            .with_status(404)
+            .expect_at_least(1)
+            .create_async()
+            .await;
+
+        let mut wkd_uri = WkdUri::new("joe@example.org").unwrap();
+        wkd_uri.direct_uri = DirectUri(format!(
+            "{}/direct/.well-known/openpgpkey/hu/iy9q119eutrkn8s1mk4r39qejnbu3n5q?l=joe",
+            mock_server.url()
+        ));
+        wkd_uri.advanced_uri = AdvancedUri(format!(
+            "{}/advanced/.well-known/openpgpkey/example.org/hu/iy9q119eutrkn8s1mk4r39qejnbu3n5q?l=joe",
+            mock_server.url()
+        ));
+        let result = WkdFetch::fetch(&wkd_uri, None, WkdMethodSelection::Advanced).await;
+        direct_mock.assert_async().await;
+        advanced_mock.assert_async().await;
+
+        assert!(result.direct_method.skipped);
+        assert!(result.direct_method.errors.is_empty());
+        assert!(result.direct_method.successes.is_empty());
+        assert!(!result.advanced_method.skipped);
+        assert!(!result.advanced_method.errors.is_empty());
+    }
+
     #[tokio::test]
     async fn fetch_uri_invalid_url() {
         let result = fetch_uri(&TestUri("not_a_url".to_string()), Client::new()).await;
@@ -374,6 +559,50 @@ mod tests {
         ));
     }
 
+    #[tokio::test]
+    async fn fetch_uri_address_blocked() {
+        let (mut mock_server, _test_uri, test_path, _test_policy_path) =
+            TestUri::create_test_uri_mock().await;
+        let mock = mock_server
+            .mock("GET", mockito::Matcher::Any)
+            .expect(0)
+            .create();
+        let test_uri = TestUri(format!(
+            "http://localhost:{}{test_path}",
+            mock_server.socket_address().port()
+        ));
+        let client = crate::client::WkdFetchConfig {
+            blocked_networks: vec!["127.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()],
+            ..Default::default()
+        }
+        .client()
+        .unwrap();
+
+        let result = fetch_uri(&test_uri, client).await;
+        mock.assert();
+        assert!(result.successes.is_empty(), "{result:?}");
+        let checks: Vec<WkdCheck> = result
+            .errors
+            .iter()
+            .map(|error| {
+                assert!(
+                    matches!(error, WkdFetchError::AddressBlocked(..)),
+                    "{error:?}"
+                );
+                error.check()
+            })
+            .collect();
+        assert_eq!(
+            checks,
+            [
+                WkdCheck::HeadMethod,
+                WkdCheck::NoIndex,
+                WkdCheck::PolicyFile,
+                WkdCheck::Fetch
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn fetch_uri_status_not_200() {
         let (mut mock_server, test_uri, test_path, _test_policy_path) =
@@ -469,6 +698,18 @@ mod tests {
             WkdFetchError::AccessControlAllowOriginNotStar
         ));
         assert!(result.data.is_some());
+
+        let checks: Vec<WkdCheck> = result.errors.iter().map(WkdFetchError::check).collect();
+        assert_eq!(
+            checks,
+            [
+                WkdCheck::HeadMethod,
+                WkdCheck::NoIndex,
+                WkdCheck::PolicyFile,
+                WkdCheck::ContentType,
+                WkdCheck::AccessControlAllowOrigin
+            ]
+        );
     }
 
     #[tokio::test]
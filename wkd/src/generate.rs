@@ -0,0 +1,307 @@
+use crate::cert::Cert;
+use crate::uri::{AdvancedUri, DirectUri, Uri, WkdUri};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use miette::Diagnostic;
+use thiserror::Error;
+
+/// Name of the policy file next to the `hu` directory, see <https://datatracker.ietf.org/doc/html/draft-koch-openpgp-webkey-service-19#section-4.5>
+pub const POLICY_FILE: &str = "policy";
+
+#[derive(Error, Diagnostic, Debug)]
+pub enum WkdGenerateError {
+    #[error("No user ID with an e-mail address to publish was found")]
+    #[diagnostic(
+        code(wkd_generate::no_user_ids),
+        help("Check the key files and the domains passed to the generator")
+    )]
+    NoUserIds,
+
+    #[error("The direct layout can only hold a single domain, found: {0}")]
+    #[diagnostic(
+        code(wkd_generate::multiple_domains),
+        help("Select one domain or use the advanced layout")
+    )]
+    MultipleDomains(String),
+
+    #[error("Failed to write {}", .0.display())]
+    #[diagnostic(code(wkd_generate::write_failed))]
+    WriteFailed(PathBuf, #[source] std::io::Error),
+}
+
+/// Directory layout, matching the two methods of looking up a key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WkdLayout {
+    /// `.well-known/openpgpkey/hu/<hash>` served from the domain itself
+    Direct,
+    /// `.well-known/openpgpkey/<domain>/hu/<hash>` served from the `openpgpkey` sub-domain
+    Advanced,
+}
+
+/// A key file of the generated directory
+#[derive(Debug)]
+pub struct WkdEntry {
+    /// E-mail address the key file is published for
+    pub user_id: String,
+    pub domain: String,
+    /// Path relative to the output directory
+    pub path: PathBuf,
+    /// Fingerprints of the certificates in the key file
+    pub fingerprints: Vec<String>,
+    data: Vec<u8>,
+}
+
+/// A `.well-known/openpgpkey` tree built from a set of certificates
+#[derive(Debug)]
+pub struct WkdDirectory {
+    pub layout: WkdLayout,
+    pub entries: Vec<WkdEntry>,
+    /// User IDs with an e-mail address that can't be looked up over WKD
+    pub invalid_user_ids: Vec<String>,
+}
+
+impl WkdLayout {
+    /// Directory holding the policy file and `hu` directory of `domain`, relative to the web root
+    pub fn openpgpkey_dir(&self, domain: &str) -> PathBuf {
+        match self {
+            WkdLayout::Direct => PathBuf::from(DirectUri::PATH)
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default(),
+            WkdLayout::Advanced => PathBuf::from(AdvancedUri::PATH).join(domain),
+        }
+    }
+
+    /// Path of the key file for `wkd_uri`, relative to the web root
+    pub fn key_path(&self, wkd_uri: &WkdUri) -> PathBuf {
+        self.openpgpkey_dir(&wkd_uri.domain_part)
+            .join("hu")
+            .join(wkd_uri.user_hash.to_string())
+    }
+}
+
+impl WkdDirectory {
+    /// Builds the directory for every e-mail user ID of `certs`.
+    ///
+    /// When `domains` isn't empty only user IDs in those domains are published. Every key file
+    /// only carries the user IDs matching its address.
+    pub fn new(
+        certs: &[Cert],
+        layout: WkdLayout,
+        domains: &[String],
+    ) -> Result<WkdDirectory, WkdGenerateError> {
+        // Keyed by domain as well, the direct layout has the same path for every domain
+        let mut entries: BTreeMap<(String, PathBuf), WkdEntry> = BTreeMap::new();
+        let mut invalid_user_ids = Vec::new();
+
+        for cert in certs {
+            for user_id in &cert.user_ids() {
+                let Some(email) = user_id.email() else {
+                    continue;
+                };
+
+                let wkd_uri = match WkdUri::new(email) {
+                    Ok(wkd_uri) => wkd_uri,
+                    Err(_) => {
+                        invalid_user_ids.push(user_id.value.clone());
+                        continue;
+                    }
+                };
+
+                if !domains.is_empty()
+                    && !domains
+                        .iter()
+                        .any(|domain| domain.eq_ignore_ascii_case(&wkd_uri.domain_part))
+                {
+                    continue;
+                }
+
+                let path = layout.key_path(&wkd_uri);
+                let key = (wkd_uri.domain_part.clone(), path.clone());
+                let entry = entries.entry(key).or_insert_with(|| WkdEntry {
+                    user_id: email.to_string(),
+                    domain: wkd_uri.domain_part.clone(),
+                    path,
+                    fingerprints: Vec::new(),
+                    data: Vec::new(),
+                });
+
+                // The same address can be on several user IDs of one certificate
+                if entry.fingerprints.contains(&cert.fingerprint) {
+                    continue;
+                }
+
+                if let Some(published) = cert.for_user_id(email) {
+                    entry.data.extend(published.to_bytes());
+                    entry.fingerprints.push(cert.fingerprint.clone());
+                }
+            }
+        }
+
+        let entries: Vec<WkdEntry> = entries.into_values().collect();
+        if entries.is_empty() {
+            return Err(WkdGenerateError::NoUserIds);
+        }
+
+        if layout == WkdLayout::Direct {
+            let mut domains: Vec<&str> =
+                entries.iter().map(|entry| entry.domain.as_str()).collect();
+            domains.sort();
+            domains.dedup();
+            if domains.len() > 1 {
+                return Err(WkdGenerateError::MultipleDomains(domains.join(", ")));
+            }
+        }
+
+        Ok(WkdDirectory {
+            layout,
+            entries,
+            invalid_user_ids,
+        })
+    }
+
+    /// Policy files of every domain, relative to the web root
+    pub fn policy_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self
+            .entries
+            .iter()
+            .map(|entry| self.layout.openpgpkey_dir(&entry.domain).join(POLICY_FILE))
+            .collect();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// Writes the key files and missing policy files below `out_dir`.
+    ///
+    /// Existing policy files are kept, returns the paths of the written files.
+    pub fn write(&self, out_dir: &Path) -> Result<Vec<PathBuf>, WkdGenerateError> {
+        let mut written = Vec::new();
+
+        let write = |path: &Path, data: &[u8]| {
+            let path = out_dir.join(path);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .map_err(|err| WkdGenerateError::WriteFailed(parent.to_path_buf(), err))?;
+            }
+            std::fs::write(&path, data).map_err(|err| WkdGenerateError::WriteFailed(path, err))
+        };
+
+        for entry in &self.entries {
+            write(&entry.path, &entry.data)?;
+            written.push(entry.path.clone());
+        }
+
+        for policy_path in self.policy_paths() {
+            if !out_dir.join(&policy_path).exists() {
+                write(&policy_path, b"")?;
+                written.push(policy_path);
+            }
+        }
+
+        Ok(written)
+    }
+}
+
+impl WkdEntry {
+    /// Binary key file content
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_KEY: &[u8] = include_bytes!("../../test_files/test_key");
+    const TEST_EXPIRED_KEY: &[u8] = include_bytes!("../../test_files/test_expired_key");
+
+    fn test_certs() -> Vec<Cert> {
+        Cert::from_bytes(&[TEST_KEY, TEST_EXPIRED_KEY].concat()).unwrap()
+    }
+
+    #[test]
+    fn advanced_layout_has_a_directory_per_domain() {
+        let directory = WkdDirectory::new(&test_certs(), WkdLayout::Advanced, &[]).unwrap();
+
+        let wkd_uri = WkdUri::new("alexis.lowe@chimbosonic.com").unwrap();
+        let entry = directory
+            .entries
+            .iter()
+            .find(|entry| entry.user_id == "alexis.lowe@chimbosonic.com")
+            .unwrap();
+        assert_eq!(
+            entry.path,
+            PathBuf::from(format!(
+                ".well-known/openpgpkey/chimbosonic.com/hu/{}",
+                wkd_uri.user_hash
+            ))
+        );
+
+        let published = Cert::from_bytes(entry.data()).unwrap();
+        assert_eq!(published[0].user_ids().len(), 1);
+        assert_eq!(
+            published[0].user_ids()[0].email(),
+            Some("alexis.lowe@chimbosonic.com")
+        );
+
+        assert!(directory.policy_paths().contains(&PathBuf::from(
+            ".well-known/openpgpkey/chimbosonic.com/policy"
+        )));
+    }
+
+    #[test]
+    fn direct_layout_needs_a_single_domain() {
+        let certs = Cert::from_bytes(TEST_KEY).unwrap();
+        assert!(matches!(
+            WkdDirectory::new(&certs, WkdLayout::Direct, &[]),
+            Err(WkdGenerateError::MultipleDomains(_))
+        ));
+
+        let directory =
+            WkdDirectory::new(&certs, WkdLayout::Direct, &["chimbosonic.com".to_string()]).unwrap();
+        assert_eq!(directory.entries.len(), 1);
+        assert!(
+            directory.entries[0]
+                .path
+                .starts_with(".well-known/openpgpkey/hu")
+        );
+        assert_eq!(
+            directory.policy_paths(),
+            vec![PathBuf::from(".well-known/openpgpkey/policy")]
+        );
+
+        assert!(matches!(
+            WkdDirectory::new(&certs, WkdLayout::Direct, &["example.org".to_string()]),
+            Err(WkdGenerateError::NoUserIds)
+        ));
+    }
+
+    #[test]
+    fn write_keeps_existing_policy() {
+        let out_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(out_dir.path().join(".well-known/openpgpkey")).unwrap();
+        std::fs::write(
+            out_dir.path().join(".well-known/openpgpkey/policy"),
+            "protocol-version: 19\n",
+        )
+        .unwrap();
+
+        let certs = Cert::from_bytes(TEST_KEY).unwrap();
+        let directory =
+            WkdDirectory::new(&certs, WkdLayout::Direct, &["chimbosonic.com".to_string()]).unwrap();
+        let written = directory.write(out_dir.path()).unwrap();
+
+        assert_eq!(written, vec![directory.entries[0].path.clone()]);
+        assert_eq!(
+            std::fs::read(out_dir.path().join(&directory.entries[0].path)).unwrap(),
+            directory.entries[0].data()
+        );
+        assert_eq!(
+            std::fs::read_to_string(out_dir.path().join(".well-known/openpgpkey/policy")).unwrap(),
+            "protocol-version: 19\n"
+        );
+    }
+}
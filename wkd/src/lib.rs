@@ -1,2 +1,8 @@
+pub mod cert;
+pub mod client;
 pub mod fetch;
+pub mod generate;
+pub mod lint;
+#[cfg(feature = "result")]
+pub mod result;
 pub mod uri;
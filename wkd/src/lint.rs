@@ -0,0 +1,517 @@
+use crate::cert::{self, Cert, WkdCertError};
+use crate::fetch::{MAX_KEY_SIZE, WkdFetchError};
+use crate::generate::{POLICY_FILE, WkdLayout};
+use crate::uri::{AdvancedUri, Uri, WkdUri};
+use std::path::{Path, PathBuf};
+
+use miette::Diagnostic;
+use thiserror::Error;
+
+/// Name of the submission address file next to the policy file, see <https://datatracker.ietf.org/doc/html/draft-koch-openpgp-webkey-service-19#section-4.4>
+pub const SUBMISSION_ADDRESS_FILE: &str = "submission-address";
+
+/// Policy keywords that don't take a value
+const POLICY_FLAGS: [&str; 3] = ["mailbox-only", "dane-only", "auth-submit"];
+
+/// Findings only a lint can make, the checks a lookup runs too report the [`WkdFetchError`] a lookup would
+#[derive(Error, Diagnostic, Debug)]
+pub enum WkdLintError {
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Fetch(#[from] WkdFetchError),
+
+    #[error("No `.well-known/openpgpkey` directory found in {}", .0.display())]
+    #[diagnostic(
+        code(wkd_lint::no_wkd_directory),
+        help("Pass the web root or the `.well-known/openpgpkey` directory itself")
+    )]
+    NoWkdDirectory(PathBuf),
+
+    #[error("Failed to read {}", .0.display())]
+    #[diagnostic(code(wkd_lint::read_failed))]
+    ReadFailed(PathBuf, #[source] std::io::Error),
+
+    #[error("File name is not a z-base-32 encoded user hash")]
+    #[diagnostic(
+        code(wkd_lint::invalid_user_hash),
+        url(
+            "https://datatracker.ietf.org/doc/html/draft-koch-openpgp-webkey-service-19#section-3.1-3"
+        )
+    )]
+    InvalidUserHash,
+
+    #[error("No user ID of the key hashes to the file name")]
+    #[diagnostic(
+        code(wkd_lint::no_matching_user_id),
+        help("Clients reject keys without a user ID for the address they looked up")
+    )]
+    NoMatchingUserId,
+
+    #[error("Key file is ASCII armored")]
+    #[diagnostic(
+        severity(Warning),
+        code(wkd_lint::armored_key),
+        help("Serve the binary key, for example from `gpg --export` without `--armor`")
+    )]
+    ArmoredKey,
+
+    #[error("Key file contains secret key material")]
+    #[diagnostic(
+        code(wkd_lint::secret_key_material),
+        help("Only publish the public part of a key, for example with `gpg --export`")
+    )]
+    SecretKeyMaterial,
+
+    #[error("Key file could not be parsed")]
+    #[diagnostic(code(wkd_lint::invalid_key))]
+    InvalidKey(#[source] WkdCertError),
+
+    #[error("Policy file line {0} is not valid: {1}")]
+    #[diagnostic(
+        severity(Warning),
+        code(wkd_lint::invalid_policy_line),
+        url(
+            "https://datatracker.ietf.org/doc/html/draft-koch-openpgp-webkey-service-19#section-4.5"
+        )
+    )]
+    InvalidPolicyLine(usize, String),
+
+    #[error("Policy file line {0} has an unknown keyword: {1}")]
+    #[diagnostic(severity(Advice), code(wkd_lint::unknown_policy_keyword))]
+    UnknownPolicyKeyword(usize, String),
+
+    #[error("Submission address file does not hold a single valid address")]
+    #[diagnostic(
+        severity(Warning),
+        code(wkd_lint::invalid_submission_address),
+        url(
+            "https://datatracker.ietf.org/doc/html/draft-koch-openpgp-webkey-service-19#section-4.4"
+        )
+    )]
+    InvalidSubmissionAddress,
+}
+
+/// Lint result of a single key or index file
+#[derive(Debug)]
+pub struct WkdLintFile {
+    /// Path relative to the web root
+    pub path: PathBuf,
+    /// Fingerprints of the certificates in the key file
+    pub fingerprints: Vec<String>,
+    /// E-mail addresses of the key that hash to the file name
+    pub user_ids: Vec<String>,
+    pub errors: Vec<WkdLintError>,
+}
+
+/// Lint result of the directory holding one `hu` directory and its policy file
+#[derive(Debug)]
+pub struct WkdLintDirectory {
+    pub layout: WkdLayout,
+    /// Domain of the advanced layout, the direct layout doesn't name its domain
+    pub domain: Option<String>,
+    /// Path relative to the web root
+    pub path: PathBuf,
+    pub errors: Vec<WkdLintError>,
+    pub files: Vec<WkdLintFile>,
+}
+
+/// Lint result of a `.well-known/openpgpkey` tree on disk
+#[derive(Debug)]
+pub struct WkdLint {
+    pub directories: Vec<WkdLintDirectory>,
+}
+
+impl WkdLint {
+    /// Lints the tree below `root`, which is either the web root or the `.well-known/openpgpkey`
+    /// directory itself.
+    ///
+    /// Runs the checks of [`crate::fetch`] against the files, no network access is needed.
+    pub fn new(root: &Path) -> Result<WkdLint, WkdLintError> {
+        let advanced_path = PathBuf::from(AdvancedUri::PATH);
+        let (openpgpkey_dir, base) = if root.join(&advanced_path).is_dir() {
+            (root.join(&advanced_path), advanced_path)
+        } else if root.ends_with(&advanced_path) && root.is_dir() {
+            (root.to_path_buf(), advanced_path)
+        } else {
+            return Err(WkdLintError::NoWkdDirectory(root.to_path_buf()));
+        };
+
+        let mut directories = Vec::new();
+
+        if openpgpkey_dir.join("hu").is_dir() {
+            directories.push(WkdLintDirectory::new(
+                &openpgpkey_dir,
+                base.clone(),
+                WkdLayout::Direct,
+                None,
+            ));
+        }
+
+        for entry in read_dir(&openpgpkey_dir)? {
+            let path = entry.path();
+            if path.is_dir() && path.join("hu").is_dir() {
+                let domain = entry.file_name().to_string_lossy().to_string();
+                directories.push(WkdLintDirectory::new(
+                    &path,
+                    base.join(&domain),
+                    WkdLayout::Advanced,
+                    Some(domain),
+                ));
+            }
+        }
+
+        if directories.is_empty() {
+            return Err(WkdLintError::NoWkdDirectory(root.to_path_buf()));
+        }
+
+        Ok(WkdLint { directories })
+    }
+
+    /// Every error of the tree, with the path it was found in
+    pub fn errors(&self) -> impl Iterator<Item = (&Path, &WkdLintError)> {
+        self.directories.iter().flat_map(|directory| {
+            let directory_errors = directory
+                .errors
+                .iter()
+                .map(|error| (directory.path.as_path(), error));
+            let file_errors = directory
+                .files
+                .iter()
+                .flat_map(|file| file.errors.iter().map(|error| (file.path.as_path(), error)));
+            directory_errors.chain(file_errors)
+        })
+    }
+}
+
+impl WkdLintDirectory {
+    fn new(dir: &Path, path: PathBuf, layout: WkdLayout, domain: Option<String>) -> Self {
+        let mut directory = WkdLintDirectory {
+            layout,
+            domain,
+            path,
+            errors: Vec::new(),
+            files: Vec::new(),
+        };
+
+        for index in find_index_files(dir).chain(find_index_files(&dir.join("hu"))) {
+            let relative = index.strip_prefix(dir).unwrap_or(&index);
+            directory.files.push(WkdLintFile {
+                path: directory.path.join(relative),
+                fingerprints: Vec::new(),
+                user_ids: Vec::new(),
+                errors: vec![WkdFetchError::WkdPathShouldNotHaveIndex.into()],
+            });
+        }
+
+        match std::fs::read_to_string(dir.join(POLICY_FILE)) {
+            Ok(policy) => directory.errors.extend(lint_policy(&policy)),
+            Err(_) => directory
+                .errors
+                .push(WkdFetchError::WkdPolicyFileNotFound.into()),
+        }
+
+        // The submission address is optional
+        if let Ok(submission_address) = std::fs::read_to_string(dir.join(SUBMISSION_ADDRESS_FILE))
+            && !is_valid_submission_address(&submission_address)
+        {
+            directory
+                .errors
+                .push(WkdLintError::InvalidSubmissionAddress);
+        }
+
+        let entries = match read_dir(&dir.join("hu")) {
+            Ok(entries) => entries,
+            Err(error) => {
+                directory.errors.push(error);
+                return directory;
+            }
+        };
+
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if is_index_file(&name) {
+                continue;
+            }
+            let file = lint_key_file(
+                &entry.path(),
+                directory.path.join("hu").join(&name),
+                directory.domain.as_deref(),
+            );
+            directory.files.push(file);
+        }
+
+        directory
+    }
+}
+
+fn read_dir(dir: &Path) -> Result<Vec<std::fs::DirEntry>, WkdLintError> {
+    let read_failed = |error| WkdLintError::ReadFailed(dir.to_path_buf(), error);
+    let mut entries = std::fs::read_dir(dir)
+        .map_err(read_failed)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(read_failed)?;
+    entries.sort_by_key(|entry| entry.file_name());
+    Ok(entries)
+}
+
+fn is_index_file(name: &str) -> bool {
+    name == "index" || name.starts_with("index.")
+}
+
+fn find_index_files(dir: &Path) -> impl Iterator<Item = PathBuf> {
+    read_dir(dir)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|entry| is_index_file(&entry.file_name().to_string_lossy()))
+        .map(|entry| entry.path())
+}
+
+fn is_valid_user_hash(name: &str) -> bool {
+    name.len() == 32 && z32::decode(name.as_bytes()).is_ok()
+}
+
+fn lint_key_file(file_path: &Path, path: PathBuf, domain: Option<&str>) -> WkdLintFile {
+    let mut file = WkdLintFile {
+        path,
+        fingerprints: Vec::new(),
+        user_ids: Vec::new(),
+        errors: Vec::new(),
+    };
+
+    let name = file_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if !is_valid_user_hash(&name) {
+        file.errors.push(WkdLintError::InvalidUserHash);
+    }
+
+    let data = match std::fs::read(file_path) {
+        Ok(data) => data,
+        Err(error) => {
+            file.errors
+                .push(WkdLintError::ReadFailed(file.path.clone(), error));
+            return file;
+        }
+    };
+
+    if data.len() > MAX_KEY_SIZE {
+        file.errors.push(WkdFetchError::ResponseSizeExceeded.into());
+        return file;
+    }
+
+    if cert::is_armored(&data) {
+        file.errors.push(WkdLintError::ArmoredKey);
+    }
+
+    let certs = match Cert::from_bytes(&data) {
+        Ok(certs) => certs,
+        Err(WkdCertError::SecretKeyMaterial) => {
+            file.errors.push(WkdLintError::SecretKeyMaterial);
+            return file;
+        }
+        Err(error) => {
+            file.errors.push(WkdLintError::InvalidKey(error));
+            return file;
+        }
+    };
+
+    for cert in &certs {
+        file.fingerprints.push(cert.fingerprint.clone());
+        let user_ids = cert.user_ids();
+        for email in user_ids.iter().filter_map(|user_id| user_id.email()) {
+            let Ok(wkd_uri) = WkdUri::new(email) else {
+                continue;
+            };
+            let in_domain =
+                domain.is_none_or(|domain| domain.eq_ignore_ascii_case(&wkd_uri.domain_part));
+            if in_domain
+                && wkd_uri.user_hash.to_string() == name
+                && !file.user_ids.iter().any(|user_id| user_id == email)
+            {
+                file.user_ids.push(email.to_string());
+            }
+        }
+    }
+
+    if is_valid_user_hash(&name) && file.user_ids.is_empty() {
+        file.errors.push(WkdLintError::NoMatchingUserId);
+    }
+
+    file
+}
+
+/// Checks the `keyword` and `keyword: value` lines of a policy file, `#` starts a comment
+fn lint_policy(policy: &str) -> Vec<WkdLintError> {
+    let mut errors = Vec::new();
+
+    for (number, line) in policy.lines().enumerate().map(|(i, line)| (i + 1, line)) {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (keyword, value) = match line.split_once(':') {
+            Some((keyword, value)) => (keyword, Some(value.trim())),
+            None => (line, None),
+        };
+
+        let valid_keyword = keyword.starts_with(|c: char| c.is_ascii_lowercase())
+            && keyword
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if !valid_keyword {
+            errors.push(WkdLintError::InvalidPolicyLine(
+                number,
+                format!("`{keyword}` is not a valid keyword"),
+            ));
+            continue;
+        }
+
+        match (keyword, value) {
+            ("protocol-version", Some(value)) if value.parse::<u32>().is_ok() => {}
+            ("protocol-version", _) => errors.push(WkdLintError::InvalidPolicyLine(
+                number,
+                "protocol-version needs a number".to_string(),
+            )),
+            (flag, Some(_)) if POLICY_FLAGS.contains(&flag) => errors.push(
+                WkdLintError::InvalidPolicyLine(number, format!("{flag} does not take a value")),
+            ),
+            (flag, None) if POLICY_FLAGS.contains(&flag) => {}
+            (keyword, _) => errors.push(WkdLintError::UnknownPolicyKeyword(
+                number,
+                keyword.to_string(),
+            )),
+        }
+    }
+
+    errors
+}
+
+fn is_valid_submission_address(submission_address: &str) -> bool {
+    let lines: Vec<&str> = submission_address
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect();
+    matches!(lines.as_slice(), [address] if WkdUri::new(address).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::WkdDirectory;
+    use crate::uri::UserHash;
+
+    const TEST_KEY: &[u8] = include_bytes!("../../test_files/test_key");
+
+    fn codes(errors: &[WkdLintError]) -> Vec<String> {
+        errors
+            .iter()
+            .filter_map(|error| error.code().map(|code| code.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn lint_policy_lines() {
+        let errors = lint_policy(
+            "# comment\n\nprotocol-version: 19\nmailbox-only\nauth-submit: yes\nProtocol\nfoo-bar: 1\nprotocol-version: x\n",
+        );
+        assert_eq!(
+            codes(&errors),
+            vec![
+                "wkd_lint::invalid_policy_line",
+                "wkd_lint::invalid_policy_line",
+                "wkd_lint::unknown_policy_keyword",
+                "wkd_lint::invalid_policy_line",
+            ]
+        );
+        assert!(matches!(errors[0], WkdLintError::InvalidPolicyLine(5, _)));
+    }
+
+    #[test]
+    fn submission_address_needs_a_single_address() {
+        assert!(is_valid_submission_address("key-submission@example.org\n"));
+        assert!(!is_valid_submission_address(""));
+        assert!(!is_valid_submission_address(
+            "a@example.org\nb@example.org\n"
+        ));
+        assert!(!is_valid_submission_address("not an address"));
+    }
+
+    #[test]
+    fn lint_generated_tree() {
+        let root = tempfile::tempdir().unwrap();
+
+        let certs = Cert::from_bytes(TEST_KEY).unwrap();
+        let directory = WkdDirectory::new(&certs, WkdLayout::Advanced, &[]).unwrap();
+        directory.write(root.path()).unwrap();
+
+        let lint = WkdLint::new(root.path()).unwrap();
+        assert_eq!(lint.directories.len(), 2);
+        assert_eq!(lint.errors().count(), 0);
+        assert_eq!(
+            lint.directories[0].files[0].user_ids,
+            vec!["alexis.lowe@chimbosonic.com"]
+        );
+
+        let domain_dir = root.path().join(".well-known/openpgpkey/chimbosonic.com");
+        let hash = UserHash::new("alexis.lowe");
+        std::fs::write(
+            domain_dir.join("hu").join(hash.to_string()),
+            Cert::from_bytes(TEST_KEY).unwrap()[0].to_armored().unwrap(),
+        )
+        .unwrap();
+        std::fs::copy(
+            domain_dir.join("hu").join(hash.to_string()),
+            domain_dir.join("hu").join(UserHash::new("joe").to_string()),
+        )
+        .unwrap();
+        std::fs::write(domain_dir.join("hu/NOT-A-HASH"), TEST_KEY).unwrap();
+        std::fs::write(domain_dir.join("hu/index.html"), "").unwrap();
+        std::fs::remove_file(domain_dir.join(POLICY_FILE)).unwrap();
+
+        let lint = WkdLint::new(&root.path().join(".well-known/openpgpkey")).unwrap();
+        let directory = &lint.directories[0];
+        assert_eq!(directory.domain.as_deref(), Some("chimbosonic.com"));
+        assert_eq!(
+            codes(&directory.errors),
+            vec!["wkd_fetch::policy_file_not_found"]
+        );
+        let file_codes: Vec<Vec<String>> = directory
+            .files
+            .iter()
+            .map(|file| codes(&file.errors))
+            .collect();
+        assert!(file_codes.contains(&vec!["wkd_fetch::path_should_not_have_index".to_string()]));
+        assert!(file_codes.contains(&vec!["wkd_lint::invalid_user_hash".to_string()]));
+        assert!(file_codes.contains(&vec!["wkd_lint::armored_key".to_string()]));
+        assert!(file_codes.contains(&vec![
+            "wkd_lint::armored_key".to_string(),
+            "wkd_lint::no_matching_user_id".to_string()
+        ]));
+
+        std::fs::write(domain_dir.join("hu/NOT-A-HASH"), vec![0; MAX_KEY_SIZE + 1]).unwrap();
+        let lint = WkdLint::new(root.path()).unwrap();
+        let too_large = lint.directories[0]
+            .files
+            .iter()
+            .find(|file| file.path.ends_with("NOT-A-HASH"))
+            .unwrap();
+        assert_eq!(
+            codes(&too_large.errors),
+            vec![
+                "wkd_lint::invalid_user_hash",
+                "wkd_fetch::response_size_exceeded"
+            ]
+        );
+    }
+
+    #[test]
+    fn lint_needs_a_wkd_directory() {
+        assert!(matches!(
+            WkdLint::new(&tempfile::tempdir().unwrap().path().join("missing")),
+            Err(WkdLintError::NoWkdDirectory(_))
+        ));
+    }
+}
@@ -0,0 +1,347 @@
+use crate::fetch::{WkdFetch, WkdFetchSuccess, WkdFetchUriResult, WkdMethodSelection};
+use crate::uri::{WkdUri, WkdUriError};
+use chrono::{DateTime, Utc};
+use miette::Diagnostic;
+use openpgp_tester_lib::load;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "openapi")]
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub enum WkdMethodType {
+    Direct,
+    Advanced,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct WkdResult {
+    pub user_id: String,
+    pub methods: Vec<WkdUriResult>,
+    /// Answered from a cache instead of being looked up for this request
+    #[serde(default)]
+    pub cached: bool,
+    /// When the lookup was stored in a cache, unset when nothing caches it
+    #[serde(default)]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>))]
+    pub cached_at: Option<DateTime<Utc>>,
+    /// When the cached lookup goes stale and the address is looked up again
+    #[serde(default)]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>))]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct WkdUriResult {
+    pub uri: String,
+    pub key: Option<WkdKey>,
+    pub errors: Vec<WkdError>,
+    pub method_type: WkdMethodType,
+    pub successes: Vec<WkdSuccess>,
+    #[cfg_attr(feature = "openapi", schema(value_type = String))]
+    pub timestamp: DateTime<Utc>,
+    /// The method wasn't selected for this lookup, so it has neither errors nor successes
+    #[serde(default)]
+    pub skipped: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct WkdSuccess(pub String);
+
+impl From<&str> for WkdSuccess {
+    fn from(value: &str) -> Self {
+        WkdSuccess(value.to_string())
+    }
+}
+
+impl From<&WkdFetchSuccess> for WkdSuccess {
+    fn from(value: &WkdFetchSuccess) -> Self {
+        match value {
+            WkdFetchSuccess::AccessControlAllowOriginStar => {
+                WkdSuccess::from("Access-Control-Allow: *")
+            }
+            WkdFetchSuccess::ContentTypeOctetStream => {
+                WkdSuccess::from("Content-Type: application/octet-stream")
+            }
+            WkdFetchSuccess::HeadMethod => WkdSuccess::from("HTTP Head Method"),
+            WkdFetchSuccess::NoIndex => WkdSuccess::from("No Index found"),
+            WkdFetchSuccess::PolicyFile => WkdSuccess::from("Policy File Found"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub enum WkdSeverity {
+    #[default]
+    Error,
+    Warning,
+    Advice,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct WkdError {
+    pub name: String,
+    /// Stable diagnostic code, for example `wkd_fetch::status_not_200`, empty in results stored before codes existed
+    #[serde(default)]
+    pub code: String,
+    #[serde(default)]
+    pub severity: WkdSeverity,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct WkdKey {
+    pub fingerprint: String,
+    pub revocation_status: String,
+    pub expiry: String,
+    pub algorithm: String,
+    pub randomart: String,
+}
+
+pub async fn get_wkd(
+    user_id: &str,
+    reqwest_client: Client,
+    selection: WkdMethodSelection,
+) -> WkdResult {
+    let wkd_uri = match WkdUri::new(user_id) {
+        Ok(wkd_uri) => wkd_uri,
+        Err(err) => return WkdResult::from_uri_error(user_id, &err),
+    };
+
+    let wkd_fetch = WkdFetch::fetch(&wkd_uri, Some(reqwest_client.clone()), selection).await;
+    WkdResult::from_fetch(user_id, wkd_uri, wkd_fetch)
+}
+
+impl WkdResult {
+    pub fn from_fetch(user_id: &str, wkd_uri: WkdUri, wkd_fetch: WkdFetch) -> Self {
+        let methods = vec![
+            WkdUriResult::from(
+                wkd_fetch.direct_method,
+                wkd_uri.direct_uri,
+                WkdMethodType::Direct,
+            ),
+            WkdUriResult::from(
+                wkd_fetch.advanced_method,
+                wkd_uri.advanced_uri,
+                WkdMethodType::Advanced,
+            ),
+        ];
+        WkdResult {
+            user_id: user_id.to_string(),
+            methods,
+            cached: false,
+            cached_at: None,
+            expires_at: None,
+        }
+    }
+
+    pub fn from_uri_error(user_id: &str, err: &WkdUriError) -> Self {
+        WkdResult {
+            user_id: user_id.to_string(),
+            methods: vec![
+                WkdUriResult {
+                    uri: "".to_string(),
+                    key: None,
+                    errors: vec![WkdError::from(err)],
+                    method_type: WkdMethodType::Direct,
+                    successes: vec![],
+                    timestamp: Utc::now(),
+                    skipped: false,
+                },
+                WkdUriResult {
+                    uri: "".to_string(),
+                    key: None,
+                    errors: vec![WkdError::from(err)],
+                    method_type: WkdMethodType::Advanced,
+                    successes: vec![],
+                    timestamp: Utc::now(),
+                    skipped: false,
+                },
+            ],
+            cached: false,
+            cached_at: None,
+            expires_at: None,
+        }
+    }
+}
+
+impl WkdUriResult {
+    pub fn from(
+        wkd_fetch: WkdFetchUriResult,
+        uri: impl std::string::ToString,
+        method_type: WkdMethodType,
+    ) -> Self {
+        let key: Option<WkdKey> = match wkd_fetch.data {
+            Some(data) => load::load_key(data).ok().map(WkdKey::from),
+            None => None,
+        };
+
+        WkdUriResult {
+            uri: uri.to_string(),
+            key,
+            errors: wkd_fetch.errors.iter().map(WkdError::from).collect(),
+            successes: wkd_fetch.successes.iter().map(WkdSuccess::from).collect(),
+            method_type,
+            timestamp: wkd_fetch.timestamp,
+            skipped: wkd_fetch.skipped,
+        }
+    }
+}
+
+impl From<Option<miette::Severity>> for WkdSeverity {
+    fn from(value: Option<miette::Severity>) -> Self {
+        match value {
+            Some(miette::Severity::Warning) => WkdSeverity::Warning,
+            Some(miette::Severity::Advice) => WkdSeverity::Advice,
+            Some(miette::Severity::Error) | None => WkdSeverity::Error,
+        }
+    }
+}
+
+impl WkdError {
+    pub fn from<Error: Diagnostic + ?Sized>(error: &Error) -> Self {
+        let name = format!("{error:?}");
+        let code = match error.code() {
+            Some(code) => code.to_string(),
+            None => name.clone(),
+        };
+
+        WkdError {
+            name,
+            code,
+            severity: WkdSeverity::from(error.severity()),
+            message: format!("{error}"),
+        }
+    }
+}
+
+impl WkdKey {
+    pub fn from(wkd_key: load::Key) -> Self {
+        WkdKey {
+            fingerprint: wkd_key.fingerprint,
+            revocation_status: wkd_key.revocation_status,
+            expiry: wkd_key.expiry,
+            algorithm: wkd_key.algorithm,
+            randomart: wkd_key.randomart,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::WkdFetchError;
+
+    #[test]
+    fn test_wkd_key_from() {
+        let wkd_key = load::Key {
+            fingerprint: "fingerprint".to_string(),
+            revocation_status: "revocation_status".to_string(),
+            expiry: "expiry".to_string(),
+            algorithm: "algorithm".to_string(),
+            randomart: "randomart".to_string(),
+        };
+        let key = WkdKey::from(wkd_key);
+        assert_eq!(key.fingerprint, "fingerprint");
+        assert_eq!(key.revocation_status, "revocation_status");
+        assert_eq!(key.expiry, "expiry");
+        assert_eq!(key.algorithm, "algorithm");
+        assert_eq!(key.randomart, "randomart");
+    }
+
+    #[test]
+    fn test_wkd_error_from() {
+        let error = WkdFetchError::AccessControlAllowOriginNotStar;
+        let wkd_error = WkdError::from(&error);
+        assert_eq!(wkd_error.name, "AccessControlAllowOriginNotStar");
+        assert_eq!(
+            wkd_error.code,
+            "wkd_fetch::access_control_allow_origin_not_star"
+        );
+        assert_eq!(wkd_error.severity, WkdSeverity::Warning);
+        assert_eq!(
+            wkd_error.message,
+            "Access-Control-Allow-Origin header is not set to '*'. This may cause issues with CORS"
+        );
+
+        let wkd_error = WkdError::from(&WkdFetchError::StatusNot200(404));
+        assert_eq!(wkd_error.name, "StatusNot200(404)");
+        assert_eq!(wkd_error.code, "wkd_fetch::status_not_200");
+        assert_eq!(wkd_error.severity, WkdSeverity::Error);
+
+        let wkd_error = WkdError::from(&WkdUriError::InvalidEmailError);
+        assert_eq!(wkd_error.code, "wkd_uri::invalid_email");
+        assert_eq!(wkd_error.severity, WkdSeverity::Error);
+    }
+
+    #[test]
+    fn test_wkd_uri_result_from() {
+        let wkd_fetch = WkdFetchUriResult {
+            successes: vec![],
+            errors: vec![WkdFetchError::AccessControlAllowOriginNotStar],
+            data: None,
+            timestamp: Utc::now(),
+            latency: None,
+            skipped: false,
+        };
+        let wkd_uri_result = WkdUriResult::from(wkd_fetch, "uri", WkdMethodType::Direct);
+        assert!(wkd_uri_result.key.is_none());
+        assert_eq!(wkd_uri_result.errors.len(), 1);
+        assert_eq!(
+            wkd_uri_result.errors[0].name,
+            "AccessControlAllowOriginNotStar"
+        );
+        assert_eq!(
+            wkd_uri_result.errors[0].message,
+            "Access-Control-Allow-Origin header is not set to '*'. This may cause issues with CORS"
+        );
+        assert!(!wkd_uri_result.skipped);
+
+        let wkd_uri_result =
+            WkdUriResult::from(WkdFetchUriResult::skipped(), "uri", WkdMethodType::Advanced);
+        assert!(wkd_uri_result.skipped);
+        assert!(wkd_uri_result.errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_wkd() {
+        let wkd_result = get_wkd(
+            "test@chimbosonic.com",
+            Client::new(),
+            WkdMethodSelection::Both,
+        )
+        .await;
+        println!("{:#?}", wkd_result);
+        assert_eq!(wkd_result.user_id, "test@chimbosonic.com");
+        assert_eq!(
+            wkd_result.methods.as_slice()[0].uri,
+            "https://chimbosonic.com/.well-known/openpgpkey/hu/iffe93qcsgp4c8ncbb378rxjo6cn9q6u?l=test"
+        );
+        assert_eq!(
+            wkd_result.methods.as_slice()[1].uri,
+            "https://openpgpkey.chimbosonic.com/.well-known/openpgpkey/chimbosonic.com/hu/iffe93qcsgp4c8ncbb378rxjo6cn9q6u?l=test"
+        );
+        assert!(wkd_result.methods.as_slice()[0].key.is_none());
+        assert!(wkd_result.methods.as_slice()[1].key.is_none());
+        assert_eq!(wkd_result.methods.as_slice()[1].errors.len(), 2);
+        assert_eq!(wkd_result.methods.as_slice()[0].errors.len(), 2);
+        println!("{:?}", wkd_result.methods.as_slice()[0].successes);
+
+        assert_eq!(
+            wkd_result.methods.as_slice()[0].successes[0],
+            WkdSuccess::from("No Index found")
+        );
+        assert_eq!(
+            wkd_result.methods.as_slice()[1].successes[0],
+            WkdSuccess::from("No Index found")
+        );
+    }
+}
@@ -7,11 +7,11 @@ use tracing::{Level, event};
 
 /// Direct Method URI conforming to <https://datatracker.ietf.org/doc/html/draft-koch-openpgp-webkey-service-19#section-3.1-10>
 #[derive(Debug)]
-pub struct DirectUri(String);
+pub struct DirectUri(pub(crate) String);
 
 #[derive(Debug)]
 /// Advanced Method URI conforming to <https://datatracker.ietf.org/doc/html/draft-koch-openpgp-webkey-service-19#section-3.1-5>
-pub struct AdvancedUri(String);
+pub struct AdvancedUri(pub(crate) String);
 
 #[derive(Debug)]
 /// User Hash conforming to <https://datatracker.ietf.org/doc/html/draft-koch-openpgp-webkey-service-19#section-3.1-3>
@@ -42,7 +42,7 @@ pub enum WkdUriError {
 
     #[error("User ID must be in the format '{{local_part}}@{{domain_part}}'")]
     #[diagnostic(
-        code(wkd_uri::parse_email),
+        code(wkd_uri::invalid_email),
         url(
             "https://datatracker.ietf.org/doc/html/draft-koch-openpgp-webkey-service-19#section-3.1-2"
         )
@@ -50,7 +50,7 @@ pub enum WkdUriError {
     InvalidEmailError,
 
     #[error("Invalid domain part")]
-    #[diagnostic(code(wkd_uri::parse_email))]
+    #[diagnostic(code(wkd_uri::invalid_domain))]
     InvalidDomainError,
 }
 
@@ -68,7 +68,8 @@ impl UserHash {
         Ok(UserHash(s.to_string()))
     }
 
-    fn new(local_part: &str) -> UserHash {
+    /// Hashes the lower cased local part of an e-mail address
+    pub fn new(local_part: &str) -> UserHash {
         let mut hasher = Sha1::new();
         let local_part = local_part.to_string().to_ascii_lowercase();
         hasher.update(local_part);